@@ -0,0 +1,40 @@
+// Generated from machine.toml, do not edit.
+#pragma once
+
+#define RESET_PC 0x40000000
+
+#define HEAP_OFFSET 0x8
+#define HEAP_SIZE 0xfa00
+
+#define RAM_OFFSET 0x40000000
+#define RAM_SIZE 0x8000000
+
+#define STACK_OFFSET 0xfff00000
+#define STACK_SIZE 0x100000
+
+#define NULL_OFFSET 0x0
+#define NULL_SIZE 0x1
+
+#define EXIT_OFFSET 0x1
+#define EXIT_SIZE 0x1
+
+#define RENDER_OFFSET 0x2
+#define RENDER_SIZE 0x2
+
+#define CONSOLE_OFFSET 0x4
+#define CONSOLE_SIZE 0x4
+
+#define SCREEN_OFFSET 0xfa08
+#define SCREEN_SIZE 0x1f40
+
+#define BUTTON_OFFSET 0x11948
+#define BUTTON_SIZE 0x4
+
+#define TICK_OFFSET 0x1194c
+#define TICK_SIZE 0x4
+
+#define CLINT_OFFSET 0x2000000
+#define CLINT_SIZE 0x10000
+
+#define PLIC_OFFSET 0xc000000
+#define PLIC_SIZE 0x4000000
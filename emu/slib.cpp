@@ -55,12 +55,8 @@ void push_vec(Vec *vec, const void *item)
     }
 }
 
-// 64 kb
-#define MEMORY_SIZE 64000
-
 // Memory-mapped heap.
 #define HEAP (volatile uint8_t *)HEAP_OFFSET
-#define HEAP_OFFSET 0x8
 
 struct Alloc {
     uint16_t size;
@@ -175,7 +171,16 @@ void free(void *block)
 
 // Memory-mapped console.
 #define CONSOLE_OUT (*((volatile uint8_t *)CONSOLE_OFFSET))
-#define CONSOLE_OFFSET 0x4
+#define CONSOLE_IN (*((volatile uint8_t *)CONSOLE_OFFSET))
+#define CONSOLE_STATUS (*((volatile uint8_t *)(CONSOLE_OFFSET + 1)))
+
+int getchar()
+{
+    if (!CONSOLE_STATUS) {
+        return -1;
+    }
+    return CONSOLE_IN;
+}
 
 static void print_char(char c)
 {
@@ -261,7 +266,7 @@ void printf(const char *fmt, ...)
 // extern void* __sdata;
 // extern void* __edata;
 
-#define EXIT *((volatile char *)0x1)
+#define EXIT *((volatile char *)EXIT_OFFSET)
 
 extern int main();
 
@@ -1,29 +1,27 @@
 #include <stddef.h>
 #include <stdint.h>
 
+#include "machine.h"
+
 #define TICK_RATE 30
 // Memory-mapped system tick.
 #define TICK ((volatile uint8_t *)TICK_OFFSET)
-#define TICK_OFFSET 0x1194C
 
 #define DEBUG 1
 #define assert(X) \
-    if (!(X)) (*((volatile char *)0x0))
-#define PANIC (*((volatile char *)0x0))
+    if (!(X)) (*((volatile char *)NULL_OFFSET))
+#define PANIC (*((volatile char *)NULL_OFFSET))
 
 // Memory-mapped screen.
 #define SCREEN ((volatile uint8_t *)SCREEN_OFFSET)
 #define SCREEN_WIDTH 320
 #define SCREEN_HEIGHT 200
 #define SCREEN_BYTES ((SCREEN_WIDTH * SCREEN_HEIGHT) / 8)
-#define SCREEN_OFFSET 0xFA08
 
-#define _RENDER 0x2
-#define RENDER (*((volatile uint8_t *)_RENDER))
+#define RENDER (*((volatile uint8_t *)RENDER_OFFSET))
 
 // Memory-mapped button.
 #define BUTTON ((volatile uint8_t *)BUTTON_OFFSET)
-#define BUTTON_OFFSET 0x11948
 
 // Allocate memory of size `bytes` from memory-mapped heap.
 void *malloc(int bytes);
@@ -32,6 +30,8 @@ void free(void *block);
 void *memset(void *s, int c, size_t n);
 // Write the formatted string `fmt` to the memory-mapped console.
 void printf(const char *fmt, ...);
+// Next byte of input from the memory-mapped console, or -1 if none has arrived yet.
+int getchar();
 
 struct Vec {
     uint32_t cap;
@@ -0,0 +1,165 @@
+use crate::memory::MemoryFault;
+use std::fmt;
+use std::ops::Range;
+
+/// `magic2` of the RISC-V kernel `Image` header, at byte 56.
+pub const IMAGE_MAGIC: &[u8; 4] = b"RSC\x05";
+const HEADER_SIZE: usize = 64;
+
+/// RV64 kernels must start on a 2 MiB boundary.
+pub const KERNEL_ALIGN: u64 = 2 << 20;
+/// Most RAM past the kernel that is skipped before the initramfs, as QEMU does.
+const INITRD_GAP: u64 = 128 << 20;
+
+/// Fields of the header at the start of a RISC-V Linux `Image`.
+///
+/// See `Documentation/arch/riscv/boot-image-header.rst` in the kernel tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelHeader {
+    /// Offset from the start of RAM the kernel expects to be loaded at.
+    pub text_offset: u64,
+    /// Bytes the kernel occupies once running, including BSS.
+    pub image_size: u64,
+}
+
+#[derive(Debug)]
+pub enum BootError {
+    /// Not a RISC-V kernel `Image`.
+    Header,
+    /// `what` does not fit in RAM next to everything placed before it.
+    DoesNotFit(&'static str),
+    Fault(MemoryFault),
+}
+
+impl fmt::Display for BootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Header => write!(f, "missing RISC-V kernel Image header"),
+            Self::DoesNotFit(what) => write!(f, "{what} does not fit in RAM"),
+            Self::Fault(fault) => write!(f, "could not write boot image: {fault}"),
+        }
+    }
+}
+
+impl std::error::Error for BootError {}
+
+/// Whether `data` is a RISC-V kernel `Image`.
+pub fn is_kernel_image(data: &[u8]) -> bool {
+    data.get(56..60) == Some(IMAGE_MAGIC)
+}
+
+impl KernelHeader {
+    pub fn parse(data: &[u8]) -> Result<Self, BootError> {
+        if data.len() < HEADER_SIZE || !is_kernel_image(data) {
+            return Err(BootError::Header);
+        }
+        let field =
+            |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+
+        Ok(Self {
+            text_offset: field(8),
+            image_size: field(16).max(data.len() as u64),
+        })
+    }
+}
+
+/// Where the kernel, device tree and initramfs go in RAM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootLayout {
+    pub kernel: Range<u64>,
+    pub dtb: Range<u64>,
+    pub initrd: Option<Range<u64>>,
+}
+
+impl BootLayout {
+    /// Place a kernel, a device tree of `dtb_len` bytes and an optional initramfs of
+    /// `initrd_len` bytes in `ram` the way QEMU's `virt` machine does.
+    ///
+    /// The kernel goes `text_offset` into the first 2 MiB aligned RAM, the initramfs half
+    /// way through RAM (at most 128 MiB past the kernel) and the device tree at the last
+    /// 2 MiB boundary it fits below, staying under 3 GiB for 32 bit DMA.
+    pub fn new(
+        ram: Range<u64>,
+        header: &KernelHeader,
+        dtb_len: u64,
+        initrd_len: Option<u64>,
+    ) -> Result<Self, BootError> {
+        // The header is untrusted, an offset or size near `u64::MAX` must not wrap around.
+        let kernel = ram
+            .start
+            .checked_next_multiple_of(KERNEL_ALIGN)
+            .and_then(|base| base.checked_add(header.text_offset))
+            .and_then(|start| Some(start..start.checked_add(header.image_size)?))
+            .filter(|kernel| kernel.end <= ram.end)
+            .ok_or(BootError::DoesNotFit("kernel"))?;
+
+        let dtb_end = ram.end.min(3 << 30);
+        let dtb_start = dtb_end
+            .checked_sub(dtb_len)
+            .map(|start| start / KERNEL_ALIGN * KERNEL_ALIGN)
+            .filter(|start| *start >= kernel.end)
+            .ok_or(BootError::DoesNotFit("device tree"))?;
+        let dtb = dtb_start..dtb_start + dtb_len;
+
+        let initrd = match initrd_len {
+            Some(len) => {
+                let start = (kernel.start + ((ram.end - ram.start) / 2).min(INITRD_GAP))
+                    .max(kernel.end)
+                    .next_multiple_of(8);
+                let end = start
+                    .checked_add(len)
+                    .filter(|end| *end <= dtb.start)
+                    .ok_or(BootError::DoesNotFit("initramfs"))?;
+                Some(start..end)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            kernel,
+            dtb,
+            initrd,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(text_offset: u64, image_size: u64) -> Vec<u8> {
+        let mut data = vec![0; HEADER_SIZE];
+        data[8..16].copy_from_slice(&text_offset.to_le_bytes());
+        data[16..24].copy_from_slice(&image_size.to_le_bytes());
+        data[56..60].copy_from_slice(IMAGE_MAGIC);
+        data
+    }
+
+    #[test]
+    fn places_like_qemu_virt() {
+        let header = KernelHeader::parse(&image(0x20_0000, 0x100_0000)).unwrap();
+        let ram = 0x8000_0000..0x8000_0000 + (512 << 20);
+        let layout = BootLayout::new(ram.clone(), &header, 0x2000, Some(0x1000)).unwrap();
+
+        assert_eq!(0x8020_0000..0x8120_0000, layout.kernel);
+        assert_eq!(Some(0x8820_0000..0x8820_1000), layout.initrd);
+        assert_eq!(0x9fe0_0000..0x9fe0_2000, layout.dtb);
+
+        let small = 0x8000_0000..0x8000_0000 + (18 << 20);
+        assert!(matches!(
+            BootLayout::new(small, &header, 0x2000, None),
+            Err(BootError::DoesNotFit("device tree"))
+        ));
+        for (text_offset, image_size) in [(u64::MAX, 0x100_0000), (0x20_0000, u64::MAX)] {
+            let header = KernelHeader::parse(&image(text_offset, image_size)).unwrap();
+            assert!(matches!(
+                BootLayout::new(ram.clone(), &header, 0x2000, None),
+                Err(BootError::DoesNotFit("kernel"))
+            ));
+        }
+        assert!(matches!(
+            KernelHeader::parse(&[0; HEADER_SIZE]),
+            Err(BootError::Header)
+        ));
+    }
+}
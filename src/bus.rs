@@ -0,0 +1,227 @@
+use crate::devices::Plic;
+use crate::memory::{Memory, Perms};
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::ops::Range;
+
+/// Side effect of a device access that the emulator has to act on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// Exit with the code in `a0`.
+    Exit,
+    NullAccess,
+    Render,
+    /// The guest polled for input and found none, a chance for the host to provide some.
+    InputEmpty,
+    /// The guest wrote a command to [`Htif`](crate::devices::Htif)'s `tohost`.
+    Htif,
+}
+
+/// Host state a device can reach during an access.
+pub struct DeviceCtx<'a> {
+    pub console: &'a mut Vec<u8>,
+    /// Host input not yet read by the guest.
+    pub input: &'a mut VecDeque<u8>,
+}
+
+/// Downcasting helpers, implemented for every [`Device`].
+pub trait AsAny {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Device + 'static> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Boxed cloning, implemented for every [`Device`] that is [`Clone`].
+pub trait BoxClone {
+    fn box_clone(&self) -> Box<dyn Device>;
+}
+
+impl<T: Device + Clone + 'static> BoxClone for T {
+    fn box_clone(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn Device> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/// A memory-mapped device.
+///
+/// Offsets are relative to the base address the device is attached at.
+pub trait Device: AsAny + BoxClone + Debug + Send + Sync {
+    fn read(&mut self, offset: u64, buf: &mut [u8], ctx: &mut DeviceCtx) -> Option<Signal>;
+
+    fn write(&mut self, offset: u64, data: &[u8], ctx: &mut DeviceCtx) -> Option<Signal>;
+
+    /// Advance the device by one system tick.
+    fn tick(&mut self) {}
+
+    /// Whether the device is asserting its interrupt line.
+    fn interrupt_pending(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mapping {
+    range: Range<u64>,
+    irq: Option<u32>,
+    device: Box<dyn Device>,
+}
+
+/// Routes guest accesses to attached devices, falling back to RAM.
+#[derive(Debug, Clone)]
+pub struct Bus {
+    memory: Memory,
+    mappings: Vec<Mapping>,
+}
+
+impl Bus {
+    pub fn new(memory: Memory) -> Self {
+        Self {
+            memory,
+            mappings: Vec::new(),
+        }
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    /// Map `device` at `base..base + size`, optionally wired to interrupt line `irq`.
+    ///
+    /// Panics if the range overlaps a device that is already attached.
+    pub fn attach(
+        &mut self,
+        base: u64,
+        size: u64,
+        irq: Option<u32>,
+        device: impl Device + 'static,
+    ) {
+        let range = base..base + size;
+        if let Some(mapping) = self
+            .mappings
+            .iter()
+            .find(|m| m.range.start < range.end && range.start < m.range.end)
+        {
+            panic!(
+                "device at {:#x}..{:#x} overlaps {:?} at {:#x}..{:#x}",
+                range.start, range.end, mapping.device, mapping.range.start, mapping.range.end
+            );
+        }
+
+        self.mappings.push(Mapping {
+            range,
+            irq,
+            device: Box::new(device),
+        });
+    }
+
+    /// First attached device of type `T`.
+    pub fn device<T: Device + 'static>(&self) -> Option<&T> {
+        self.mappings
+            .iter()
+            .find_map(|m| m.device.as_any().downcast_ref::<T>())
+    }
+
+    /// Address range of the first attached device of type `T`.
+    pub fn device_range<T: Device + 'static>(&self) -> Option<Range<u64>> {
+        self.mappings
+            .iter()
+            .find(|m| m.device.as_any().is::<T>())
+            .map(|m| m.range.clone())
+    }
+
+    pub fn device_mut<T: Device + 'static>(&mut self) -> Option<&mut T> {
+        self.mappings
+            .iter_mut()
+            .find_map(|m| m.device.as_any_mut().downcast_mut::<T>())
+    }
+
+    /// Permissions for an access of `len` bytes at `addr`, `None` if nothing is mapped there.
+    ///
+    /// Devices can be read and written but never executed.
+    pub fn permissions(&self, addr: u64, len: usize) -> Option<Perms> {
+        if !self.memory.in_address_space(addr, len) {
+            return None;
+        }
+
+        if self.has_device(addr) {
+            Some(Perms::RW)
+        } else {
+            self.memory.perms_at(addr, len)
+        }
+    }
+
+    /// Whether a device is mapped at `addr`.
+    pub fn has_device(&self, addr: u64) -> bool {
+        self.mappings.iter().any(|m| m.range.contains(&addr))
+    }
+
+    /// Device mapped at `addr` along with its base address.
+    fn route(&mut self, addr: u64) -> Option<(u64, &mut Box<dyn Device>)> {
+        self.mappings
+            .iter_mut()
+            .find(|m| m.range.contains(&addr))
+            .map(|m| (m.range.start, &mut m.device))
+    }
+
+    /// Accesses are routed by their first byte, a device receives the whole access.
+    pub fn read(&mut self, addr: u64, buf: &mut [u8], ctx: &mut DeviceCtx) -> Option<Signal> {
+        match self.route(addr) {
+            Some((base, device)) => device.read(addr - base, buf, ctx),
+            None => {
+                self.memory.read(addr, buf);
+                None
+            }
+        }
+    }
+
+    pub fn write(&mut self, addr: u64, data: &[u8], ctx: &mut DeviceCtx) -> Option<Signal> {
+        match self.route(addr) {
+            Some((base, device)) => device.write(addr - base, data, ctx),
+            None => {
+                self.memory.write(addr, data);
+                None
+            }
+        }
+    }
+
+    /// Advance every device, then latch the asserted interrupt lines into the [`Plic`].
+    pub fn tick(&mut self) {
+        for mapping in self.mappings.iter_mut() {
+            mapping.device.tick();
+        }
+
+        let lines = self.pending_interrupts();
+        if let Some(plic) = self.device_mut::<Plic>() {
+            plic.set_pending(lines);
+        }
+    }
+
+    /// Bit mask of the interrupt lines currently asserted.
+    pub fn pending_interrupts(&self) -> u64 {
+        self.mappings
+            .iter()
+            .filter(|m| m.device.interrupt_pending())
+            .filter_map(|m| m.irq)
+            .fold(0, |mask, irq| mask | 1 << irq)
+    }
+}
@@ -0,0 +1,131 @@
+use crate::devices::TICK_HZ;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Instructions retired per second of virtual time.
+pub const VIRTUAL_HZ: u64 = 10_000_000;
+
+/// Wall clock time a virtual clock starts at, 2024-01-01T00:00:00Z.
+const VIRTUAL_EPOCH: Duration = Duration::from_secs(1_704_067_200);
+
+/// Where the guest's time and randomness come from.
+#[derive(Debug, Clone)]
+pub enum Clock {
+    /// Host time and entropy, devices tick whenever the host calls
+    /// [`Emulator::tick`](crate::emulator::Emulator::tick).
+    Host { started: Instant },
+    /// Time counted in retired instructions at [`VIRTUAL_HZ`] and randomness drawn from a
+    /// seeded generator, so runs with the same seed and input behave the same. Devices tick
+    /// every `VIRTUAL_HZ / TICK_HZ` instructions.
+    Virtual { rng: u64 },
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::host()
+    }
+}
+
+impl Clock {
+    pub fn host() -> Self {
+        Self::Host {
+            started: Instant::now(),
+        }
+    }
+
+    pub fn seeded(seed: u64) -> Self {
+        Self::Virtual { rng: seed }
+    }
+
+    pub fn is_virtual(&self) -> bool {
+        matches!(self, Self::Virtual { .. })
+    }
+
+    /// Time since the guest started, once it has retired `instret` instructions.
+    pub fn uptime(&self, instret: u64) -> Duration {
+        match self {
+            Self::Host { started } => started.elapsed(),
+            Self::Virtual { .. } => {
+                Duration::from_nanos(instret.saturating_mul(1_000_000_000 / VIRTUAL_HZ))
+            }
+        }
+    }
+
+    /// Device ticks since the guest started, the timebase of the `time` CSR. On a virtual
+    /// clock these are the ticks the devices saw.
+    pub fn ticks(&self, instret: u64) -> u64 {
+        match self {
+            Self::Host { started } => {
+                (started.elapsed().as_nanos() * TICK_HZ as u128 / 1_000_000_000) as u64
+            }
+            Self::Virtual { .. } => instret / (VIRTUAL_HZ / TICK_HZ),
+        }
+    }
+
+    /// Time since the Unix epoch, once the guest has retired `instret` instructions.
+    pub fn wall_time(&self, instret: u64) -> Duration {
+        match self {
+            Self::Host { .. } => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            Self::Virtual { .. } => VIRTUAL_EPOCH + self.uptime(instret),
+        }
+    }
+
+    /// Fill `buf` with random bytes.
+    pub fn fill_random(&mut self, buf: &mut [u8]) {
+        let Self::Virtual { rng } = self else {
+            crate::process::host_entropy(buf);
+            return;
+        };
+
+        // SplitMix64.
+        for chunk in buf.chunks_mut(8) {
+            *rng = rng.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut z = *rng;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            z ^= z >> 31;
+            chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::Clint;
+    use crate::primitives::Reg;
+    use crate::testing::{machine, SPIN};
+
+    #[test]
+    fn time_follows_instructions() {
+        let (mut emu, _) = machine(&SPIN);
+        emu.set_clock(Clock::seeded(1));
+        emu.run_for((VIRTUAL_HZ / TICK_HZ * 2) as usize);
+
+        assert_eq!(VIRTUAL_HZ / TICK_HZ * 2, emu.instret());
+        assert_eq!(2, emu.bus().device::<Clint>().unwrap().mtime());
+        assert_eq!(33, emu.uptime().as_millis());
+        assert_eq!(VIRTUAL_EPOCH + emu.uptime(), emu.wall_time());
+
+        let pc = emu.pc() + 4;
+        emu.flash_prgm(&[0xf3, 0x22, 0x10, 0xc0], pc).unwrap(); // rdtime t0
+        emu.set_pc(pc);
+        emu.run_for(1);
+        assert_eq!(2, emu.reg(Reg::T(0)));
+    }
+
+    #[test]
+    fn seed_picks_random_bytes() {
+        let random = |seed| {
+            let mut buf = [0; 12];
+            let (mut emu, _) = machine(&SPIN);
+            emu.set_clock(Clock::seeded(seed));
+            emu.fill_random(&mut buf);
+            buf
+        };
+
+        assert_eq!(random(1), random(1));
+        assert_ne!(random(1), random(2));
+    }
+}
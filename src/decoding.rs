@@ -1,7 +1,8 @@
 use crate::instruction_set::*;
 use crate::primitives::*;
 
-pub fn decode(raw_instr: u32, pc: u64) -> Instr {
+/// Unknown encodings decode to [`Instr::Illegal`].
+pub fn decode(raw_instr: u32) -> Instr {
     let opcode = raw_instr & 0b1111111;
     match opcode {
         // R-type
@@ -28,42 +29,49 @@ pub fn decode(raw_instr: u32, pc: u64) -> Instr {
                         0b101 => Instr::Srl(rd, rs1, rs2),
                         0b110 => Instr::Or(rd, rs1, rs2),
                         0b111 => Instr::And(rd, rs1, rs2),
-                        val => panic!("{val:#b}"),
+                        _ => Instr::Illegal(raw_instr),
                     },
                     0b0100000 => match fn3 {
                         0b000 => Instr::Sub(rd, rs1, rs2),
                         0b101 => Instr::Sra(rd, rs1, rs2),
-                        val => panic!("{val:#b}"),
+                        _ => Instr::Illegal(raw_instr),
                     },
                     0b0000001 => match fn3 {
                         0b000 => Instr::Mul(rd, rs1, rs2),
+                        0b001 => Instr::Mulh(rd, rs1, rs2),
+                        0b010 => Instr::Mulhsu(rd, rs1, rs2),
+                        0b011 => Instr::Mulhu(rd, rs1, rs2),
                         0b100 => Instr::Div(rd, rs1, rs2),
+                        0b101 => Instr::Divu(rd, rs1, rs2),
                         0b110 => Instr::Rem(rd, rs1, rs2),
-                        val => panic!("{val:#b}"),
+                        0b111 => Instr::Remu(rd, rs1, rs2),
+                        _ => Instr::Illegal(raw_instr),
                     },
-                    val => panic!("{val:#b}"),
+                    _ => Instr::Illegal(raw_instr),
                 },
                 0b111011 => match fn7 {
                     0b000 => match fn3 {
                         0b000 => Instr::Addw(rd, rs1, rs2),
                         0b001 => Instr::Sllw(rd, rs1, rs2),
                         0b101 => Instr::Srlw(rd, rs1, rs2),
-                        val => panic!("{val:#b}"),
+                        _ => Instr::Illegal(raw_instr),
                     },
                     0b0100000 => match fn3 {
                         0b000 => Instr::Subw(rd, rs1, rs2),
                         0b101 => Instr::Sraw(rd, rs1, rs2),
-                        val => panic!("{val:#b}"),
+                        _ => Instr::Illegal(raw_instr),
                     },
                     0b0000001 => match fn3 {
                         0b000 => Instr::Mulw(rd, rs1, rs2),
                         0b100 => Instr::Divw(rd, rs1, rs2),
+                        0b101 => Instr::Divuw(rd, rs1, rs2),
                         0b110 => Instr::Remw(rd, rs1, rs2),
-                        val => panic!("{val:#b}"),
+                        0b111 => Instr::Remuw(rd, rs1, rs2),
+                        _ => Instr::Illegal(raw_instr),
                     },
-                    val => panic!("{val:#b}"),
+                    _ => Instr::Illegal(raw_instr),
                 },
-                val => panic!("{val:#b}"),
+                _ => Instr::Illegal(raw_instr),
             }
         }
         // I-type
@@ -94,7 +102,7 @@ pub fn decode(raw_instr: u32, pc: u64) -> Instr {
                     0b100 => Instr::Lbu(rd, Offset(rs1, imm)),
                     0b101 => Instr::Lhu(rd, Offset(rs1, imm)),
                     0b110 => Instr::Lwu(rd, Offset(rs1, imm)),
-                    _ => panic!("Invalid fn3 for LOAD: {fn3:#b}"),
+                    _ => Instr::Illegal(raw_instr),
                 },
                 0x13 => match fn3 {
                     0b000 => Instr::Addi(rd, rs1, imm),
@@ -107,13 +115,13 @@ pub fn decode(raw_instr: u32, pc: u64) -> Instr {
                     0b101 => match fn7 >> 1 {
                         0b000000 => Instr::Srli(rd, rs1, shamt),
                         0b010000 => Instr::Srai(rd, rs1, shamt),
-                        _ => panic!("Invalid fn7 for SRLI/SRAI: {fn7:#b}"),
+                        _ => Instr::Illegal(raw_instr),
                     },
-                    _ => panic!("Invalid fn3 for OP-IMM: {fn3:#b}"),
+                    _ => Instr::Illegal(raw_instr),
                 },
                 0x67 => match fn3 {
                     0b000 => Instr::Jalr(rd, rs1, imm),
-                    _ => panic!("Invalid fn3 for JALR: {fn3:#b}"),
+                    _ => Instr::Illegal(raw_instr),
                 },
                 0x1b => match fn3 {
                     0b000 => Instr::Addiw(rd, rs1, imm),
@@ -121,9 +129,9 @@ pub fn decode(raw_instr: u32, pc: u64) -> Instr {
                     0b101 => match fn7 {
                         0b0000000 => Instr::Srliw(rd, rs1, shamt),
                         0b0100000 => Instr::Sraiw(rd, rs1, shamt),
-                        _ => panic!("Invalid fn7 for SRLIW/SRAIW: {fn7:#b}"),
+                        _ => Instr::Illegal(raw_instr),
                     },
-                    _ => panic!("Invalid fn3 for OP-IMM-32: {fn3:#b}"),
+                    _ => Instr::Illegal(raw_instr),
                 },
                 _ => unreachable!(),
             }
@@ -157,7 +165,7 @@ pub fn decode(raw_instr: u32, pc: u64) -> Instr {
                 0b001 => Instr::Sh(rs2, offset),
                 0b010 => Instr::Sw(rs2, offset),
                 0b011 => Instr::Sd(rs2, offset),
-                val => panic!("{val:#b}"),
+                _ => Instr::Illegal(raw_instr),
             }
         }
         // B-type
@@ -167,11 +175,11 @@ pub fn decode(raw_instr: u32, pc: u64) -> Instr {
             let rs2 = (raw_instr >> 20) & 0b11111;
 
             let mut imm: i64 = 0;
-            let raw_instr = raw_instr as i64;
-            imm |= ((raw_instr >> 31) & 0x1) << 12; // imm[12]
-            imm |= ((raw_instr >> 7) & 0x1) << 11; // imm[11]
-            imm |= ((raw_instr >> 25) & 0x3f) << 5; // imm[10:5]
-            imm |= ((raw_instr >> 8) & 0xf) << 1; // imm[4:1]
+            let raw = raw_instr as i64;
+            imm |= ((raw >> 31) & 0x1) << 12; // imm[12]
+            imm |= ((raw >> 7) & 0x1) << 11; // imm[11]
+            imm |= ((raw >> 25) & 0x3f) << 5; // imm[10:5]
+            imm |= ((raw >> 8) & 0xf) << 1; // imm[4:1]
 
             // Sign extend
             if (imm & 0x1000) > 0 {
@@ -191,7 +199,7 @@ pub fn decode(raw_instr: u32, pc: u64) -> Instr {
                 0b101 => Instr::Bge(rs1, rs2, imm),
                 0b110 => Instr::Bltu(rs1, rs2, imm),
                 0b111 => Instr::Bgeu(rs1, rs2, imm),
-                val => panic!("{val:#b}"),
+                _ => Instr::Illegal(raw_instr),
             }
         }
         // U-type
@@ -212,7 +220,7 @@ pub fn decode(raw_instr: u32, pc: u64) -> Instr {
             match opcode {
                 0b0110111 => Instr::Lui(rd, imm),
                 0b0010111 => Instr::Auipc(rd, imm),
-                val => panic!("{val:#b}"),
+                _ => Instr::Illegal(raw_instr),
             }
         }
         // J-type
@@ -238,7 +246,72 @@ pub fn decode(raw_instr: u32, pc: u64) -> Instr {
 
             Instr::Jal(rd, imm)
         }
-        0b1110011 => Instr::Ecall,
-        opcode => panic!("invalid opcode: {:#x}, pc: {:#x}", opcode, pc),
+        0b0001111 => match (raw_instr >> 12) & 0b111 {
+            0b000 | 0b001 => Instr::Fence,
+            _ => Instr::Illegal(raw_instr),
+        },
+        // A extension
+        0b0101111 => {
+            let rd = Reg::new((raw_instr >> 7) & 0b11111);
+            let fn3 = (raw_instr >> 12) & 0b111;
+            let rs1 = Reg::new((raw_instr >> 15) & 0b11111);
+            let rs2 = (raw_instr >> 20) & 0b11111;
+            let fn5 = raw_instr >> 27;
+
+            let op = match fn5 {
+                0b00001 => AmoOp::Swap,
+                0b00000 => AmoOp::Add,
+                0b00100 => AmoOp::Xor,
+                0b01100 => AmoOp::And,
+                0b01000 => AmoOp::Or,
+                0b10000 => AmoOp::Min,
+                0b10100 => AmoOp::Max,
+                0b11000 => AmoOp::Minu,
+                0b11100 => AmoOp::Maxu,
+                0b00010 if rs2 == 0 => {
+                    return match fn3 {
+                        0b010 => Instr::LrW(rd, rs1),
+                        0b011 => Instr::LrD(rd, rs1),
+                        _ => Instr::Illegal(raw_instr),
+                    }
+                }
+                0b00011 => {
+                    return match fn3 {
+                        0b010 => Instr::ScW(rd, rs1, Reg::new(rs2)),
+                        0b011 => Instr::ScD(rd, rs1, Reg::new(rs2)),
+                        _ => Instr::Illegal(raw_instr),
+                    }
+                }
+                _ => return Instr::Illegal(raw_instr),
+            };
+            match fn3 {
+                0b010 => Instr::AmoW(op, rd, rs1, Reg::new(rs2)),
+                0b011 => Instr::AmoD(op, rd, rs1, Reg::new(rs2)),
+                _ => Instr::Illegal(raw_instr),
+            }
+        }
+        // Only reads of the counters are implemented out of the CSR instructions, that is
+        // `csrrs` or `csrrc` without a source register or immediate.
+        0b1110011 => {
+            let rd = Reg::new((raw_instr >> 7) & 0b11111);
+            let fn3 = (raw_instr >> 12) & 0b111;
+            let rs1 = (raw_instr >> 15) & 0b11111;
+            let counter = match raw_instr >> 20 {
+                0xC00 => Some(Counter::Cycle),
+                0xC01 => Some(Counter::Time),
+                0xC02 => Some(Counter::Instret),
+                _ => None,
+            };
+
+            match (raw_instr, fn3, counter) {
+                (0x0000_0073, _, _) => Instr::Ecall,
+                (0x0010_0073, _, _) => Instr::Ebreak,
+                (_, 0b010 | 0b011 | 0b110 | 0b111, Some(counter)) if rs1 == 0 => {
+                    Instr::Csrr(rd, counter)
+                }
+                _ => Instr::Illegal(raw_instr),
+            }
+        }
+        _ => Instr::Illegal(raw_instr),
     }
 }
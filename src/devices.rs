@@ -0,0 +1,524 @@
+use crate::bus::{Device, DeviceCtx, Signal};
+use crate::io::Button;
+
+pub const SCREEN_WIDTH: usize = 320;
+pub const SCREEN_HEIGHT: usize = 200;
+pub const SCREEN_SIZE: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
+
+/// Copy `reg[offset..]` into `buf`, zero filling past the end of the register.
+fn read_register(reg: &[u8], offset: u64, buf: &mut [u8]) {
+    buf.fill(0);
+    if let Some(reg) = reg.get(offset as usize..) {
+        let len = reg.len().min(buf.len());
+        buf[..len].copy_from_slice(&reg[..len]);
+    }
+}
+
+/// Merge `data` into `reg[offset..]`, dropping bytes past the end of the register.
+fn write_register(reg: &mut [u8], offset: u64, data: &[u8]) {
+    if let Some(reg) = reg.get_mut(offset as usize..) {
+        let len = reg.len().min(data.len());
+        reg[..len].copy_from_slice(&data[..len]);
+    }
+}
+
+/// Traps any access, guarding the null page.
+#[derive(Debug, Clone)]
+pub struct NullGuard;
+
+impl Device for NullGuard {
+    fn read(&mut self, _offset: u64, buf: &mut [u8], _ctx: &mut DeviceCtx) -> Option<Signal> {
+        buf.fill(0);
+        Some(Signal::NullAccess)
+    }
+
+    fn write(&mut self, _offset: u64, _data: &[u8], _ctx: &mut DeviceCtx) -> Option<Signal> {
+        Some(Signal::NullAccess)
+    }
+}
+
+/// Exits with the code in `a0` when read.
+#[derive(Debug, Clone)]
+pub struct ExitPort;
+
+impl Device for ExitPort {
+    fn read(&mut self, _offset: u64, buf: &mut [u8], _ctx: &mut DeviceCtx) -> Option<Signal> {
+        buf.fill(0);
+        Some(Signal::Exit)
+    }
+
+    fn write(&mut self, _offset: u64, _data: &[u8], _ctx: &mut DeviceCtx) -> Option<Signal> {
+        None
+    }
+}
+
+/// Requests a frame when read.
+#[derive(Debug, Clone)]
+pub struct RenderPort;
+
+impl Device for RenderPort {
+    fn read(&mut self, _offset: u64, buf: &mut [u8], _ctx: &mut DeviceCtx) -> Option<Signal> {
+        buf.fill(0);
+        Some(Signal::Render)
+    }
+
+    fn write(&mut self, _offset: u64, _data: &[u8], _ctx: &mut DeviceCtx) -> Option<Signal> {
+        None
+    }
+}
+
+/// Appends bytes written to its first register to the console.
+///
+/// Reading the first register takes the next byte of host input, or all ones when there is
+/// none. The second register reads 1 while input is waiting and 0 otherwise. Reading the
+/// first register with no input stops the emulator, so a guest waiting on it gives the host
+/// a chance to provide some, polling the second one never does.
+#[derive(Debug, Clone)]
+pub struct ConsolePort;
+
+impl ConsolePort {
+    pub const RX_STATUS: u64 = 1;
+}
+
+impl Device for ConsolePort {
+    fn read(&mut self, offset: u64, buf: &mut [u8], ctx: &mut DeviceCtx) -> Option<Signal> {
+        buf.fill(0);
+        match offset {
+            0 => match ctx.input.pop_front() {
+                Some(byte) => buf[0] = byte,
+                None => {
+                    buf.fill(0xFF);
+                    return Some(Signal::InputEmpty);
+                }
+            },
+            Self::RX_STATUS => buf[0] = !ctx.input.is_empty() as u8,
+            _ => {}
+        }
+        None
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8], ctx: &mut DeviceCtx) -> Option<Signal> {
+        if offset == 0 {
+            ctx.console.push(data[0]);
+        }
+        None
+    }
+}
+
+/// 1 bit per pixel framebuffer.
+#[derive(Debug, Clone)]
+pub struct Screen {
+    framebuffer: Vec<u8>,
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Self {
+            framebuffer: vec![0; SCREEN_SIZE / 8],
+        }
+    }
+}
+
+impl Screen {
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+}
+
+impl Device for Screen {
+    fn read(&mut self, offset: u64, buf: &mut [u8], _ctx: &mut DeviceCtx) -> Option<Signal> {
+        read_register(&self.framebuffer, offset, buf);
+        None
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8], _ctx: &mut DeviceCtx) -> Option<Signal> {
+        let offset = offset as usize;
+        let len = data.len().min(self.framebuffer.len() - offset);
+        self.framebuffer[offset..offset + len].copy_from_slice(&data[..len]);
+        None
+    }
+}
+
+/// Button state, one bit per [`Button`].
+#[derive(Debug, Clone, Default)]
+pub struct Buttons {
+    state: u8,
+}
+
+impl Buttons {
+    fn mask(button: Button) -> u8 {
+        let shamt = match button {
+            Button::Zero => 0,
+            Button::One => 1,
+            Button::Two => 2,
+            Button::Three => 3,
+        };
+
+        1 << shamt
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.state |= Self::mask(button);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.state &= !Self::mask(button);
+    }
+
+    pub fn pressed(&self, button: Button) -> bool {
+        self.state & Self::mask(button) != 0
+    }
+}
+
+impl Device for Buttons {
+    fn read(&mut self, offset: u64, buf: &mut [u8], _ctx: &mut DeviceCtx) -> Option<Signal> {
+        read_register(&[self.state], offset, buf);
+        None
+    }
+
+    fn write(&mut self, _offset: u64, _data: &[u8], _ctx: &mut DeviceCtx) -> Option<Signal> {
+        None
+    }
+}
+
+/// System tick counter, incremented by the host.
+#[derive(Debug, Clone, Default)]
+pub struct Tick {
+    ticks: u32,
+}
+
+impl Device for Tick {
+    fn read(&mut self, offset: u64, buf: &mut [u8], _ctx: &mut DeviceCtx) -> Option<Signal> {
+        read_register(&self.ticks.to_le_bytes(), offset, buf);
+        None
+    }
+
+    fn write(&mut self, _offset: u64, _data: &[u8], _ctx: &mut DeviceCtx) -> Option<Signal> {
+        None
+    }
+
+    fn tick(&mut self) {
+        self.ticks = self.ticks.wrapping_add(1);
+    }
+}
+
+/// Rate the host ticks devices at, see [`Device::tick`].
+pub const TICK_HZ: u64 = 60;
+
+/// Core local interruptor with the SiFive register layout: a software interrupt and a
+/// timer compare register per hart, and a shared `mtime` counting host ticks.
+#[derive(Debug, Clone)]
+pub struct Clint {
+    msip: Vec<u32>,
+    mtimecmp: Vec<u64>,
+    mtime: u64,
+}
+
+impl Clint {
+    pub const SIZE: u64 = 0x10000;
+    const MTIMECMP: u64 = 0x4000;
+    const MTIME: u64 = 0xbff8;
+
+    pub fn new(harts: usize) -> Self {
+        Self {
+            msip: vec![0; harts],
+            mtimecmp: vec![u64::MAX; harts],
+            mtime: 0,
+        }
+    }
+
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    pub fn set_mtimecmp(&mut self, hart: usize, val: u64) {
+        self.mtimecmp[hart] = val;
+    }
+
+    pub fn set_msip(&mut self, hart: usize, pending: bool) {
+        self.msip[hart] = pending as u32;
+    }
+
+    pub fn software_pending(&self, hart: usize) -> bool {
+        self.msip[hart] & 1 != 0
+    }
+
+    pub fn timer_pending(&self, hart: usize) -> bool {
+        self.mtime >= self.mtimecmp[hart]
+    }
+}
+
+impl Device for Clint {
+    fn read(&mut self, offset: u64, buf: &mut [u8], _ctx: &mut DeviceCtx) -> Option<Signal> {
+        buf.fill(0);
+        match offset {
+            Self::MTIME.. => read_register(&self.mtime.to_le_bytes(), offset - Self::MTIME, buf),
+            Self::MTIMECMP.. => {
+                let hart = (offset - Self::MTIMECMP) / 8;
+                if let Some(cmp) = self.mtimecmp.get(hart as usize) {
+                    read_register(&cmp.to_le_bytes(), (offset - Self::MTIMECMP) % 8, buf);
+                }
+            }
+            _ => {
+                if let Some(msip) = self.msip.get(offset as usize / 4) {
+                    read_register(&msip.to_le_bytes(), offset % 4, buf);
+                }
+            }
+        }
+        None
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8], _ctx: &mut DeviceCtx) -> Option<Signal> {
+        match offset {
+            Self::MTIME.. => {
+                let mut reg = self.mtime.to_le_bytes();
+                write_register(&mut reg, offset - Self::MTIME, data);
+                self.mtime = u64::from_le_bytes(reg);
+            }
+            Self::MTIMECMP.. => {
+                let hart = ((offset - Self::MTIMECMP) / 8) as usize;
+                if let Some(cmp) = self.mtimecmp.get_mut(hart) {
+                    let mut reg = cmp.to_le_bytes();
+                    write_register(&mut reg, (offset - Self::MTIMECMP) % 8, data);
+                    *cmp = u64::from_le_bytes(reg);
+                }
+            }
+            _ => {
+                if let Some(msip) = self.msip.get_mut(offset as usize / 4) {
+                    // Only bit 0 is implemented.
+                    let mut reg = msip.to_le_bytes();
+                    write_register(&mut reg, offset % 4, data);
+                    *msip = u32::from_le_bytes(reg) & 1;
+                }
+            }
+        }
+        None
+    }
+
+    fn tick(&mut self) {
+        self.mtime = self.mtime.wrapping_add(1);
+    }
+}
+
+/// Platform-level interrupt controller with the SiFive register layout.
+///
+/// Sources are the bus interrupt lines, fed in by [`Bus::tick`](crate::bus::Bus::tick).
+/// Each hart has an M-mode and an S-mode context.
+#[derive(Debug, Clone)]
+pub struct Plic {
+    priority: [u32; Self::SOURCES],
+    pending: u64,
+    /// Claimed and not yet completed.
+    in_service: u64,
+    enable: Vec<u64>,
+    threshold: Vec<u32>,
+}
+
+impl Plic {
+    pub const SIZE: u64 = 0x400_0000;
+    /// Source 0 means "no interrupt", so this is one more than the usable lines.
+    pub const SOURCES: usize = 64;
+    const PENDING: u64 = 0x1000;
+    const ENABLE: u64 = 0x2000;
+    const ENABLE_STRIDE: u64 = 0x80;
+    const CONTEXT: u64 = 0x20_0000;
+    const CONTEXT_STRIDE: u64 = 0x1000;
+
+    pub fn new(harts: usize) -> Self {
+        Self {
+            priority: [0; Self::SOURCES],
+            pending: 0,
+            in_service: 0,
+            enable: vec![0; harts * 2],
+            threshold: vec![0; harts * 2],
+        }
+    }
+
+    /// Latch the asserted lines of the bus, line `n` is source `n`.
+    pub fn set_pending(&mut self, lines: u64) {
+        self.pending |= lines & !1;
+    }
+
+    /// Highest priority enabled source pending for `context`, lowest number on ties.
+    fn best(&self, context: usize) -> Option<usize> {
+        let candidates = self.pending & !self.in_service & self.enable[context];
+        (1..Self::SOURCES)
+            .filter(|src| candidates & 1 << src != 0)
+            .filter(|src| self.priority[*src] > self.threshold[context])
+            .max_by_key(|src| (self.priority[*src], std::cmp::Reverse(*src)))
+    }
+
+    /// Whether `context` has an interrupt to claim.
+    pub fn context_pending(&self, context: usize) -> bool {
+        self.best(context).is_some()
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best(context) {
+            Some(src) => {
+                self.pending &= !(1 << src);
+                self.in_service |= 1 << src;
+                src as u32
+            }
+            None => 0,
+        }
+    }
+
+    /// Context number and offset within it for the per context registers.
+    fn context(&self, offset: u64, base: u64, stride: u64) -> Option<(usize, u64)> {
+        let context = ((offset - base) / stride) as usize;
+        (context < self.enable.len()).then_some((context, (offset - base) % stride))
+    }
+}
+
+impl Device for Plic {
+    fn read(&mut self, offset: u64, buf: &mut [u8], _ctx: &mut DeviceCtx) -> Option<Signal> {
+        buf.fill(0);
+        match offset {
+            Self::CONTEXT.. => {
+                if let Some((context, reg)) =
+                    self.context(offset, Self::CONTEXT, Self::CONTEXT_STRIDE)
+                {
+                    let val = match reg & !3 {
+                        0 => self.threshold[context],
+                        4 => self.claim(context),
+                        _ => 0,
+                    };
+                    read_register(&val.to_le_bytes(), reg % 4, buf);
+                }
+            }
+            Self::ENABLE.. => {
+                if let Some((context, reg)) =
+                    self.context(offset, Self::ENABLE, Self::ENABLE_STRIDE)
+                {
+                    read_register(&self.enable[context].to_le_bytes(), reg, buf);
+                }
+            }
+            Self::PENDING.. => {
+                read_register(&self.pending.to_le_bytes(), offset - Self::PENDING, buf)
+            }
+            _ => {
+                if let Some(priority) = self.priority.get(offset as usize / 4) {
+                    read_register(&priority.to_le_bytes(), offset % 4, buf);
+                }
+            }
+        }
+        None
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8], _ctx: &mut DeviceCtx) -> Option<Signal> {
+        match offset {
+            Self::CONTEXT.. => {
+                if let Some((context, reg)) =
+                    self.context(offset, Self::CONTEXT, Self::CONTEXT_STRIDE)
+                {
+                    let mut val = [0; 4];
+                    write_register(&mut val, reg % 4, data);
+                    let val = u32::from_le_bytes(val);
+                    match reg & !3 {
+                        0 => self.threshold[context] = val,
+                        // Completion.
+                        4 => self.in_service &= !(1u64.checked_shl(val).unwrap_or(0)),
+                        _ => {}
+                    }
+                }
+            }
+            Self::ENABLE.. => {
+                if let Some((context, reg)) =
+                    self.context(offset, Self::ENABLE, Self::ENABLE_STRIDE)
+                {
+                    let mut enable = self.enable[context].to_le_bytes();
+                    write_register(&mut enable, reg, data);
+                    self.enable[context] = u64::from_le_bytes(enable);
+                }
+            }
+            // Pending bits are read only.
+            Self::PENDING.. => {}
+            _ => {
+                if let Some(priority) = self.priority.get_mut(offset as usize / 4) {
+                    let mut reg = priority.to_le_bytes();
+                    write_register(&mut reg, offset % 4, data);
+                    *priority = u32::from_le_bytes(reg);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Berkeley host-target interface, `tohost` at offset 0 and `fromhost` further in.
+///
+/// A store completing a non-zero `tohost` hands a command to the host, see
+/// [`crate::htif`], which clears `tohost` and answers through `fromhost`.
+#[derive(Debug, Clone)]
+pub struct Htif {
+    tohost: u64,
+    fromhost: u64,
+    fromhost_offset: u64,
+    /// A console read is waiting for host input.
+    pub(crate) pending_read: bool,
+}
+
+impl Htif {
+    /// Window for the riscv-tests layout, `fromhost` 64 bytes after `tohost`.
+    pub const SIZE: u64 = 0x48;
+    pub const FROMHOST: u64 = 0x40;
+
+    pub fn new(fromhost_offset: u64) -> Self {
+        Self {
+            tohost: 0,
+            fromhost: 0,
+            fromhost_offset,
+            pending_read: false,
+        }
+    }
+
+    /// Take the command in `tohost`, acknowledging it.
+    pub fn take_command(&mut self) -> u64 {
+        std::mem::take(&mut self.tohost)
+    }
+
+    pub fn fromhost(&self) -> u64 {
+        self.fromhost
+    }
+
+    pub fn set_fromhost(&mut self, val: u64) {
+        self.fromhost = val;
+    }
+}
+
+impl Device for Htif {
+    fn read(&mut self, offset: u64, buf: &mut [u8], _ctx: &mut DeviceCtx) -> Option<Signal> {
+        buf.fill(0);
+        match offset {
+            0..8 => read_register(&self.tohost.to_le_bytes(), offset, buf),
+            _ if (self.fromhost_offset..self.fromhost_offset + 8).contains(&offset) => {
+                let offset = offset - self.fromhost_offset;
+                read_register(&self.fromhost.to_le_bytes(), offset, buf);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8], _ctx: &mut DeviceCtx) -> Option<Signal> {
+        match offset {
+            0..8 => {
+                let mut reg = self.tohost.to_le_bytes();
+                write_register(&mut reg, offset, data);
+                self.tohost = u64::from_le_bytes(reg);
+                // RV32 guests write the upper half last.
+                if offset + data.len() as u64 >= 8 && self.tohost != 0 {
+                    return Some(Signal::Htif);
+                }
+            }
+            _ if (self.fromhost_offset..self.fromhost_offset + 8).contains(&offset) => {
+                let mut reg = self.fromhost.to_le_bytes();
+                write_register(&mut reg, offset - self.fromhost_offset, data);
+                self.fromhost = u64::from_le_bytes(reg);
+            }
+            _ => {}
+        }
+        None
+    }
+}
@@ -0,0 +1,254 @@
+use crate::memory::{MemoryFault, Perms};
+use object::elf::{self, FileHeader64};
+use object::read::elf::{FileHeader, ProgramHeader};
+use object::LittleEndian;
+use std::fmt;
+
+/// Offsets into `e_ident`.
+const EI_CLASS: usize = 4;
+const EI_DATA: usize = 5;
+
+/// A `PT_LOAD` segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    /// Physical address, there is no MMU so this is where the bytes end up.
+    pub addr: u64,
+    /// File contents, zero filled up to `mem_size`.
+    pub data: Vec<u8>,
+    pub mem_size: u64,
+    pub perms: Perms,
+}
+
+/// Loadable contents of a RISC-V ELF64 executable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElfImage {
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+    /// Guest address of the program headers. When no segment maps them, they get a read
+    /// only segment of their own on the page after the others.
+    pub phdr: u64,
+    pub phent: u16,
+    pub phnum: u16,
+}
+
+#[derive(Debug)]
+pub enum ElfError {
+    Malformed(String),
+    /// Not a 64 bit little endian image.
+    Class,
+    Machine(u16),
+    /// Not an `ET_EXEC` image, relocation is not supported.
+    Type(u16),
+    /// A segment could not be written to guest memory.
+    Fault(MemoryFault),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed(err) => write!(f, "malformed ELF: {err}"),
+            Self::Class => write!(f, "expected a 64 bit little endian ELF"),
+            Self::Machine(machine) => write!(f, "expected a RISC-V ELF, found machine {machine}"),
+            Self::Type(ty) => write!(f, "expected an executable ELF, found type {ty}"),
+            Self::Fault(fault) => write!(f, "segment does not fit in guest memory: {fault}"),
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+impl From<object::Error> for ElfError {
+    fn from(err: object::Error) -> Self {
+        Self::Malformed(err.to_string())
+    }
+}
+
+/// Whether `data` starts with the ELF magic.
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(&elf::ELFMAG)
+}
+
+impl ElfImage {
+    pub fn parse(data: &[u8]) -> Result<Self, ElfError> {
+        if !is_elf(data) {
+            return Err(ElfError::Malformed("missing ELF magic".into()));
+        }
+        if data.get(EI_CLASS) != Some(&elf::ELFCLASS64)
+            || data.get(EI_DATA) != Some(&elf::ELFDATA2LSB)
+        {
+            return Err(ElfError::Class);
+        }
+
+        let header = FileHeader64::<LittleEndian>::parse(data)?;
+        let endian = header.endian()?;
+        if header.e_machine(endian) != elf::EM_RISCV {
+            return Err(ElfError::Machine(header.e_machine(endian)));
+        }
+        if header.e_type(endian) != elf::ET_EXEC {
+            return Err(ElfError::Type(header.e_type(endian)));
+        }
+
+        let phoff = header.e_phoff(endian);
+        let mut phdr_addr = None;
+        let mut segments = Vec::new();
+        for phdr in header.program_headers(endian, data)? {
+            if phdr.p_type(endian) == elf::PT_PHDR {
+                phdr_addr = Some(phdr.p_paddr(endian));
+            }
+            if phdr.p_type(endian) != elf::PT_LOAD {
+                continue;
+            }
+
+            let flags = phdr.p_flags(endian);
+            let data = phdr
+                .data(endian, data)
+                .map_err(|_| ElfError::Malformed("segment data out of bounds".into()))?;
+            let addr = phdr.p_paddr(endian);
+            let mem_size = phdr.p_memsz(endian).max(data.len() as u64);
+            if addr.checked_add(mem_size).is_none() {
+                return Err(ElfError::Malformed("segment wraps around memory".into()));
+            }
+
+            // In bounds of the file after the check above, and inside the segment in memory.
+            let offset = phdr.p_offset(endian);
+            let file_end = offset
+                .checked_add(phdr.p_filesz(endian))
+                .ok_or_else(|| ElfError::Malformed("segment data out of bounds".into()))?;
+            if phdr_addr.is_none() && (offset..file_end).contains(&phoff) {
+                phdr_addr = Some(addr + phoff - offset);
+            }
+
+            segments.push(Segment {
+                addr,
+                data: data.to_vec(),
+                mem_size,
+                perms: Perms::new(
+                    flags & elf::PF_R != 0,
+                    flags & elf::PF_W != 0,
+                    flags & elf::PF_X != 0,
+                ),
+            });
+        }
+
+        let phdr = match phdr_addr {
+            Some(addr) => addr,
+            None => {
+                let table = phoff as usize
+                    ..phoff as usize
+                        + header.e_phnum(endian) as usize * header.e_phentsize(endian) as usize;
+                let table = data
+                    .get(table)
+                    .ok_or_else(|| ElfError::Malformed("program headers out of bounds".into()))?;
+                let addr = segments
+                    .iter()
+                    .try_fold(0, |end: u64, segment| {
+                        Some(end.max(segment.addr.checked_add(segment.mem_size)?))
+                    })
+                    .and_then(|end| end.checked_next_multiple_of(crate::memory::PAGE_SIZE as u64))
+                    .ok_or_else(|| ElfError::Malformed("no room for program headers".into()))?;
+                segments.push(Segment {
+                    addr,
+                    data: table.to_vec(),
+                    mem_size: table.len() as u64,
+                    perms: Perms::R,
+                });
+                addr
+            }
+        };
+
+        Ok(Self {
+            entry: header.e_entry(endian),
+            segments,
+            phdr,
+            phent: header.e_phentsize(endian),
+            phnum: header.e_phnum(endian),
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Minimal RISC-V executable with one `PT_LOAD` per `(addr, data, mem_size, flags)`.
+    pub(crate) fn build_elf(entry: u64, segments: &[(u64, &[u8], u64, u32)]) -> Vec<u8> {
+        const EHDR_SIZE: usize = 64;
+        const PHDR_SIZE: usize = 56;
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&elf::ELFMAG);
+        bytes.extend_from_slice(&[elf::ELFCLASS64, elf::ELFDATA2LSB, elf::EV_CURRENT]);
+        bytes.resize(16, 0);
+        bytes.extend_from_slice(&elf::ET_EXEC.to_le_bytes());
+        bytes.extend_from_slice(&elf::EM_RISCV.to_le_bytes());
+        bytes.extend_from_slice(&(elf::EV_CURRENT as u32).to_le_bytes());
+        bytes.extend_from_slice(&entry.to_le_bytes());
+        bytes.extend_from_slice(&(EHDR_SIZE as u64).to_le_bytes()); // e_phoff
+        bytes.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+        bytes.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        bytes.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+        bytes.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        bytes.extend_from_slice(&(segments.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&[0; 6]); // e_shentsize, e_shnum, e_shstrndx
+
+        let mut offset = EHDR_SIZE + PHDR_SIZE * segments.len();
+        for (addr, data, mem_size, flags) in segments.iter() {
+            bytes.extend_from_slice(&elf::PT_LOAD.to_le_bytes());
+            bytes.extend_from_slice(&flags.to_le_bytes());
+            bytes.extend_from_slice(&(offset as u64).to_le_bytes());
+            bytes.extend_from_slice(&addr.to_le_bytes()); // p_vaddr
+            bytes.extend_from_slice(&addr.to_le_bytes()); // p_paddr
+            bytes.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(&mem_size.to_le_bytes());
+            bytes.extend_from_slice(&4u64.to_le_bytes()); // p_align
+            offset += data.len();
+        }
+        for (_, data, _, _) in segments.iter() {
+            bytes.extend_from_slice(data);
+        }
+
+        bytes
+    }
+
+    #[test]
+    fn parses_load_segments() {
+        let elf = build_elf(
+            0x1004,
+            &[
+                (0x1000, &[1, 2, 3, 4], 4, elf::PF_R | elf::PF_X),
+                (0x2000, &[5], 16, elf::PF_R | elf::PF_W),
+            ],
+        );
+        let image = ElfImage::parse(&elf).unwrap();
+
+        assert_eq!(0x1004, image.entry);
+        assert_eq!(3, image.segments.len());
+        assert_eq!(Perms::RX, image.segments[0].perms);
+        assert_eq!(vec![5], image.segments[1].data);
+        assert_eq!(16, image.segments[1].mem_size);
+        assert_eq!(Perms::RW, image.segments[1].perms);
+        // The headers are in the file but not in any segment, so they get one after the rest.
+        assert_eq!((0x3000, 56, 2), (image.phdr, image.phent, image.phnum));
+        assert_eq!(&elf[64..64 + 2 * 56], &image.segments[2].data[..]);
+        assert_eq!(Perms::R, image.segments[2].perms);
+    }
+
+    #[test]
+    fn rejects_other_machines() {
+        let mut elf = build_elf(0, &[]);
+        elf[18..20].copy_from_slice(&elf::EM_X86_64.to_le_bytes());
+        assert!(matches!(ElfImage::parse(&elf), Err(ElfError::Machine(_))));
+
+        elf[EI_CLASS] = elf::ELFCLASS32;
+        assert!(matches!(ElfImage::parse(&elf), Err(ElfError::Class)));
+    }
+
+    #[test]
+    fn rejects_segments_past_the_address_space() {
+        // One wraps around, the other leaves no page for the program headers.
+        for addr in [u64::MAX - 0xFF, u64::MAX - 0x1FF] {
+            let elf = build_elf(0, &[(addr, &[1], 0x100, elf::PF_R)]);
+            assert!(matches!(ElfImage::parse(&elf), Err(ElfError::Malformed(_))));
+        }
+    }
+}
@@ -1,210 +1,764 @@
+use crate::boot::{BootError, BootLayout, KernelHeader};
+use crate::bus::{Bus, DeviceCtx, Signal};
+use crate::clock::{Clock, VIRTUAL_HZ};
+use crate::devices::{Buttons, Htif, TICK_HZ};
+use crate::elf::{ElfError, ElfImage};
+use crate::firmware::{FirmwareError, FirmwareImage, ImageFormat};
+use crate::heatmap::Heatmap;
 use crate::instruction_set::*;
 use crate::io::Button;
+use crate::machine::MachineConfig;
+use crate::memcheck::Memcheck;
+use crate::memory::{AccessKind, FaultCause, Memory, MemoryFault, Perms};
 use crate::primitives::*;
+use crate::process::{Process, StartInfo};
+use crate::signals::{ILL_ILLOPC, SIGILL};
+use crate::strace::Strace;
+use crate::symbols::Symbolizer;
+use crate::threads::QUANTUM;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const DEFAULT_PHYS_ADDR_BITS: u32 = 32;
+
+/// Reason the emulator handed control back to the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The guest exited with a status code.
+    Exit(i32),
+    /// The guest accessed a [`NullGuard`](crate::devices::NullGuard).
+    NullPointer { pc: u64 },
+    /// The guest accessed memory outside of the address space.
+    Fault(MemoryFault),
+    /// Execution reached a breakpoint or an `ebreak` at pc.
+    Breakpoint(u64),
+    /// The instruction budget passed to [`Emulator::run_for`] ran out.
+    BudgetExhausted,
+    /// The guest requested a frame through a [`RenderPort`](crate::devices::RenderPort).
+    Render,
+    /// The guest is blocked on input the host has not provided yet.
+    WaitingForInput,
+    /// Every thread of a user-mode guest waits on a futex no thread is left to wake.
+    Deadlock,
+    /// The guest ran an instruction that is not implemented, see [`crate::signals`].
+    IllegalInstruction { pc: u64, raw: u32 },
+    /// A user-mode guest was killed by a signal it did not handle.
+    Signal(i32),
+}
 
-pub const NULL: u64 = 0x0;
-pub const EXIT: u64 = 0x1;
-pub const RENDER: u64 = 0x2;
-pub const CONSOLE_OFFSET: u64 = 0x4;
-pub const HEAP_OFFSET: u64 = 0x8;
-pub const SCREEN_OFFSET: u32 = 0xFA08;
-pub const SCREEN_SIZE: usize = 320 * 200;
-
-pub const BUTTON_OFFSET: usize = 0x11948;
-pub const TICK_OFFSET: usize = 0x1194C;
+impl ExitReason {
+    /// Process exit status for reasons that end execution.
+    pub fn exit_code(&self) -> Option<i32> {
+        match self {
+            Self::Exit(code) => Some(*code),
+            Self::NullPointer { .. } | Self::Fault(_) => Some(139),
+            Self::IllegalInstruction { .. } => Some(132),
+            Self::Signal(sig) => Some(128 + sig),
+            _ => None,
+        }
+    }
+}
 
-pub const DRAM_OFFSET: u64 = 0x40000000;
-pub const STACK_OFFSET: u64 = USER_MEMORY_SIZE as u64;
-pub const USER_MEMORY_SIZE: usize = u32::MAX as usize;
+/// RISC-V privilege level the hart runs at.
+///
+/// There are no CSRs, traps or MMU, so the level only selects how `ecall` is serviced:
+/// from U-mode it is a Linux system call answered by the host, from S-mode an SBI call,
+/// see [`crate::sbi`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Privilege {
+    #[default]
+    User,
+    Supervisor,
+    Machine,
+}
 
-#[derive(Debug)]
+/// Cloning is cheap, guest memory is shared copy-on-write, see [`Emulator::fork`].
+#[derive(Debug, Clone)]
 pub struct Emulator {
     regs: [u64; 32],
-    memory: Vec<u8>,
+    bus: Bus,
     pc: u64,
 
     current_instr: Instr,
-
-    render: bool,
-    exiting: bool,
-    exit_code: i32,
+    /// Address of [`Self::current_instr`].
+    current_pc: u64,
+
+    /// Terminal exit, repeated for every run after the guest stops.
+    exit: Option<ExitReason>,
+    /// Non-terminal stop raised by the last instruction.
+    stop: Option<ExitReason>,
+    breakpoints: Vec<u64>,
+    privilege: Privilege,
+    /// Where [`Self::load_device_tree`] put the device tree.
+    device_tree: Option<Range<u64>>,
+    /// Files and heap of a user-mode guest, see [`crate::syscalls`].
+    process: Process,
+    /// Instructions retired since the emulator was created.
+    instret: u64,
+    /// Address reserved by the last `lr`, dropped when another thread or a signal handler
+    /// could have run.
+    reservation: Option<u64>,
+    clock: Clock,
+    memcheck: Option<Box<Memcheck>>,
+    heatmap: Option<Box<Heatmap>>,
+    symbols: Option<Arc<Symbolizer>>,
+    /// Print every instruction to stderr as it executes.
+    trace: bool,
+    /// Log every system call, see [`crate::syscalls`].
+    strace: Option<Strace>,
 
     console: Vec<u8>,
+    /// Bytes from the host the guest has not read yet, see [`Self::push_input`].
+    input: VecDeque<u8>,
+    /// The host has no more input, reads past the queue see end of file.
+    input_closed: bool,
 }
 
 impl Default for Emulator {
     fn default() -> Self {
+        Self::new(DEFAULT_PHYS_ADDR_BITS)
+    }
+}
+
+impl Emulator {
+    /// Create an emulator with `phys_addr_bits` of physical address space and no devices.
+    ///
+    /// Accesses at or above `1 << phys_addr_bits` fault.
+    pub fn new(phys_addr_bits: u32) -> Self {
+        Self::with_bus(Bus::new(Memory::new(phys_addr_bits)))
+    }
+
+    /// Create an emulator around a populated bus, see [`MachineConfig::build`](crate::machine::MachineConfig::build).
+    pub fn with_bus(bus: Bus) -> Self {
         Self {
             pc: 0,
-            memory: vec![0; USER_MEMORY_SIZE as usize],
+            bus,
             regs: Default::default(),
-            render: false,
-            exit_code: 0,
-            exiting: false,
+            exit: None,
+            stop: None,
+            breakpoints: Vec::new(),
+            privilege: Privilege::default(),
+            device_tree: None,
+            process: Process::default(),
+            instret: 0,
+            reservation: None,
+            clock: Clock::default(),
             current_instr: Instr::Ecall,
+            current_pc: 0,
             console: Vec::new(),
+            input: VecDeque::new(),
+            input_closed: false,
+            memcheck: None,
+            heatmap: None,
+            symbols: None,
+            trace: false,
+            strace: None,
         }
     }
-}
 
-impl Emulator {
     pub fn tick(&mut self) {
-        self.memory[TICK_OFFSET] += 1;
+        self.bus.tick();
+        crate::htif::poll(self);
     }
 
     pub fn press_button(&mut self, button: Button) {
-        let shamt = match button {
-            Button::Zero => 0,
-            Button::One => 1,
-            Button::Two => 2,
-            Button::Three => 3,
-        };
-
-        self.memory[BUTTON_OFFSET] |= 1 << shamt;
+        if let Some(buttons) = self.bus.device_mut::<Buttons>() {
+            buttons.press(button);
+        }
     }
 
     pub fn release_button(&mut self, button: Button) {
-        let shamt = match button {
-            Button::Zero => 0,
-            Button::One => 1,
-            Button::Two => 2,
-            Button::Three => 3,
-        };
-
-        self.memory[BUTTON_OFFSET] &= !(1 << shamt);
+        if let Some(buttons) = self.bus.device_mut::<Buttons>() {
+            buttons.release(button);
+        }
     }
 
     pub fn button(&self, button: Button) -> bool {
-        let shamt = match button {
-            Button::Zero => 0,
-            Button::One => 1,
-            Button::Two => 2,
-            Button::Three => 3,
-        };
+        self.bus
+            .device::<Buttons>()
+            .is_some_and(|buttons| buttons.pressed(button))
+    }
+
+    pub fn bus(&self) -> &Bus {
+        &self.bus
+    }
 
-        ((self.memory[BUTTON_OFFSET] >> shamt) & 1) == 1
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        &mut self.bus
     }
 
     pub fn current_instruction(&self) -> &Instr {
         &self.current_instr
     }
 
+    /// Address of [`Self::current_instruction`].
+    pub fn current_pc(&self) -> u64 {
+        self.current_pc
+    }
+
     pub fn console(&self) -> &[u8] {
         &self.console
     }
 
-    pub fn should_render(&mut self) -> bool {
-        let render = self.render;
-        if render {
-            self.render = false;
-        }
+    pub(crate) fn console_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.console
+    }
+
+    /// Queue `data` for the guest to read from standard input or the console port.
+    pub fn push_input(&mut self, data: &[u8]) {
+        self.input.extend(data);
+    }
+
+    /// Signal end of file once the queued input has been read.
+    pub fn close_input(&mut self) {
+        self.input_closed = true;
+    }
+
+    /// Take up to `len` bytes of queued input.
+    pub(crate) fn take_input(&mut self, len: usize) -> Vec<u8> {
+        let len = len.min(self.input.len());
+        self.input.drain(..len).collect()
+    }
+
+    /// Bytes of input queued for the guest.
+    pub fn pending_input(&self) -> usize {
+        self.input.len()
+    }
+
+    /// Whether a read of the empty queue is end of file rather than a wait for the host.
+    pub(crate) fn input_closed(&self) -> bool {
+        self.input_closed
+    }
+
+    pub fn process(&self) -> &Process {
+        &self.process
+    }
 
-        render
+    pub fn process_mut(&mut self) -> &mut Process {
+        &mut self.process
     }
 
     pub fn finished(&self) -> bool {
-        self.exiting
+        self.exit.is_some()
+    }
+
+    /// The reason execution ended, if the guest has stopped.
+    pub fn exit_reason(&self) -> Option<ExitReason> {
+        self.exit
+    }
+
+    /// The memory fault that stopped execution, if any.
+    pub fn fault(&self) -> Option<&MemoryFault> {
+        match &self.exit {
+            Some(ExitReason::Fault(fault)) => Some(fault),
+            _ => None,
+        }
+    }
+
+    pub fn pc(&self) -> u64 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u64) {
+        self.pc = pc;
+    }
+
+    pub fn privilege(&self) -> Privilege {
+        self.privilege
+    }
+
+    pub fn set_privilege(&mut self, privilege: Privilege) {
+        self.privilege = privilege;
+    }
+
+    /// Track definedness of guest memory and registers, see [`Memcheck`].
+    ///
+    /// Enable before flashing, memory that is already populated counts as undefined.
+    pub fn enable_memcheck(&mut self) {
+        let mut memcheck = Memcheck::new();
+        memcheck.set_symbols(self.symbols.clone());
+        self.memcheck = Some(Box::new(memcheck));
+    }
+
+    pub fn memcheck(&self) -> Option<&Memcheck> {
+        self.memcheck.as_deref()
+    }
+
+    pub(crate) fn memcheck_mut(&mut self) -> Option<&mut Memcheck> {
+        self.memcheck.as_deref_mut()
+    }
+
+    /// Count guest accesses per block of `1 << block_bits` bytes, see [`Heatmap`].
+    pub fn enable_heatmap(&mut self, block_bits: u32) {
+        self.heatmap = Some(Box::new(Heatmap::new(block_bits)));
+    }
+
+    pub fn heatmap(&self) -> Option<&Heatmap> {
+        self.heatmap.as_deref()
+    }
+
+    pub fn heatmap_mut(&mut self) -> Option<&mut Heatmap> {
+        self.heatmap.as_deref_mut()
+    }
+
+    pub fn symbols(&self) -> Option<&Symbolizer> {
+        self.symbols.as_deref()
+    }
+
+    /// Symbolize guest addresses with `symbols`, [`Self::load_elf`] does this automatically.
+    pub fn set_symbols(&mut self, symbols: Option<Symbolizer>) {
+        self.symbols = symbols.map(Arc::new);
+        if let Some(memcheck) = &mut self.memcheck {
+            memcheck.set_symbols(self.symbols.clone());
+        }
+    }
+
+    /// `addr` with its symbol and source line where known.
+    pub fn describe(&self, addr: u64) -> String {
+        crate::symbols::describe(self.symbols(), addr)
+    }
+
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    pub fn instret(&self) -> u64 {
+        self.instret
+    }
+
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    /// Take time and randomness from `clock`, see [`Clock::Virtual`] for reproducible runs.
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    /// Time since the guest started, see [`Clock::uptime`].
+    pub fn uptime(&self) -> Duration {
+        self.clock.uptime(self.instret)
+    }
+
+    /// Time since the Unix epoch as the guest sees it.
+    pub fn wall_time(&self) -> Duration {
+        self.clock.wall_time(self.instret)
+    }
+
+    /// Fill `buf` with random bytes from the clock's source.
+    pub fn fill_random(&mut self, buf: &mut [u8]) {
+        self.clock.fill_random(buf);
+    }
+
+    pub fn strace(&self) -> Option<&Strace> {
+        self.strace.as_ref()
     }
 
-    /// Load program data into memory at offset [`Addr`].
+    pub fn set_strace(&mut self, strace: Option<Strace>) {
+        self.strace = strace;
+    }
+
+    /// Independent copy of this emulator that shares guest memory until either side writes.
+    pub fn fork(&self) -> Self {
+        self.clone()
+    }
+
+    /// Base addresses of the guest pages whose contents differ between this emulator and `other`.
+    pub fn diff_pages(&self, other: &Emulator) -> Vec<u64> {
+        self.bus.memory().diff(other.bus.memory())
+    }
+
+    /// Stop execution with [`ExitReason::Breakpoint`] before the instruction at `addr` runs.
+    pub fn add_breakpoint(&mut self, addr: u64) {
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u64) {
+        self.breakpoints.retain(|bp| *bp != addr);
+    }
+
+    /// Load program data into memory at `offset`.
     ///
     /// Subsequently sets pc to offset.
-    pub fn flash_prgm(&mut self, prgm: &[u8], offset: u32) {
-        println!("flashing program...");
+    ///
+    /// This is a host write and ignores region permissions, the text can be write-protected
+    /// afterwards with [`Memory::protect`].
+    pub fn flash_prgm(&mut self, prgm: &[u8], offset: u64) -> Result<(), MemoryFault> {
+        eprintln!("flashing program...");
+
+        self.host_write(offset, prgm)?;
+        self.pc = offset;
 
-        for (byte, b) in self
-            .memory_mut(offset, prgm.len())
-            .iter_mut()
-            .zip(prgm.iter())
+        eprintln!("finished!");
+        Ok(())
+    }
+
+    /// Map every `PT_LOAD` segment of a RISC-V ELF64 executable and point pc at its entry.
+    ///
+    /// BSS is zero filled and each segment is protected with its `p_flags` where it lies
+    /// within a single region. Symbols and line info are loaded when present, an [`Htif`]
+    /// is attached at `tohost` if the program has one.
+    pub fn load_elf(&mut self, data: &[u8]) -> Result<ElfImage, ElfError> {
+        let image = ElfImage::parse(data)?;
+        self.set_symbols(Symbolizer::parse(data).ok());
+        self.attach_htif();
+
+        for segment in image.segments.iter() {
+            // Check all of `p_memsz` up front, it comes from the file and can be anything.
+            let len = segment.mem_size as usize;
+            let unmapped =
+                self.access_fault(segment.addr, len, AccessKind::Store, FaultCause::Unmapped);
+            let range = segment
+                .addr
+                .checked_add(segment.mem_size)
+                .map(|end| segment.addr..end)
+                .ok_or(ElfError::Fault(unmapped))?;
+            self.check_mapped(range.start, len, AccessKind::Store)
+                .map_err(ElfError::Fault)?;
+
+            self.host_write(segment.addr, &segment.data)
+                .map_err(ElfError::Fault)?;
+
+            let zeros = [0; crate::memory::PAGE_SIZE];
+            let mut bss = segment.addr + segment.data.len() as u64;
+            while bss < range.end {
+                let chunk = (range.end - bss).min(zeros.len() as u64) as usize;
+                self.host_write(bss, &zeros[..chunk])
+                    .map_err(ElfError::Fault)?;
+                bss += chunk as u64;
+            }
+
+            self.bus.memory_mut().protect(range, segment.perms);
+        }
+        self.pc = image.entry;
+
+        Ok(image)
+    }
+
+    /// Map an [`Htif`] over the `tohost` and `fromhost` symbols, unless there already is one.
+    ///
+    /// `fromhost` is only reachable in the page after `tohost`, as in riscv-tests and pk.
+    pub(crate) fn attach_htif(&mut self) {
+        let symbol = |name| self.symbols().and_then(|symbols| symbols.lookup(name));
+        let Some(tohost) = symbol("tohost") else {
+            return;
+        };
+        // Without `fromhost` the window is just `tohost`, with `fromhost` past its end.
+        let fromhost = symbol("fromhost")
+            .filter(|addr| (tohost + 8..tohost + crate::memory::PAGE_SIZE as u64).contains(addr))
+            .map(|addr| addr - tohost);
+        let size = fromhost.map_or(8, |offset| offset + 8);
+        let fromhost = fromhost.unwrap_or(8);
+        if self.bus.device::<Htif>().is_none()
+            && !self.bus.has_device(tohost)
+            && !self.bus.has_device(tohost + size - 1)
         {
-            *byte = *b;
+            self.bus.attach(tohost, size, None, Htif::new(fromhost));
+        }
+    }
+
+    /// Write every record of an Intel HEX or S-record image, pointing pc at its start
+    /// address if it has one.
+    pub fn load_firmware(&mut self, image: &FirmwareImage) -> Result<(), FirmwareError> {
+        for (addr, data) in image.chunks.iter() {
+            self.host_write(*addr, data).map_err(FirmwareError::Fault)?;
+        }
+        if let Some(entry) = image.entry {
+            self.pc = entry;
         }
-        self.pc = offset as u64;
 
-        println!("finished!");
+        Ok(())
     }
 
-    /// Run program until the exit ecall is made.
-    pub fn run(&mut self) {
-        loop {
-            self.run_next();
+    /// Load a RISC-V Linux kernel `Image`, its device tree and an optional initramfs into
+    /// `ram` and enter the kernel in S-mode, see [`BootLayout::new`].
+    ///
+    /// Follows the boot protocol: `a0` holds `hartid`, `a1` the device tree address and pc
+    /// the first kernel instruction. The initramfs location is not written into the device
+    /// tree, its `/chosen` node must already name the returned range, which
+    /// [`MachineConfig::device_tree`] does given the same layout.
+    pub fn boot_linux(
+        &mut self,
+        ram: Range<u64>,
+        kernel: &[u8],
+        dtb: &[u8],
+        initrd: Option<&[u8]>,
+        hartid: u64,
+    ) -> Result<BootLayout, BootError> {
+        let header = KernelHeader::parse(kernel)?;
+        let layout = BootLayout::new(
+            ram,
+            &header,
+            dtb.len() as u64,
+            initrd.map(|initrd| initrd.len() as u64),
+        )?;
+
+        self.host_write(layout.kernel.start, kernel)
+            .map_err(BootError::Fault)?;
+        self.host_write(layout.dtb.start, dtb)
+            .map_err(BootError::Fault)?;
+        if let (Some(initrd), Some(range)) = (initrd, &layout.initrd) {
+            self.host_write(range.start, initrd)
+                .map_err(BootError::Fault)?;
         }
+
+        self.set(Reg::A(0), hartid);
+        self.set(Reg::A(1), layout.dtb.start);
+        self.pc = layout.kernel.start;
+        self.privilege = Privilege::Supervisor;
+
+        Ok(layout)
     }
 
-    pub fn run_next(&mut self) {
-        let raw_instr = self.read_pc();
-        self.step(raw_instr as u32);
+    /// Place a device tree blob at the top of memory, below which the stack then starts,
+    /// and pass its address in `a1` as a boot loader would. Only for supervisor-mode
+    /// guests, a user-mode program expects its stack and `a1` untouched.
+    pub fn load_device_tree(&mut self, dtb: &[u8]) -> Result<u64, MemoryFault> {
+        let len = dtb.len();
+        let addr =
+            self.stack_top().checked_sub(len as u64).ok_or_else(|| {
+                self.access_fault(0, len, AccessKind::Store, FaultCause::Unmapped)
+            })? & !0x7;
+        self.host_write(addr, dtb)?;
+        self.device_tree = Some(addr..addr + dtb.len() as u64);
+        self.set(Reg::A(1), addr);
+
+        Ok(addr)
     }
 
-    /// Run program until iterations is reached or the exit ecall made.
-    #[allow(unused)]
-    pub fn run_for(&mut self, mut iterations: usize) {
-        loop {
-            let raw_instr = self.read_pc();
-            if iterations == 0 {
-                return;
+    /// Push `argc`, `argv`, `envp` and the auxiliary vector onto the stack and point sp
+    /// at them, see [`crate::process::initial_stack`].
+    ///
+    /// Call after loading, `image` supplies the program headers and pc is `AT_ENTRY`. The
+    /// rest of the region holding the end of the image becomes the heap for `brk` and
+    /// `mmap`, raw binaries have no heap since their size is unknown.
+    pub fn start_process(
+        &mut self,
+        info: &StartInfo,
+        image: Option<&ElfImage>,
+    ) -> Result<(), MemoryFault> {
+        let end = image.and_then(|image| {
+            image
+                .segments
+                .iter()
+                .map(|segment| segment.addr + segment.mem_size)
+                .max()
+        });
+        if let Some(end) = end {
+            let brk_start = end.next_multiple_of(crate::memory::PAGE_SIZE as u64);
+            if let Some(region) = self.bus.memory().region_at(end - 1, 1) {
+                let heap = brk_start.min(region.range.end)..region.range.end;
+                self.process.set_heap(heap, brk_start.min(region.range.end));
             }
+        }
+
+        self.process.args = info.args.clone();
+        self.process.linux = image.is_some();
+        let auxv = StartInfo::auxv(image, self.pc);
+        let (sp, stack) = crate::process::initial_stack(self.stack_top(), info, &auxv);
+        self.host_write(sp, &stack)?;
+        self.set(Reg::Sp, sp);
+
+        Ok(())
+    }
 
-            if !self.step(raw_instr as u32) {
-                return;
+    /// Report a system call reading undefined bytes when memcheck is enabled.
+    pub(crate) fn check_syscall_buf(&mut self, name: &'static str, addr: u64, len: usize) {
+        if let Some(memcheck) = &mut self.memcheck {
+            memcheck.check_syscall_buf(name, addr, len, self.pc);
+        }
+    }
+
+    /// Write guest memory on behalf of the host, ignoring region permissions.
+    pub(crate) fn host_write(&mut self, addr: u64, data: &[u8]) -> Result<(), MemoryFault> {
+        self.check_mapped(addr, data.len(), AccessKind::Store)?;
+        self.bus.memory_mut().write(addr, data);
+        if let Some(memcheck) = &mut self.memcheck {
+            memcheck.set_defined(addr, data.len(), true);
+        }
+
+        Ok(())
+    }
+
+    /// Run program until the guest exits or needs the host.
+    pub fn run(&mut self) -> ExitReason {
+        loop {
+            if let Some(reason) = self.run_next() {
+                return reason;
             }
+        }
+    }
 
-            iterations -= 1;
+    /// Count a retired instruction, ticking the devices on a virtual clock and switching
+    /// threads when one blocked or its quantum is up.
+    fn retire(&mut self) {
+        self.instret += 1;
+        if self.clock.is_virtual() && self.instret.is_multiple_of(VIRTUAL_HZ / TICK_HZ) {
+            self.tick();
+        }
+
+        let preempt = self.process.threads.len() > 1 && self.instret.is_multiple_of(QUANTUM);
+        if self.process.reschedule || preempt {
+            self.reservation = None;
+            self.exit = crate::threads::schedule(self);
+        }
+        if self.exit.is_none() && self.process.thread().pending != 0 {
+            self.reservation = None;
+            self.exit = crate::signals::deliver_pending(self);
         }
     }
 
-    /// Slice of memory at location [`Addr`].
+    /// Execute a single instruction.
     ///
-    /// User address space: 0..[`u32::MAX`].
-    pub fn memory(&self, offset: u32, len: usize) -> &[u8] {
-        let start = offset as usize;
-        let end = offset as usize + len;
-        // println!(
-        //     "memory fetch => start: {start:#x}: end {end:#x} max: {:#x}",
-        //     USER_MEMORY_SIZE
-        // );
-
-        if end <= USER_MEMORY_SIZE {
-            &self.memory[start..end]
+    /// Returns the reason execution stopped, if it did.
+    pub fn run_next(&mut self) -> Option<ExitReason> {
+        if self.exit.is_some() {
+            return self.exit;
+        }
+
+        if let Err(fault) = self
+            .read_pc()
+            .and_then(|raw_instr| self.step(raw_instr as u32))
+        {
+            self.exit = crate::signals::raise_fault(self, fault);
+        }
+        // An instruction waiting for the host runs again rather than retiring.
+        let retrying = self.stop == Some(ExitReason::WaitingForInput) && self.pc == self.current_pc;
+        if self.exit.is_none() && !retrying {
+            self.retire();
+        }
+
+        if self.exit.is_some() {
+            self.exit
+        } else if let Some(stop) = self.stop.take() {
+            Some(stop)
+        } else if self.breakpoints.contains(&self.pc) {
+            Some(ExitReason::Breakpoint(self.pc))
         } else {
-            panic!("SEGFAULT");
+            None
+        }
+    }
+
+    /// Run program until iterations is reached or the guest stops.
+    pub fn run_for(&mut self, iterations: usize) -> ExitReason {
+        for _ in 0..iterations {
+            if let Some(reason) = self.run_next() {
+                return reason;
+            }
+        }
+
+        ExitReason::BudgetExhausted
+    }
+
+    /// Run program until pc reaches `addr` or the guest stops.
+    pub fn run_until(&mut self, addr: u64) -> ExitReason {
+        loop {
+            if let Some(reason) = self.run_next() {
+                return reason;
+            }
+
+            if self.pc == addr {
+                return ExitReason::Breakpoint(addr);
+            }
         }
     }
 
-    /// Slice of mutable memory at location [`Addr`].
+    /// Slice of RAM at location [`Addr`], bypassing devices.
     ///
-    /// User address space: 0..[`u32::MAX`].
-    fn memory_mut(&mut self, offset: u32, len: usize) -> &mut [u8] {
-        let start = offset as usize;
-        let end = offset as usize + len;
-        // println!(
-        //     "mut memory fetch => start: {start:#x}: end {end:#x} max: {:#x}",
-        //     USER_MEMORY_SIZE
-        // );
-
-        if end <= USER_MEMORY_SIZE {
-            &mut self.memory[start..end]
-        } else {
-            panic!("segfault");
+    /// User address space: 0..[`Memory::size`].
+    ///
+    /// Borrowed unless the slice crosses a page boundary.
+    pub fn memory(&self, offset: u64, len: usize) -> Result<Cow<'_, [u8]>, MemoryFault> {
+        self.check_mapped(offset, len, AccessKind::Load)?;
+        Ok(self.bus.memory().slice(offset, len))
+    }
+
+    /// Backing store for guest memory.
+    pub fn guest_memory(&self) -> &Memory {
+        self.bus.memory()
+    }
+
+    /// Initial stack pointer, the 16 byte aligned end of the highest RAM region or the start
+    /// of the device tree if it was placed there.
+    pub fn stack_top(&self) -> u64 {
+        if let Some(dtb) = &self.device_tree {
+            return dtb.start & !0xF;
+        }
+
+        let end = self
+            .bus
+            .memory()
+            .regions()
+            .iter()
+            .map(|region| region.range.end)
+            .max()
+            .unwrap_or(0);
+
+        end & !0xF
+    }
+
+    fn access_fault(
+        &self,
+        addr: u64,
+        len: usize,
+        kind: AccessKind,
+        cause: FaultCause,
+    ) -> MemoryFault {
+        MemoryFault {
+            addr,
+            size: len,
+            kind,
+            cause,
+            pc: self.pc,
         }
     }
 
-    fn step(&mut self, raw_instr: u32) -> bool {
-        // assert_eq!(self.memory[BUTTON_OFFSET as usize], 0);
+    /// Host side check, ignores region permissions.
+    fn check_mapped(&self, addr: u64, len: usize, kind: AccessKind) -> Result<Perms, MemoryFault> {
+        self.bus
+            .permissions(addr, len)
+            .ok_or_else(|| self.access_fault(addr, len, kind, FaultCause::Unmapped))
+    }
 
-        if self.exiting {
-            // println!("exiting: {}", self.exit_code);
-            return false;
+    /// Guest side check, enforces region permissions and counts the access.
+    fn check_access(
+        &mut self,
+        addr: u64,
+        len: usize,
+        kind: AccessKind,
+    ) -> Result<u64, MemoryFault> {
+        if self.check_mapped(addr, len, kind)?.allows(kind) {
+            if let Some(heatmap) = &mut self.heatmap {
+                heatmap.record(addr, len, kind);
+            }
+            Ok(addr)
+        } else {
+            Err(self.access_fault(addr, len, kind, FaultCause::Permission))
         }
+    }
 
+    fn step(&mut self, raw_instr: u32) -> Result<(), MemoryFault> {
         // println!("fetching instr: {:#x}:{raw_instr:#x}", self.pc);
 
-        let instr = crate::decoding::decode(raw_instr, self.pc);
+        let instr = crate::decoding::decode(raw_instr);
 
         self.current_instr = instr;
+        self.current_pc = self.pc;
 
-        self.execute(instr);
-        true
+        if self.trace {
+            eprintln!("{}: {:?}", self.describe(self.pc), instr);
+        }
+
+        if let Some(memcheck) = &mut self.memcheck {
+            memcheck.step(instr, &self.regs, &self.bus, self.pc);
+        }
+
+        self.execute(instr)
     }
 
     pub fn set(&mut self, reg: Reg, val: u64) {
@@ -215,6 +769,14 @@ impl Emulator {
         self.regs[reg] = val as u64;
     }
 
+    pub(crate) fn regs(&self) -> [u64; 32] {
+        self.regs
+    }
+
+    pub(crate) fn set_regs(&mut self, regs: [u64; 32]) {
+        self.regs = regs;
+    }
+
     pub fn reg(&self, reg: Reg) -> u64 {
         self.regs[reg]
     }
@@ -227,56 +789,142 @@ impl Emulator {
         self.pc = self.pc.wrapping_add(offset.val());
     }
 
-    pub fn read_pc(&mut self) -> u64 {
-        let mut val = 0;
-        let offset = self.pc;
-
-        let memory = self.memory(offset as u32, 4);
+    pub fn read_pc(&mut self) -> Result<u64, MemoryFault> {
+        let addr = self.check_access(self.pc, 4, AccessKind::Fetch)?;
 
-        for (i, byte) in memory.iter().enumerate() {
-            val += (*byte as u64) << (i * 8);
-        }
+        let mut bytes = [0; 4];
+        let signal = self.bus.read(
+            addr,
+            &mut bytes,
+            &mut DeviceCtx {
+                console: &mut self.console,
+                input: &mut self.input,
+            },
+        );
+        self.handle_signal(signal);
 
-        val
+        Ok(u32::from_le_bytes(bytes) as u64)
     }
 
-    pub fn load(&mut self, offset: Offset, bytes: usize) -> u64 {
-        let mut val = 0;
+    pub fn load(&mut self, offset: Offset, bytes: usize) -> Result<u64, MemoryFault> {
         let offset = self.reg(offset.0).wrapping_add(offset.1.val());
+        let addr = self.check_access(offset, bytes, AccessKind::Load)?;
 
-        if offset == NULL {
-            self.exiting = true;
-            self.exit_code = 139;
-        } else if offset == EXIT {
-            self.exiting = true;
-            self.exit_code = self.reg(Reg::A(0)) as i32;
-        } else if offset == RENDER {
-            self.render = true;
-        }
-
-        let memory = self.memory(offset as u32, bytes);
+        let mut buf = [0; 8];
+        let signal = self.bus.read(
+            addr,
+            &mut buf[..bytes],
+            &mut DeviceCtx {
+                console: &mut self.console,
+                input: &mut self.input,
+            },
+        );
+        self.handle_signal(signal);
 
-        for (i, byte) in memory.iter().enumerate() {
-            val += (*byte as u64) << (i * 8);
-        }
-
-        val
+        Ok(u64::from_le_bytes(buf))
     }
 
-    pub fn store(&mut self, offset: Offset, bytes: usize, val: u64) {
+    pub fn store(&mut self, offset: Offset, bytes: usize, val: u64) -> Result<(), MemoryFault> {
         let offset = self.reg(offset.0).wrapping_add(offset.1.val());
+        let addr = self.check_access(offset, bytes, AccessKind::Store)?;
+
+        let signal = self.bus.write(
+            addr,
+            &val.to_le_bytes()[..bytes],
+            &mut DeviceCtx {
+                console: &mut self.console,
+                input: &mut self.input,
+            },
+        );
+        self.handle_signal(signal);
+
+        Ok(())
+    }
+
+    /// Address of an `lr`, `sc` or AMO, which must be naturally aligned.
+    fn atomic_offset(
+        &self,
+        reg: Reg,
+        bytes: usize,
+        kind: AccessKind,
+    ) -> Result<Offset, MemoryFault> {
+        let addr = self.reg(reg);
+        if addr.is_multiple_of(bytes as u64) {
+            Ok(Offset(reg, Imm::new(0)))
+        } else {
+            Err(self.access_fault(addr, bytes, kind, FaultCause::Misaligned))
+        }
+    }
 
-        let memory = self.memory_mut(offset as u32, bytes);
-        for (i, byte) in memory.iter_mut().enumerate() {
-            *byte = (val >> (i * 8)) as u8;
+    fn load_reserved(&mut self, addr: Reg, bytes: usize) -> Result<u64, MemoryFault> {
+        let offset = self.atomic_offset(addr, bytes, AccessKind::Load)?;
+        let val = self.load(offset, bytes)?;
+        self.reservation = Some(self.reg(addr));
+        Ok(val)
+    }
+
+    /// Returns whether the store failed, which is what `sc` writes to its destination.
+    fn store_conditional(
+        &mut self,
+        addr: Reg,
+        bytes: usize,
+        val: u64,
+    ) -> Result<bool, MemoryFault> {
+        let offset = self.atomic_offset(addr, bytes, AccessKind::Store)?;
+        let reserved = self.reservation.take() == Some(self.reg(addr));
+        if reserved {
+            self.store(offset, bytes, val)?;
         }
+        Ok(!reserved)
+    }
+
+    /// Apply `op` to the memory at `addr` and `src`, returning the old value zero-extended.
+    fn amo(&mut self, op: AmoOp, addr: Reg, bytes: usize, src: u64) -> Result<u64, MemoryFault> {
+        let offset = self.atomic_offset(addr, bytes, AccessKind::Store)?;
+        let old = self.load(offset, bytes)?;
+        // Compare words shifted to the top so their sign bit is the sign bit, the store
+        // truncates the result again.
+        let shift = 64 - 8 * bytes as u32;
+        let (a, b) = (old << shift, src << shift);
+        let new = match op {
+            AmoOp::Swap => src,
+            AmoOp::Add => old.wrapping_add(src),
+            AmoOp::Xor => old ^ src,
+            AmoOp::And => old & src,
+            AmoOp::Or => old | src,
+            AmoOp::Min => ((a as i64).min(b as i64) >> shift) as u64,
+            AmoOp::Max => ((a as i64).max(b as i64) >> shift) as u64,
+            AmoOp::Minu => a.min(b) >> shift,
+            AmoOp::Maxu => a.max(b) >> shift,
+        };
+        self.store(offset, bytes, new)?;
+        Ok(old)
+    }
 
-        if offset == CONSOLE_OFFSET {
-            self.console.push(self.memory(offset as u32, 1)[0]);
+    fn handle_signal(&mut self, signal: Option<Signal>) {
+        match signal {
+            Some(Signal::Exit) => {
+                self.exit = Some(ExitReason::Exit(self.reg(Reg::A(0)) as i32));
+            }
+            Some(Signal::NullAccess) => {
+                self.exit = Some(ExitReason::NullPointer { pc: self.pc });
+            }
+            Some(Signal::Render) => self.stop = Some(ExitReason::Render),
+            Some(Signal::InputEmpty) => self.stop = Some(ExitReason::WaitingForInput),
+            Some(Signal::Htif) => match crate::htif::handle(self) {
+                // Only stores raise this, run the store again once the host has input.
+                Some(ExitReason::WaitingForInput) => {
+                    self.pc = self.pc.wrapping_sub(4);
+                    self.stop = Some(ExitReason::WaitingForInput);
+                }
+                Some(reason) => self.exit = Some(reason),
+                None => {}
+            },
+            None => {}
         }
     }
 
-    fn execute(&mut self, instr: Instr) {
+    fn execute(&mut self, instr: Instr) -> Result<(), MemoryFault> {
         // println!("\t\texecuting: {instr:?}");
 
         self.set(Reg::Zero, 0);
@@ -360,10 +1008,11 @@ impl Emulator {
                 );
             }
             Instr::Sll(dst, src1, src2) => {
-                self.set(dst, self.reg(src1) << self.reg(src2));
+                self.set(dst, self.reg(src1) << (self.reg(src2) & 0b111111));
             }
             Instr::Sllw(dst, src1, src2) => {
-                self.set(dst, self.reg(src1) << self.reg(src2));
+                let result = (self.reg(src1) as u32) << (self.reg(src2) & 0b11111);
+                self.set_signed(dst, result as i32 as i64);
             }
             Instr::Slt(dst, src1, src2) => {
                 self.set(
@@ -402,7 +1051,8 @@ impl Emulator {
                 self.set(dst, self.reg(src1) >> (self.reg(src2) & 0b111111));
             }
             Instr::Srlw(dst, src1, src2) => {
-                self.set(dst, self.reg(src1) >> (self.reg(src2) & 0b111111));
+                let result = (self.reg(src1) as u32) >> (self.reg(src2) & 0b11111);
+                self.set_signed(dst, result as i32 as i64);
             }
             Instr::Sra(dst, src1, src2) => {
                 self.set_signed(
@@ -411,10 +1061,8 @@ impl Emulator {
                 );
             }
             Instr::Sraw(dst, src1, src2) => {
-                self.set_signed(
-                    dst,
-                    self.reg_signed(src1) >> (self.reg_signed(src2) & 0b111111),
-                );
+                let result = (self.reg(src1) as i32) >> (self.reg(src2) & 0b11111);
+                self.set_signed(dst, result as i64);
             }
             Instr::Or(dst, src1, src2) => {
                 self.set(dst, self.reg(src1) | self.reg(src2));
@@ -423,53 +1071,58 @@ impl Emulator {
                 self.set(dst, self.reg(src1) & self.reg(src2));
             }
             Instr::Lb(dst, offset) => {
-                let val = se_byte(self.load(offset, 1) as u8);
+                let val = se_byte(self.load(offset, 1)? as u8);
                 self.set_signed(dst, val);
             }
             Instr::Lh(dst, offset) => {
-                let val = se_half(self.load(offset, 2) as u16);
+                let val = se_half(self.load(offset, 2)? as u16);
                 self.set_signed(dst, val);
             }
             Instr::Ld(dst, offset) => {
-                let val = self.load(offset, 8);
+                let val = self.load(offset, 8)?;
                 self.set(dst, val);
             }
             Instr::Lbu(dst, offset) => {
-                let val = self.load(offset, 1);
+                let val = self.load(offset, 1)?;
                 self.set(dst, val);
             }
             Instr::Lhu(dst, offset) => {
-                let val = self.load(offset, 2);
+                let val = self.load(offset, 2)?;
                 self.set(dst, val);
             }
             Instr::Sb(src, offset) => {
-                self.store(offset, 1, self.reg(src));
+                self.store(offset, 1, self.reg(src))?;
             }
             Instr::Sh(src, offset) => {
-                self.store(offset, 2, self.reg(src));
+                self.store(offset, 2, self.reg(src))?;
             }
             Instr::Sw(src, offset) => {
-                self.store(offset, 4, self.reg(src));
+                self.store(offset, 4, self.reg(src))?;
             }
             Instr::Sd(src, offset) => {
-                self.store(offset, 8, self.reg(src));
+                self.store(offset, 8, self.reg(src))?;
             }
             Instr::Jal(dst, offset) => {
                 self.set(dst, self.pc + 4);
-                self.pc = ((self.pc as u32).wrapping_add(offset.val() as u32)) as u64;
+                self.pc = self.pc.wrapping_add(offset.val());
             }
             Instr::Jalr(dst, src, offset) => {
+                // Read the base first, `call` links through the register it jumps with.
+                let ra = self.reg(src).wrapping_add(offset.val()) & !1;
                 self.set(dst, self.pc + 4);
-                let ra = ((self.reg(src) as u32 + offset.val() as u32) >> 1) << 1;
-                if ra == 0 {
+                if ra != 0 {
+                    self.pc = ra;
+                } else if self.process.linux {
+                    // Nothing is mapped at 0 in a Linux process, so the fetch there faults.
+                    self.pc = ra;
+                    self.set(Reg::Zero, 0);
+                    return Err(self.access_fault(ra, 4, AccessKind::Fetch, FaultCause::Unmapped));
+                } else {
                     // HACK: main function returns to libc, so unfortunately, it can be assumed
                     // that if the return address is 0, since Reg::Ra will be 0, that we are
                     // returning from main.
 
-                    self.exiting = true;
-                    self.exit_code = self.reg(Reg::A(0)) as i32;
-                } else {
-                    self.pc = (ra.wrapping_add(offset.val() as u32)) as u64;
+                    self.exit = Some(ExitReason::Exit(self.reg(Reg::A(0)) as i32));
                 }
             }
             Instr::Beq(src1, src2, offset) => {
@@ -514,35 +1167,78 @@ impl Emulator {
                     self.add_pc(Imm::new(4));
                 }
             }
-            Instr::Ecall => {
-                let syscall = self.reg(Reg::A(7));
-                match syscall {
-                    // Exit
-                    93 => {
-                        self.exiting = true;
-                        self.exit_code = self.reg_signed(Reg::A(0)) as i32;
+            Instr::Ecall if self.privilege == Privilege::Supervisor => {
+                if let Some(reason) = crate::sbi::call(self) {
+                    self.exit = Some(reason);
+                }
+            }
+            Instr::Ecall => match crate::syscalls::call(self) {
+                // Run the `ecall` again once the host has input, pc moves past it below.
+                Some(ExitReason::WaitingForInput) => {
+                    self.pc = self.pc.wrapping_sub(4);
+                    self.stop = Some(ExitReason::WaitingForInput);
+                }
+                Some(reason) => self.exit = Some(reason),
+                None => {}
+            },
+            Instr::Ebreak if crate::semihosting::is_call(self, self.pc) => {
+                match crate::semihosting::call(self) {
+                    // Run the `ebreak` again once the host has input, pc moves past it below.
+                    Some(ExitReason::WaitingForInput) => {
+                        self.pc = self.pc.wrapping_sub(4);
+                        self.stop = Some(ExitReason::WaitingForInput);
                     }
-                    // Write
-                    64 => match self.reg(Reg::A(0)) {
-                        1 => {
-                            let buf_addr = self.reg(Reg::A(1)) as usize;
-                            let buf_len = self.reg(Reg::A(2)) as usize;
-                            // println!("writing {} bytes of buf {} to console.", buf_len, buf_addr);
-                            let memory = self.memory(buf_addr as u32, buf_len).to_vec();
-                            self.console.extend_from_slice(&memory);
-                        }
-                        _ => unimplemented!(),
-                    },
-                    val => println!("invalid syscall: {}", val),
+                    Some(reason) => self.exit = Some(reason),
+                    None => {}
                 }
             }
+            Instr::Ebreak => {
+                self.stop = Some(ExitReason::Breakpoint(self.pc));
+            }
+            Instr::Csrr(dst, counter) => {
+                let val = match counter {
+                    Counter::Cycle | Counter::Instret => self.instret,
+                    Counter::Time => self.clock.ticks(self.instret),
+                };
+                self.set(dst, val);
+            }
+            Instr::Fence => {}
+            Instr::LrW(dst, addr) => {
+                let val = se_word(self.load_reserved(addr, 4)? as u32);
+                self.set_signed(dst, val);
+            }
+            Instr::LrD(dst, addr) => {
+                let val = self.load_reserved(addr, 8)?;
+                self.set(dst, val);
+            }
+            Instr::ScW(dst, addr, src) => {
+                let failed = self.store_conditional(addr, 4, self.reg(src))?;
+                self.set(dst, failed as u64);
+            }
+            Instr::ScD(dst, addr, src) => {
+                let failed = self.store_conditional(addr, 8, self.reg(src))?;
+                self.set(dst, failed as u64);
+            }
+            Instr::AmoW(op, dst, addr, src) => {
+                let old = se_word(self.amo(op, addr, 4, self.reg(src))? as u32);
+                self.set_signed(dst, old);
+            }
+            Instr::AmoD(op, dst, addr, src) => {
+                let old = self.amo(op, addr, 8, self.reg(src))?;
+                self.set(dst, old);
+            }
+            // A handler starts at its own address, otherwise pc stays put for the report.
+            Instr::Illegal(raw) => {
+                let reason = ExitReason::IllegalInstruction { pc: self.pc, raw };
+                self.exit = crate::signals::raise(self, SIGILL, ILL_ILLOPC, self.pc, reason);
+            }
 
             Instr::Lw(dst, offset) => {
-                let val = se_word(self.load(offset, 4) as u32);
+                let val = se_word(self.load(offset, 4)? as u32);
                 self.set_signed(dst, val);
             }
             Instr::Lwu(dst, offset) => {
-                let val = self.load(offset, 4) as u64;
+                let val = self.load(offset, 4)? as u64;
                 self.set(dst, val);
             }
             Instr::Slliw(dst, rs1, imm) => {
@@ -561,24 +1257,59 @@ impl Emulator {
                 self.set_signed(dst, result as i64);
             }
 
+            // Division never traps: dividing by zero gives all ones and leaves the dividend
+            // as the remainder, and overflow gives the dividend and a remainder of zero.
             Instr::Mul(dst, rs1, rs2) => {
-                self.set(dst, (self.reg(rs1) as u32 * self.reg(rs2) as u32) as u64);
+                self.set(dst, self.reg(rs1).wrapping_mul(self.reg(rs2)));
+            }
+            Instr::Mulh(dst, rs1, rs2) => {
+                let product = self.reg_signed(rs1) as i128 * self.reg_signed(rs2) as i128;
+                self.set(dst, (product >> 64) as u64);
+            }
+            Instr::Mulhsu(dst, rs1, rs2) => {
+                let product = self.reg_signed(rs1) as i128 * self.reg(rs2) as i128;
+                self.set(dst, (product >> 64) as u64);
+            }
+            Instr::Mulhu(dst, rs1, rs2) => {
+                let product = self.reg(rs1) as u128 * self.reg(rs2) as u128;
+                self.set(dst, (product >> 64) as u64);
             }
             Instr::Div(dst, rs1, rs2) => {
-                self.set(dst, (self.reg(rs1) as u32 / self.reg(rs2) as u32) as u64);
+                let (a, b) = (self.reg_signed(rs1), self.reg_signed(rs2));
+                self.set_signed(dst, if b == 0 { -1 } else { a.wrapping_div(b) });
+            }
+            Instr::Divu(dst, rs1, rs2) => {
+                let (a, b) = (self.reg(rs1), self.reg(rs2));
+                self.set(dst, a.checked_div(b).unwrap_or(u64::MAX));
             }
             Instr::Rem(dst, rs1, rs2) => {
-                self.set(dst, (self.reg(rs1) as u32 % self.reg(rs2) as u32) as u64);
+                let (a, b) = (self.reg_signed(rs1), self.reg_signed(rs2));
+                self.set_signed(dst, if b == 0 { a } else { a.wrapping_rem(b) });
+            }
+            Instr::Remu(dst, rs1, rs2) => {
+                let (a, b) = (self.reg(rs1), self.reg(rs2));
+                self.set(dst, a.checked_rem(b).unwrap_or(a));
             }
 
             Instr::Mulw(dst, rs1, rs2) => {
-                self.set(dst, self.reg(rs1) * self.reg(rs2));
+                let result = (self.reg(rs1) as i32).wrapping_mul(self.reg(rs2) as i32);
+                self.set_signed(dst, result as i64);
             }
             Instr::Divw(dst, rs1, rs2) => {
-                self.set(dst, self.reg(rs1) / self.reg(rs2));
+                let (a, b) = (self.reg(rs1) as i32, self.reg(rs2) as i32);
+                self.set_signed(dst, if b == 0 { -1 } else { a.wrapping_div(b) as i64 });
+            }
+            Instr::Divuw(dst, rs1, rs2) => {
+                let (a, b) = (self.reg(rs1) as u32, self.reg(rs2) as u32);
+                self.set_signed(dst, a.checked_div(b).unwrap_or(u32::MAX) as i32 as i64);
             }
             Instr::Remw(dst, rs1, rs2) => {
-                self.set(dst, self.reg(rs1) % self.reg(rs2));
+                let (a, b) = (self.reg(rs1) as i32, self.reg(rs2) as i32);
+                self.set_signed(dst, if b == 0 { a } else { a.wrapping_rem(b) } as i64);
+            }
+            Instr::Remuw(dst, rs1, rs2) => {
+                let (a, b) = (self.reg(rs1) as u32, self.reg(rs2) as u32);
+                self.set_signed(dst, a.checked_rem(b).unwrap_or(a) as i32 as i64);
             }
         }
 
@@ -590,13 +1321,15 @@ impl Emulator {
             | Instr::Bne(_, _, _)
             | Instr::Beq(_, _, _)
             | Instr::Jal(_, _)
-            | Instr::Jalr(_, _, _) => {}
+            | Instr::Jalr(_, _, _)
+            | Instr::Illegal(_) => {}
             _ => {
                 self.add_pc(Imm::new(4));
             }
         }
 
         self.set(Reg::Zero, 0);
+        Ok(())
     }
 }
 
@@ -612,11 +1345,200 @@ fn se_word(byte: u32) -> i64 {
     ((byte as i64) << 32) >> 32
 }
 
-pub fn run_emulator(prgm: &[u8]) {
-    let mut emulator = Emulator::default();
-    emulator.flash_prgm(prgm, DRAM_OFFSET as u32);
-    emulator.set(Reg::Sp, STACK_OFFSET);
-    crate::interface::start(emulator);
+/// Host options for [`run_emulator`].
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// Report uses of uninitialised memory, see [`Memcheck`].
+    pub memcheck: bool,
+    /// Count accesses per cache line, shown as an overlay, see [`Heatmap`].
+    pub heatmap: bool,
+    /// Print every instruction to stderr as it executes.
+    pub trace: bool,
+    /// Run on a virtual clock with randomness from this seed, see [`Clock::Virtual`].
+    pub seed: Option<u64>,
+    /// Log every system call of a user-mode guest to stderr, see [`Strace`].
+    pub strace: bool,
+    /// File to write the system call log to instead of stderr.
+    pub strace_file: Option<std::path::PathBuf>,
+    /// Guest `argv`, starting with the program name.
+    pub args: Vec<String>,
+    /// Guest environment as `KEY=VALUE` pairs.
+    pub env: Vec<String>,
+    /// Host directory the guest sees as `/`.
+    pub root: Option<std::path::PathBuf>,
+    /// Device tree handed to a Linux kernel instead of one generated from the config.
+    pub dtb: Option<Vec<u8>>,
+    /// Initramfs handed to a Linux kernel.
+    pub initrd: Option<Vec<u8>>,
+    /// Run in the terminal instead of the GUI, see [`run_headless`].
+    pub headless: bool,
+}
+
+/// Run `prgm` in the GUI, see [`ImageFormat::detect`].
+///
+/// ELF, Intel HEX and S-record images are loaded at their own addresses, a Linux kernel
+/// `Image` is booted from the `ram` region and anything else is flashed as a raw binary at
+/// the reset vector.
+pub fn run_emulator(prgm: &[u8], config: &MachineConfig, options: &RunOptions) {
+    let mut emulator = config.build().unwrap_or_else(|err| panic!("{err}"));
+    if options.memcheck {
+        emulator.enable_memcheck();
+    }
+    if options.heatmap {
+        emulator.enable_heatmap(crate::heatmap::CACHE_LINE_BITS);
+    }
+    emulator.set_trace(options.trace);
+    if let Some(seed) = options.seed {
+        emulator.set_clock(Clock::seeded(seed));
+    }
+    match &options.strace_file {
+        Some(path) => {
+            let file = std::fs::File::create(path)
+                .unwrap_or_else(|err| panic!("{}: {err}", path.display()));
+            emulator.set_strace(Some(Strace::new(std::io::LineWriter::new(file))));
+        }
+        None if options.strace => emulator.set_strace(Some(Strace::stderr())),
+        None => {}
+    }
+    let text = || String::from_utf8_lossy(prgm);
+    let mut elf = None;
+    match ImageFormat::detect(prgm) {
+        ImageFormat::Elf => {
+            elf = Some(
+                emulator
+                    .load_elf(prgm)
+                    .unwrap_or_else(|err| panic!("{err}")),
+            );
+        }
+        ImageFormat::IntelHex => FirmwareImage::parse_ihex(&text())
+            .and_then(|image| emulator.load_firmware(&image))
+            .unwrap_or_else(|err| panic!("{err}")),
+        ImageFormat::SRecord => FirmwareImage::parse_srec(&text())
+            .and_then(|image| emulator.load_firmware(&image))
+            .unwrap_or_else(|err| panic!("{err}")),
+        ImageFormat::Linux => {
+            let ram = config
+                .region("ram")
+                .expect("a `ram` region to boot Linux in");
+            let ram = ram.base..ram.base + ram.size;
+            let initrd = options.initrd.as_deref();
+            let dtb = match &options.dtb {
+                Some(dtb) => dtb.clone(),
+                None => {
+                    // The tree only grows by the fixed size initrd properties, so it can be
+                    // measured with a placeholder range before the real one is known.
+                    let header = KernelHeader::parse(prgm).unwrap_or_else(|err| panic!("{err}"));
+                    let placeholder = initrd.map(|_| 0..0);
+                    let len = config.device_tree(placeholder).len() as u64;
+                    let initrd_len = initrd.map(|initrd| initrd.len() as u64);
+                    let layout = BootLayout::new(ram.clone(), &header, len, initrd_len)
+                        .unwrap_or_else(|err| panic!("{err}"));
+                    config.device_tree(layout.initrd)
+                }
+            };
+            let layout = emulator
+                .boot_linux(ram, prgm, &dtb, initrd, 0)
+                .unwrap_or_else(|err| panic!("{err}"));
+            eprintln!("booting Linux: {layout:#x?}");
+            start(emulator, options);
+            return;
+        }
+        ImageFormat::Raw => emulator
+            .flash_prgm(prgm, config.reset_pc)
+            .expect("program fits in user memory"),
+    }
+
+    let mut info = StartInfo {
+        args: options.args.clone(),
+        env: options.env.clone(),
+        ..Default::default()
+    };
+    emulator.fill_random(&mut info.random);
+    emulator.process_mut().set_root(options.root.clone());
+    emulator
+        .start_process(&info, elf.as_ref())
+        .expect("arguments fit on the stack");
+    start(emulator, options);
+}
+
+fn start(emulator: Emulator, options: &RunOptions) {
+    if options.headless {
+        let reason = run_headless(emulator);
+        eprintln!("emu: {reason:?}");
+    } else {
+        crate::interface::start(emulator);
+    }
+}
+
+/// Instructions [`run_headless`] runs between looking for host input.
+const HEADLESS_SLICE: usize = 100_000;
+
+/// Run without the GUI, printing the console to stdout and feeding host stdin to the guest.
+///
+/// Devices tick [`TICK_HZ`] times a second of host time unless the clock is virtual. On a
+/// virtual clock all of stdin is read before the guest starts, so the guest sees its input
+/// at the same point of every run. Returns once the guest exits.
+pub fn run_headless(mut emulator: Emulator) -> ExitReason {
+    use std::io::{Read, Write};
+    use std::sync::mpsc::RecvTimeoutError;
+    use std::time::Instant;
+
+    let virtual_clock = emulator.clock().is_virtual();
+    let (sender, receiver) = std::sync::mpsc::channel();
+    if virtual_clock {
+        let mut input = Vec::new();
+        let _ = std::io::stdin().read_to_end(&mut input);
+        emulator.push_input(&input);
+        emulator.close_input();
+    } else {
+        // Reading stdin blocks, so it gets a thread of its own. Dropping the sender is end
+        // of file.
+        std::thread::spawn(move || {
+            let mut buf = [0; 4096];
+            let mut stdin = std::io::stdin().lock();
+            while let Ok(len @ 1..) = stdin.read(&mut buf) {
+                if sender.send(buf[..len].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    let tick = Duration::from_secs(1) / TICK_HZ as u32;
+    let mut next_tick = Instant::now() + tick;
+    let mut printed = 0;
+    let mut stdout = std::io::stdout();
+    loop {
+        let reason = emulator.run_for(HEADLESS_SLICE);
+        let console = emulator.console();
+        let _ = stdout
+            .write_all(&console[printed..])
+            .and_then(|_| stdout.flush());
+        printed = console.len();
+        if emulator.finished() {
+            return reason;
+        }
+        if virtual_clock {
+            continue;
+        }
+
+        // Only sleep when the guest cannot get any further without the host.
+        let received = match reason {
+            ExitReason::WaitingForInput => {
+                receiver.recv_timeout(next_tick.saturating_duration_since(Instant::now()))
+            }
+            _ => receiver.recv_timeout(Duration::ZERO),
+        };
+        match received {
+            Ok(data) => emulator.push_input(&data),
+            Err(RecvTimeoutError::Disconnected) => emulator.close_input(),
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+        while Instant::now() >= next_tick {
+            emulator.tick();
+            next_tick += tick;
+        }
+    }
 }
 
 pub fn print_emulator(emulator: &Emulator) {
@@ -625,24 +1547,49 @@ pub fn print_emulator(emulator: &Emulator) {
         println!("x{} \t{:#018x}", i, emulator.regs[i]);
     }
 
-    println!("\nHeap:");
-    for mem in 0..8 {
-        print!("{:#09x}\t", mem * 8 * 16 + HEAP_OFFSET as usize);
-        let index = mem * 16 + HEAP_OFFSET as usize;
-        for byte in emulator.memory[index..index + 16].iter() {
-            print!("{:02X} ", byte);
+    if let Some(heap) = emulator.guest_memory().region("heap") {
+        println!("\nHeap:");
+        for mem in 0..8 {
+            let index = mem * 16 + heap.range.start;
+            print!("{:#09x}\t", index);
+            for byte in emulator.guest_memory().slice(index, 16).iter() {
+                print!("{:02X} ", byte);
+            }
+            println!();
         }
-        println!();
     }
 
     println!("\nConsole:\n{}", String::from_utf8_lossy(&emulator.console));
-    println!("\nexit code: {}", emulator.exit_code);
+    println!("\npc: {}", emulator.describe(emulator.current_pc));
+    match emulator.exit {
+        Some(ExitReason::Fault(fault)) => {
+            println!("\n{fault}\n   at {}", emulator.describe(fault.pc))
+        }
+        Some(ExitReason::NullPointer { pc }) => {
+            println!("\nnull pointer access\n   at {}", emulator.describe(pc))
+        }
+        Some(ExitReason::IllegalInstruction { pc, raw }) => {
+            println!(
+                "\nillegal instruction {raw:#010x}\n   at {}",
+                emulator.describe(pc)
+            )
+        }
+        _ => {}
+    }
+    if let Some(memcheck) = emulator.memcheck() {
+        println!("\nmemcheck: {} errors", memcheck.reports().len());
+    }
+    if let Some(code) = emulator.exit.and_then(|reason| reason.exit_code()) {
+        println!("\nexit code: {}", code);
+    }
 }
 
 /// https://github.com/d0iasm/rvemu/blob/main/tests/rv32i.rs
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::devices::Clint;
+    use crate::memcheck::UninitUse;
 
     const REGISTERS_COUNT: usize = 32;
 
@@ -663,7 +1610,7 @@ mod tests {
 
     /// Start a test and check if the registers are expected.
     pub fn run(emulator: &mut Emulator, data: Vec<u8>, expected_xregs: &[u64; 32]) {
-        emulator.flash_prgm(&data, 0);
+        emulator.flash_prgm(&data, 0).unwrap();
         emulator.run_for(data.len() / 4);
 
         for (i, e) in expected_xregs.iter().enumerate() {
@@ -1067,6 +2014,146 @@ mod tests {
         run(&mut emu, data, &expected_xregs);
     }
 
+    #[test]
+    fn div_rem_never_trap() {
+        let mut emu = Emulator::default();
+        emu.set(Reg::T(0), i32::MIN as i64 as u64);
+        emu.regs[16] = 7;
+        emu.regs[18] = i64::MIN as u64;
+        emu.regs[19] = u64::MAX;
+        emu.regs[23] = 1;
+
+        let data = vec![
+            0x33, 0x4a, 0x18, 0x03, // div x20, x16, x17
+            0xb3, 0x6a, 0x18, 0x03, // rem x21, x16, x17
+            0x33, 0x4b, 0x39, 0x03, // div x22, x18, x19
+            0xb3, 0x6b, 0x39, 0x03, // rem x23, x18, x19
+            0x3b, 0xcc, 0x32, 0x03, // divw x24, x5, x19
+            0xbb, 0x6c, 0x18, 0x03, // remw x25, x16, x17
+            0x3b, 0x4d, 0x18, 0x03, // divw x26, x16, x17
+        ];
+        let expected_xregs = create_xregs(vec![
+            (20, u64::MAX),
+            (21, 7),
+            (22, i64::MIN as u64),
+            (24, i32::MIN as i64 as u64),
+            (25, 7),
+            (26, u64::MAX),
+        ]);
+
+        run(&mut emu, data, &expected_xregs);
+        assert_eq!(0, emu.regs[23]);
+    }
+
+    #[test]
+    fn mul_high_and_unsigned_division() {
+        let mut emu = Emulator::default();
+        emu.regs[16] = u64::MAX;
+        emu.regs[17] = 2;
+
+        let data = vec![
+            0x33, 0x9a, 0x08, 0x03, // mulh x20, x17, x16
+            0xb3, 0xaa, 0x08, 0x03, // mulhsu x21, x17, x16
+            0x33, 0x3b, 0x08, 0x03, // mulhu x22, x16, x16
+            0xb3, 0x5b, 0x18, 0x03, // divu x23, x16, x17
+            0x33, 0x7c, 0x18, 0x03, // remu x24, x16, x17
+            0xb3, 0x5c, 0x08, 0x02, // divu x25, x16, x0
+            0x33, 0xfd, 0x08, 0x02, // remu x26, x17, x0
+            0xbb, 0x5d, 0x18, 0x03, // divuw x27, x16, x17
+            0x3b, 0x7e, 0x08, 0x02, // remuw x28, x16, x0
+            0xbb, 0xde, 0x08, 0x02, // divuw x29, x17, x0
+        ];
+        let expected_xregs = create_xregs(vec![
+            (20, u64::MAX),
+            (21, 1),
+            (22, u64::MAX - 1),
+            (23, i64::MAX as u64),
+            (24, 1),
+            (25, u64::MAX),
+            (26, 2),
+            (27, 0x7fff_ffff),
+            (28, u64::MAX),
+            (29, u64::MAX),
+        ]);
+
+        run(&mut emu, data, &expected_xregs);
+    }
+
+    #[test]
+    fn atomics() {
+        let mut emu = Emulator::default();
+        emu.host_write(0x100, &(-16i64).to_le_bytes()).unwrap();
+        emu.host_write(0x108, &0x10u64.to_le_bytes()).unwrap();
+        emu.regs[8] = 0x100;
+        emu.regs[9] = 0x108;
+        emu.regs[10] = 0x102;
+        emu.regs[11] = u64::MAX;
+
+        let data = vec![
+            0x2f, 0x26, 0xb4, 0x80, // amomin.w x12, x11, (x8)
+            0xaf, 0xa6, 0xb4, 0xe0, // amomaxu.w x13, x11, (x9)
+            0x2f, 0xb7, 0x04, 0x10, // lr.d x14, (x9)
+            0xaf, 0x37, 0xb4, 0x18, // sc.d x15, x11, (x8)
+            0x2f, 0xb7, 0x04, 0x10, // lr.d x14, (x9)
+            0x2f, 0xb8, 0xb4, 0x18, // sc.d x16, x11, (x9)
+            0x83, 0xb8, 0x04, 0x00, // ld x17, 0(x9)
+            0x2f, 0x25, 0xb5, 0x08, // amoswap.w x10, x11, (x10)
+        ];
+        emu.flash_prgm(&data, 0).unwrap();
+        let reason = emu.run_for(data.len() / 4);
+
+        // The signed minimum of a word sees its sign bit, the unsigned maximum does not.
+        assert_eq!(-16, emu.regs[12] as i64);
+        assert_eq!(0x10, emu.regs[13]);
+        assert_eq!(0xFFFF_FFFF, emu.regs[14]);
+        // An `sc` to another address fails and drops the reservation.
+        assert_eq!((1, 0), (emu.regs[15], emu.regs[16]));
+        assert_eq!(u64::MAX, emu.regs[17]);
+        assert_eq!(
+            ExitReason::Fault(MemoryFault {
+                addr: 0x102,
+                size: 4,
+                kind: AccessKind::Store,
+                cause: FaultCause::Misaligned,
+                pc: 28,
+            }),
+            reason
+        );
+    }
+
+    #[test]
+    fn mul_and_shifts_wrap() {
+        let mut emu = Emulator::default();
+        emu.regs[16] = 0x1_0000_0001;
+        emu.regs[17] = 3;
+        emu.regs[18] = 0x7fff_ffff;
+        emu.regs[19] = 2;
+        emu.regs[23] = 65;
+        emu.regs[25] = 33;
+        emu.regs[28] = i32::MIN as i64 as u64;
+
+        let data = vec![
+            0x33, 0x0a, 0x18, 0x03, // mul x20, x16, x17
+            0xbb, 0x0a, 0x39, 0x03, // mulw x21, x18, x19
+            0x33, 0x9b, 0x78, 0x01, // sll x22, x17, x23
+            0x3b, 0x9c, 0x98, 0x01, // sllw x24, x17, x25
+            0x3b, 0x1d, 0x39, 0x01, // sllw x26, x18, x19
+            0xbb, 0x5d, 0x9e, 0x01, // srlw x27, x28, x25
+            0xbb, 0x5e, 0x9e, 0x41, // sraw x29, x28, x25
+        ];
+        let expected_xregs = create_xregs(vec![
+            (20, 0x3_0000_0003),
+            (21, -2i64 as u64),
+            (22, 6),
+            (24, 6),
+            (26, -4i64 as u64),
+            (27, 0x4000_0000),
+            (29, 0xffff_ffff_c000_0000),
+        ]);
+
+        run(&mut emu, data, &expected_xregs);
+    }
+
     #[test]
     fn lui_rd_imm() {
         let mut emu = Emulator::default();
@@ -1191,6 +2278,21 @@ mod tests {
         assert_eq!(44, emu.pc);
     }
 
+    #[test]
+    fn jalr_rd_is_rs1() {
+        let mut emu = Emulator::default();
+
+        let data = vec![
+            0x93, 0x00, 0x80, 0x02, // addi x1, x0, 40
+            0xe7, 0x80, 0x40, 0x00, // jalr x1, 4(x1)
+        ];
+        let expected_xregs = create_xregs(vec![(1, 8)]);
+
+        run(&mut emu, data, &expected_xregs);
+
+        assert_eq!(44, emu.pc);
+    }
+
     #[test]
     fn jal_rd_imm() {
         let mut emu = Emulator::default();
@@ -1206,4 +2308,400 @@ mod tests {
 
         assert_eq!(20, emu.pc);
     }
+
+    #[test]
+    fn load_fault_reports_address() {
+        let mut emu = Emulator::default();
+
+        let data = vec![
+            0x13, 0x08, 0xe0, 0xff, // addi x16, x0, -2
+            0x83, 0x28, 0x08, 0x00, // lw x17, 0(x16)
+        ];
+        emu.flash_prgm(&data, 0).unwrap();
+        let reason = emu.run_for(data.len() / 4);
+
+        assert!(emu.finished());
+        assert_eq!(
+            ExitReason::Fault(MemoryFault {
+                addr: 0xFFFF_FFFF_FFFF_FFFE,
+                size: 4,
+                kind: AccessKind::Load,
+                cause: FaultCause::Unmapped,
+                pc: 4,
+            }),
+            reason
+        );
+    }
+
+    #[test]
+    fn load_above_4gib_does_not_alias() {
+        let data = vec![
+            0x13, 0x08, 0x10, 0x00, // addi x16, x0, 1
+            0x13, 0x18, 0x08, 0x02, // slli x16, x16, 32
+            0x83, 0x38, 0x88, 0x00, // ld x17, 8(x16)
+        ];
+
+        let mut emu = Emulator::default();
+        emu.flash_prgm(&data, 0).unwrap();
+        assert_eq!(
+            ExitReason::Fault(MemoryFault {
+                addr: 0x1_0000_0008,
+                size: 8,
+                kind: AccessKind::Load,
+                cause: FaultCause::Unmapped,
+                pc: 8,
+            }),
+            emu.run_for(data.len() / 4)
+        );
+
+        let mut emu = Emulator::new(36);
+        emu.flash_prgm(&0xDEAD_BEEFu64.to_le_bytes(), 0x1_0000_0008)
+            .unwrap();
+        emu.flash_prgm(&data, 0).unwrap();
+        emu.run_for(data.len() / 4);
+        assert_eq!(0xDEAD_BEEF, emu.reg(Reg::A(7)));
+    }
+
+    #[test]
+    fn run_reports_exit_and_budget() {
+        let mut emu = Emulator::default();
+
+        let data = vec![
+            0x13, 0x05, 0x70, 0x00, // addi x10, x0, 7
+            0x93, 0x08, 0xd0, 0x05, // addi x17, x0, 93
+            0x73, 0x00, 0x00, 0x00, // ecall
+        ];
+        emu.flash_prgm(&data, 0).unwrap();
+
+        assert_eq!(ExitReason::BudgetExhausted, emu.run_for(1));
+        assert_eq!(ExitReason::Breakpoint(8), emu.run_until(8));
+        assert_eq!(ExitReason::Exit(7), emu.run());
+        assert_eq!(ExitReason::Exit(7), emu.run());
+    }
+
+    #[test]
+    fn default_devices() {
+        let config = MachineConfig::default();
+        let mut emu = config.build().unwrap();
+
+        let data = vec![
+            0x93, 0x02, 0x10, 0x04, // addi x5, x0, 65
+            0x23, 0x02, 0x50, 0x00, // sb x5, 4(x0)
+            0x13, 0x05, 0x30, 0x00, // addi x10, x0, 3
+            0x03, 0x20, 0x10, 0x00, // lw x0, 1(x0)
+        ];
+        emu.flash_prgm(&data, config.reset_pc).unwrap();
+
+        assert_eq!(ExitReason::Exit(3), emu.run());
+        assert_eq!(b"A", emu.console());
+    }
+
+    #[test]
+    fn console_port_input() {
+        let config = MachineConfig::default();
+        let mut emu = config.build().unwrap();
+
+        let data = vec![
+            0x03, 0x43, 0x50, 0x00, // lbu x6, 5(x0)
+            0x83, 0x42, 0x40, 0x00, // lbu x5, 4(x0)
+        ];
+        emu.flash_prgm(&data, config.reset_pc).unwrap();
+
+        assert_eq!(ExitReason::WaitingForInput, emu.run_for(3));
+        assert_eq!(0, emu.reg(Reg::T(1)));
+        assert_eq!(0xFF, emu.reg(Reg::T(0)));
+        assert_eq!(config.reset_pc + 8, emu.pc());
+
+        emu.push_input(b"A");
+        emu.set_pc(config.reset_pc);
+        assert_eq!(ExitReason::BudgetExhausted, emu.run_for(2));
+        assert_eq!((1, b'A' as u64), (emu.reg(Reg::T(1)), emu.reg(Reg::T(0))));
+    }
+
+    #[test]
+    fn device_tree_below_stack() {
+        let config = MachineConfig::default();
+        let mut emu = config.build().unwrap();
+        let top = emu.stack_top();
+
+        let dtb = config.device_tree(None);
+        let addr = emu.load_device_tree(&dtb).unwrap();
+        emu.tick();
+        emu.tick();
+
+        assert_eq!(addr, emu.reg(Reg::A(1)));
+        assert!(addr + dtb.len() as u64 <= top);
+        assert!(emu.stack_top() <= addr);
+        assert_eq!(&dtb[..8], emu.memory(addr, 8).unwrap().as_ref());
+        assert_eq!(2, emu.bus().device::<Clint>().unwrap().mtime());
+    }
+
+    #[test]
+    fn ebreak_stops_and_resumes() {
+        let mut emu = Emulator::default();
+
+        let data = vec![
+            0x73, 0x00, 0x10, 0x00, // ebreak
+            0x13, 0x08, 0x30, 0x00, // addi x16, x0, 3
+        ];
+        emu.flash_prgm(&data, 0).unwrap();
+
+        assert_eq!(ExitReason::Breakpoint(0), emu.run_for(2));
+        assert_eq!(ExitReason::BudgetExhausted, emu.run_for(1));
+        assert_eq!(3, emu.reg(Reg::A(6)));
+    }
+
+    #[test]
+    fn write_protected_text() {
+        let config = MachineConfig::default();
+        let mut emu = config.build().unwrap();
+
+        let data = vec![
+            0x23, 0x20, 0x05, 0x00, // sw x0, 0(x10)
+        ];
+        let text = config.reset_pc..config.reset_pc + data.len() as u64;
+        emu.flash_prgm(&data, config.reset_pc).unwrap();
+        assert!(emu.bus_mut().memory_mut().protect(text, Perms::RX));
+        emu.set(Reg::A(0), config.reset_pc);
+
+        assert_eq!(
+            ExitReason::Fault(MemoryFault {
+                addr: config.reset_pc,
+                size: 4,
+                kind: AccessKind::Store,
+                cause: FaultCause::Permission,
+                pc: config.reset_pc,
+            }),
+            emu.run()
+        );
+    }
+
+    #[test]
+    fn stack_is_not_executable() {
+        let config = MachineConfig::default();
+        let mut emu = config.build().unwrap();
+
+        let data = vec![
+            0x67, 0x00, 0x01, 0x00, // jalr x0, 0(x2)
+        ];
+        emu.flash_prgm(&data, config.reset_pc).unwrap();
+        let sp = emu.stack_top() - 16;
+        emu.set(Reg::Sp, sp);
+
+        assert_eq!(
+            ExitReason::Fault(MemoryFault {
+                addr: sp,
+                size: 4,
+                kind: AccessKind::Fetch,
+                cause: FaultCause::Permission,
+                pc: sp,
+            }),
+            emu.run()
+        );
+    }
+
+    #[test]
+    fn forks_diverge() {
+        let mut emu = Emulator::default();
+
+        let data = vec![
+            0x13, 0x08, 0x30, 0x00, // addi x16, x0, 3
+            0x37, 0x25, 0x00, 0x00, // lui x10, 2
+            0x23, 0x30, 0x05, 0x01, // sd x16, 0(x10)
+        ];
+        emu.flash_prgm(&data, 0).unwrap();
+        emu.run_for(2);
+
+        let mut fork = emu.fork();
+        fork.set(Reg::A(6), 7);
+        emu.run_for(1);
+        fork.run_for(1);
+
+        assert_eq!(3, emu.guest_memory().byte(0x2000));
+        assert_eq!(7, fork.guest_memory().byte(0x2000));
+        assert_eq!(vec![0x2000], emu.diff_pages(&fork));
+    }
+
+    #[test]
+    fn memcheck_reports_undefined_branch() {
+        let mut emu = Emulator::default();
+        emu.enable_memcheck();
+
+        let data = vec![
+            0x37, 0x25, 0x00, 0x00, // lui x10, 2
+            0x83, 0x35, 0x05, 0x00, // ld x11, 0(x10)
+            0x63, 0x84, 0x05, 0x00, // beq x11, x0, 8
+            0x13, 0x00, 0x00, 0x00, // addi x0, x0, 0
+            0x23, 0x30, 0x05, 0x00, // sd x0, 0(x10)
+            0x83, 0x35, 0x05, 0x00, // ld x11, 0(x10)
+            0x63, 0x84, 0x05, 0x00, // beq x11, x0, 8
+        ];
+        emu.flash_prgm(&data, 0).unwrap();
+        // The first branch skips the nop.
+        emu.run_for(data.len() / 4 - 1);
+
+        let memcheck = emu.memcheck().unwrap();
+        assert_eq!(1, memcheck.reports().len());
+        assert_eq!(UninitUse::Branch, memcheck.reports()[0].kind);
+        assert_eq!(8, memcheck.reports()[0].pc);
+    }
+
+    #[test]
+    fn heatmap_counts_accesses() {
+        let mut emu = Emulator::default();
+        emu.enable_heatmap(crate::heatmap::CACHE_LINE_BITS);
+
+        let data = vec![
+            0x37, 0x25, 0x00, 0x00, // lui x10, 2
+            0x23, 0x30, 0x05, 0x00, // sd x0, 0(x10)
+            0x83, 0x35, 0x05, 0x00, // ld x11, 0(x10)
+            0x83, 0x35, 0x05, 0x04, // ld x11, 64(x10)
+        ];
+        emu.flash_prgm(&data, 0).unwrap();
+        emu.run_for(data.len() / 4);
+
+        let heatmap = emu.heatmap().unwrap();
+        assert_eq!(4, heatmap.counts(0).executes);
+        assert_eq!(1, heatmap.counts(0x2000).writes);
+        assert_eq!(1, heatmap.counts(0x2000).reads);
+        assert_eq!(2, heatmap.blocks_touched(0x2000..0x3000));
+        assert_eq!(64, heatmap.window(0x2000..0x3000).len());
+    }
+
+    #[test]
+    fn load_elf_zero_fills_bss() {
+        let config = MachineConfig::default();
+        let mut emu = config.build().unwrap();
+        emu.enable_memcheck();
+
+        let text = [
+            0x37, 0x05, 0x00, 0x40, // lui x10, 0x40000
+            0x83, 0x35, 0x05, 0x10, // ld x11, 256(x10)
+            0x23, 0x20, 0x05, 0x00, // sw x0, 0(x10)
+        ];
+        let elf = crate::elf::tests::build_elf(
+            config.reset_pc,
+            &[
+                (config.reset_pc, &text, text.len() as u64, 0b101),
+                (config.reset_pc + 0x100, &[], 8, 0b110),
+            ],
+        );
+        emu.flash_prgm(&[0xFF; 0x200], config.reset_pc).unwrap();
+        let image = emu.load_elf(&elf).unwrap();
+
+        assert_eq!(config.reset_pc, emu.pc());
+        // The two segments and the program headers after them.
+        assert_eq!(3, image.segments.len());
+        let reason = emu.run();
+        assert_eq!(0, emu.reg(Reg::A(1)));
+        assert!(emu.memcheck().unwrap().reports().is_empty());
+        assert!(matches!(
+            reason,
+            ExitReason::Fault(MemoryFault {
+                kind: AccessKind::Store,
+                cause: FaultCause::Permission,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn return_to_zero() {
+        let ret = [0x67, 0x80, 0x00, 0x00]; // ret
+                                            // A bare-metal `main` returning with ra still 0 ends the run.
+        let (mut emu, _) = crate::testing::machine(&ret);
+        emu.set(Reg::A(0), 3);
+        assert_eq!(ExitReason::Exit(3), emu.run_for(1));
+
+        // A Linux process has nothing to return to, it gets a fetch fault at 0 instead.
+        let config = MachineConfig::default();
+        let mut emu = config.build().unwrap();
+        let elf =
+            crate::elf::tests::build_elf(config.reset_pc, &[(config.reset_pc, &ret, 4, 0b101)]);
+        let image = emu.load_elf(&elf).unwrap();
+        emu.start_process(&StartInfo::default(), Some(&image))
+            .unwrap();
+        assert_eq!(
+            ExitReason::Fault(MemoryFault {
+                addr: 0,
+                size: 4,
+                kind: AccessKind::Fetch,
+                cause: FaultCause::Unmapped,
+                pc: 0,
+            }),
+            emu.run_for(1)
+        );
+        assert_eq!(0, emu.reg(Reg::Zero));
+    }
+
+    #[test]
+    fn load_elf_checks_whole_segment() {
+        let config = MachineConfig::default();
+        let mut emu = config.build().unwrap();
+        emu.flash_prgm(&[0xFF; 4], config.reset_pc).unwrap();
+
+        // BSS running 128 MiB past the end of RAM.
+        let elf = crate::elf::tests::build_elf(
+            config.reset_pc,
+            &[(config.reset_pc, &[0; 4], 0x1000_0000, 0b110)],
+        );
+        assert!(matches!(
+            emu.load_elf(&elf),
+            Err(ElfError::Fault(MemoryFault {
+                cause: FaultCause::Unmapped,
+                ..
+            }))
+        ));
+        assert_eq!(&[0xFF; 4], emu.memory(config.reset_pc, 4).unwrap().as_ref());
+    }
+
+    #[test]
+    fn load_sparse_srec() {
+        let config = MachineConfig::default();
+        let mut emu = config.build().unwrap();
+
+        // Heap word at 0x8 and an entry point in RAM.
+        let image = FirmwareImage::parse_srec(
+            "S10700080102030AE0\nS30940000000AABBCCDDA8\nS70540000000BA\n",
+        )
+        .unwrap();
+        emu.load_firmware(&image).unwrap();
+
+        assert_eq!(0x4000_0000, emu.pc());
+        assert_eq!(&[1, 2, 3, 10], emu.memory(0x8, 4).unwrap().as_ref());
+        assert_eq!(
+            &[0xAA, 0xBB, 0xCC, 0xDD],
+            emu.memory(0x4000_0000, 4).unwrap().as_ref()
+        );
+    }
+
+    #[test]
+    fn boot_linux_protocol() {
+        let config = MachineConfig::default();
+        let ram = config.region("ram").unwrap();
+        let mut emu = config.build().unwrap();
+
+        let mut kernel = vec![0; 64];
+        kernel[8..16].copy_from_slice(&0x20_0000u64.to_le_bytes()); // text_offset
+        kernel[56..60].copy_from_slice(crate::boot::IMAGE_MAGIC);
+        let dtb = [0xD0, 0x0D, 0xFE, 0xED];
+        let layout = emu
+            .boot_linux(
+                ram.base..ram.base + ram.size,
+                &kernel,
+                &dtb,
+                Some(&[1; 8]),
+                0,
+            )
+            .unwrap();
+
+        assert_eq!(ImageFormat::Linux, ImageFormat::detect(&kernel));
+        assert_eq!(0x4020_0000, emu.pc());
+        assert_eq!(0, emu.reg(Reg::A(0)));
+        assert_eq!(layout.dtb.start, emu.reg(Reg::A(1)));
+        assert_eq!(Privilege::Supervisor, emu.privilege());
+        assert_eq!(&dtb, emu.memory(layout.dtb.start, 4).unwrap().as_ref());
+        let initrd = layout.initrd.unwrap();
+        assert_eq!(&[1; 8], emu.memory(initrd.start, 8).unwrap().as_ref());
+    }
 }
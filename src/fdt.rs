@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+const HEADER_SIZE: usize = 40;
+/// Size of the empty memory reservation map, a single terminating entry.
+const RSVMAP_SIZE: usize = 16;
+
+/// Writes a flattened device tree (version 17) one node and property at a time.
+///
+/// Nodes are opened with [`Self::begin_node`] and closed with [`Self::end_node`], properties
+/// belong to the innermost open node.
+#[derive(Debug, Default)]
+pub struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+    depth: usize,
+}
+
+impl FdtWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn token(&mut self, token: u32) {
+        self.structure.extend_from_slice(&token.to_be_bytes());
+    }
+
+    fn pad(&mut self) {
+        let len = self.structure.len().next_multiple_of(4);
+        self.structure.resize(len, 0);
+    }
+
+    /// Open a node, the root node is called `""`.
+    pub fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.pad();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "no open device tree node");
+        self.token(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let offset = match self.string_offsets.get(name) {
+            Some(offset) => *offset,
+            None => {
+                let offset = self.strings.len() as u32;
+                self.strings.extend_from_slice(name.as_bytes());
+                self.strings.push(0);
+                self.string_offsets.insert(name.to_owned(), offset);
+                offset
+            }
+        };
+
+        self.token(FDT_PROP);
+        self.token(value.len() as u32);
+        self.token(offset);
+        self.structure.extend_from_slice(value);
+        self.pad();
+    }
+
+    /// Property without a value, such as `interrupt-controller`.
+    pub fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, val: u32) {
+        self.property_cells(name, &[val]);
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    /// Each value as two cells, for `#address-cells = <2>` and `#size-cells = <2>`.
+    pub fn property_u64s(&mut self, name: &str, vals: &[u64]) {
+        let value: Vec<u8> = vals.iter().flat_map(|val| val.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    pub fn property_str(&mut self, name: &str, val: &str) {
+        self.property_strs(name, &[val]);
+    }
+
+    /// String list, such as a `compatible` with fallbacks.
+    pub fn property_strs(&mut self, name: &str, vals: &[&str]) {
+        let mut value = Vec::new();
+        for val in vals {
+            value.extend_from_slice(val.as_bytes());
+            value.push(0);
+        }
+        self.property(name, &value);
+    }
+
+    /// The blob, with `boot_cpuid` as the boot hart.
+    pub fn finish(mut self, boot_cpuid: u32) -> Vec<u8> {
+        assert_eq!(0, self.depth, "unclosed device tree node");
+        self.token(FDT_END);
+
+        let off_mem_rsvmap = HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + RSVMAP_SIZE;
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let total_size = off_dt_strings + self.strings.len();
+
+        let mut blob = Vec::with_capacity(total_size);
+        for field in [
+            FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            17, // version
+            16, // last_comp_version
+            boot_cpuid,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ] {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        blob.resize(off_dt_struct, 0);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+
+        blob
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn be32(blob: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn writes_header_and_shares_strings() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fdt.property_u32("#size-cells", 2);
+        fdt.begin_node("cpu@0");
+        fdt.property_u32("#size-cells", 0);
+        fdt.end_node();
+        fdt.end_node();
+        let blob = fdt.finish(0);
+
+        assert_eq!(FDT_MAGIC, be32(&blob, 0));
+        assert_eq!(blob.len() as u32, be32(&blob, 4));
+        let structure = be32(&blob, 8) as usize;
+        let strings = be32(&blob, 12) as usize;
+        assert_eq!(b"#size-cells\0", &blob[strings..]);
+        assert_eq!(FDT_BEGIN_NODE, be32(&blob, structure));
+        // Root name and padding, then the first property with its name at offset 0.
+        assert_eq!(
+            [FDT_PROP, 4, 0, 2],
+            [0, 4, 8, 12].map(|i| be32(&blob, structure + 8 + i))
+        );
+        assert_eq!(FDT_END, be32(&blob, strings - 4));
+    }
+}
@@ -0,0 +1,303 @@
+use crate::memory::MemoryFault;
+use std::fmt;
+
+/// Contents of an Intel HEX or Motorola S-record file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FirmwareImage {
+    /// Data records in file order, each written where it says.
+    pub chunks: Vec<(u64, Vec<u8>)>,
+    /// Address from the start address record.
+    pub entry: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum FirmwareError {
+    Syntax {
+        line: usize,
+        msg: &'static str,
+    },
+    Checksum {
+        line: usize,
+        expected: u8,
+        found: u8,
+    },
+    /// A record could not be written to guest memory.
+    Fault(MemoryFault),
+}
+
+impl fmt::Display for FirmwareError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Syntax { line, msg } => write!(f, "line {line}: {msg}"),
+            Self::Checksum {
+                line,
+                expected,
+                found,
+            } => write!(
+                f,
+                "line {line}: checksum mismatch, expected {expected:#04x}, found {found:#04x}"
+            ),
+            Self::Fault(fault) => write!(f, "record does not fit in guest memory: {fault}"),
+        }
+    }
+}
+
+impl std::error::Error for FirmwareError {}
+
+/// How a program image is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Elf,
+    IntelHex,
+    SRecord,
+    /// RISC-V Linux kernel `Image`.
+    Linux,
+    Raw,
+}
+
+impl ImageFormat {
+    /// Guess the format from the contents.
+    pub fn detect(data: &[u8]) -> Self {
+        if crate::elf::is_elf(data) {
+            return Self::Elf;
+        }
+        if crate::boot::is_kernel_image(data) {
+            return Self::Linux;
+        }
+
+        let Ok(text) = std::str::from_utf8(data) else {
+            return Self::Raw;
+        };
+        let text = text.trim_start().as_bytes();
+        if text.starts_with(b":") {
+            Self::IntelHex
+        } else if matches!(text, [b'S', b'0'..=b'3', ..]) {
+            Self::SRecord
+        } else {
+            Self::Raw
+        }
+    }
+}
+
+/// Hex bytes of a record, after the start code.
+fn decode_hex(line: usize, record: &str) -> Result<Vec<u8>, FirmwareError> {
+    let syntax = |msg| FirmwareError::Syntax { line, msg };
+
+    if !record.len().is_multiple_of(2) {
+        return Err(syntax("odd number of hex digits"));
+    }
+    (0..record.len())
+        .step_by(2)
+        .map(|i| {
+            record
+                .get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or(syntax("invalid hex digit"))
+        })
+        .collect()
+}
+
+fn be_addr(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |addr, byte| addr << 8 | *byte as u64)
+}
+
+/// Records of a text file with their 1 based line numbers, skipping blank lines.
+fn records(src: &str) -> impl Iterator<Item = (usize, &str)> {
+    src.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty())
+}
+
+impl FirmwareImage {
+    /// Parse an Intel HEX file, including extended segment and linear addressing.
+    pub fn parse_ihex(src: &str) -> Result<Self, FirmwareError> {
+        let mut image = Self::default();
+        let mut base = 0;
+        // Set by an extended segment address, whose offsets wrap at 64 KiB.
+        let mut segmented = false;
+
+        for (line, record) in records(src) {
+            let syntax = |msg| FirmwareError::Syntax { line, msg };
+
+            let record = record
+                .strip_prefix(':')
+                .ok_or(syntax("expected `:` start code"))?;
+            let bytes = decode_hex(line, record)?;
+            if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+                return Err(syntax("record length does not match byte count"));
+            }
+
+            let (body, checksum) = bytes.split_at(bytes.len() - 1);
+            let expected = body
+                .iter()
+                .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+                .wrapping_neg();
+            if expected != checksum[0] {
+                return Err(FirmwareError::Checksum {
+                    line,
+                    expected,
+                    found: checksum[0],
+                });
+            }
+
+            let offset = be_addr(&body[1..3]);
+            let data = &body[4..];
+            match body[3] {
+                0x00 if segmented => {
+                    let (head, tail) = data.split_at(data.len().min(0x10000 - offset as usize));
+                    image.chunks.push((base + offset, head.to_vec()));
+                    if !tail.is_empty() {
+                        image.chunks.push((base, tail.to_vec()));
+                    }
+                }
+                0x00 => image.chunks.push((base + offset, data.to_vec())),
+                0x01 => break,
+                0x02 if data.len() == 2 => {
+                    base = be_addr(data) << 4;
+                    segmented = true;
+                }
+                0x03 if data.len() == 4 => {
+                    image.entry = Some((be_addr(&data[..2]) << 4) + be_addr(&data[2..]))
+                }
+                0x04 if data.len() == 2 => {
+                    base = be_addr(data) << 16;
+                    segmented = false;
+                }
+                0x05 if data.len() == 4 => image.entry = Some(be_addr(data)),
+                0x02..=0x05 => return Err(syntax("wrong data length for record type")),
+                _ => return Err(syntax("unknown record type")),
+            }
+        }
+
+        Ok(image)
+    }
+
+    /// Parse a Motorola S-record file with 16, 24 or 32 bit addresses.
+    pub fn parse_srec(src: &str) -> Result<Self, FirmwareError> {
+        let mut image = Self::default();
+
+        for (line, record) in records(src) {
+            let syntax = |msg| FirmwareError::Syntax { line, msg };
+
+            let record = record
+                .strip_prefix('S')
+                .ok_or(syntax("expected `S` start code"))?;
+            let ty = record.get(..1).ok_or(syntax("missing record type"))?;
+            let bytes = decode_hex(line, &record[1..])?;
+            if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+                return Err(syntax("record length does not match byte count"));
+            }
+
+            let (body, checksum) = bytes.split_at(bytes.len() - 1);
+            let expected = !body.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+            if expected != checksum[0] {
+                return Err(FirmwareError::Checksum {
+                    line,
+                    expected,
+                    found: checksum[0],
+                });
+            }
+
+            let addr_len = match ty {
+                "0" | "1" | "5" | "9" => 2,
+                "2" | "6" | "8" => 3,
+                "3" | "7" => 4,
+                _ => return Err(syntax("unknown record type")),
+            };
+            if body.len() < 1 + addr_len {
+                return Err(syntax("record too short for its address"));
+            }
+            let addr = be_addr(&body[1..1 + addr_len]);
+            let data = &body[1 + addr_len..];
+
+            match ty {
+                "1" | "2" | "3" => image.chunks.push((addr, data.to_vec())),
+                "7" | "8" | "9" => image.entry = Some(addr),
+                // Header and record counts.
+                _ => {}
+            }
+        }
+
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ihex_extended_linear_address() {
+        let src = "\
+            :020000044000BA\n\
+            :0400100013050000D4\n\
+            :0400000540000010A7\n\
+            :00000001FF\n";
+        let image = FirmwareImage::parse_ihex(src).unwrap();
+
+        assert_eq!(
+            vec![(0x4000_0010, vec![0x13, 0x05, 0x00, 0x00])],
+            image.chunks
+        );
+        assert_eq!(Some(0x4000_0010), image.entry);
+        assert_eq!(ImageFormat::IntelHex, ImageFormat::detect(src.as_bytes()));
+    }
+
+    #[test]
+    fn ihex_segment_wraps() {
+        let src = "\
+            :020000021000EC\n\
+            :04FFFE0001020304F5\n\
+            :00000001FF\n";
+        let image = FirmwareImage::parse_ihex(src).unwrap();
+
+        assert_eq!(
+            vec![(0x1_FFFE, vec![0x01, 0x02]), (0x1_0000, vec![0x03, 0x04])],
+            image.chunks
+        );
+    }
+
+    #[test]
+    fn ihex_bad_checksum() {
+        let err = FirmwareImage::parse_ihex(":00000001FE\n").unwrap_err();
+
+        assert!(matches!(
+            err,
+            FirmwareError::Checksum {
+                line: 1,
+                expected: 0xFF,
+                found: 0xFE
+            }
+        ));
+    }
+
+    #[test]
+    fn srec_sparse_image() {
+        let src = "\
+            S00600004844521B\n\
+            S10700080102030AE0\n\
+            S30940000000AABBCCDDA8\n\
+            S70540000000BA\n";
+        let image = FirmwareImage::parse_srec(src).unwrap();
+
+        assert_eq!(
+            vec![
+                (0x8, vec![0x01, 0x02, 0x03, 0x0A]),
+                (0x4000_0000, vec![0xAA, 0xBB, 0xCC, 0xDD]),
+            ],
+            image.chunks
+        );
+        assert_eq!(Some(0x4000_0000), image.entry);
+        assert_eq!(ImageFormat::SRecord, ImageFormat::detect(src.as_bytes()));
+    }
+
+    #[test]
+    fn srec_without_header() {
+        let src = "S205010000AA4F\nS804010000FA\n";
+        let image = FirmwareImage::parse_srec(src).unwrap();
+
+        assert_eq!(vec![(0x1_0000, vec![0xAA])], image.chunks);
+        assert_eq!(ImageFormat::SRecord, ImageFormat::detect(src.as_bytes()));
+    }
+}
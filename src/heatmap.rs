@@ -0,0 +1,106 @@
+use crate::memory::AccessKind;
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Bytes per block when tracking cache lines.
+pub const CACHE_LINE_BITS: u32 = 6;
+/// Bytes per block when tracking pages.
+pub const PAGE_BITS: u32 = 12;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AccessCounts {
+    pub reads: u64,
+    pub writes: u64,
+    pub executes: u64,
+}
+
+impl AccessCounts {
+    pub fn total(&self) -> u64 {
+        self.reads + self.writes + self.executes
+    }
+}
+
+/// Guest access counts per block of `1 << block_bits` bytes.
+#[derive(Debug, Clone)]
+pub struct Heatmap {
+    block_bits: u32,
+    blocks: HashMap<u64, AccessCounts>,
+}
+
+impl Heatmap {
+    pub fn new(block_bits: u32) -> Self {
+        assert!(
+            block_bits < 64,
+            "invalid heatmap block size: {block_bits} bits"
+        );
+
+        Self {
+            block_bits,
+            blocks: HashMap::new(),
+        }
+    }
+
+    pub fn block_size(&self) -> u64 {
+        1 << self.block_bits
+    }
+
+    /// Count an access of `len` bytes at `addr` against every block it touches.
+    pub fn record(&mut self, addr: u64, len: usize, kind: AccessKind) {
+        let first = addr >> self.block_bits;
+        let last = addr.saturating_add(len.max(1) as u64 - 1) >> self.block_bits;
+        for block in first..=last {
+            let counts = self.blocks.entry(block).or_default();
+            match kind {
+                AccessKind::Fetch => counts.executes += 1,
+                AccessKind::Load => counts.reads += 1,
+                AccessKind::Store => counts.writes += 1,
+            }
+        }
+    }
+
+    /// Counts for the block containing `addr`.
+    pub fn counts(&self, addr: u64) -> AccessCounts {
+        self.blocks
+            .get(&(addr >> self.block_bits))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Base address and counts of every block accessed, in ascending order.
+    pub fn blocks(&self) -> Vec<(u64, AccessCounts)> {
+        let mut blocks: Vec<_> = self
+            .blocks
+            .iter()
+            .map(|(block, counts)| (block << self.block_bits, *counts))
+            .collect();
+        blocks.sort_unstable_by_key(|(addr, _)| *addr);
+
+        blocks
+    }
+
+    /// Counts for each block overlapping `range`, in order.
+    pub fn window(&self, range: Range<u64>) -> Vec<AccessCounts> {
+        if range.is_empty() {
+            return Vec::new();
+        }
+
+        let first = range.start >> self.block_bits;
+        let last = (range.end - 1) >> self.block_bits;
+        (first..=last)
+            .map(|block| self.blocks.get(&block).copied().unwrap_or_default())
+            .collect()
+    }
+
+    /// Number of blocks overlapping `range` that were accessed at all.
+    pub fn blocks_touched(&self, range: Range<u64>) -> usize {
+        self.window(range)
+            .iter()
+            .filter(|counts| counts.total() > 0)
+            .count()
+    }
+
+    /// Forget all counts, e.g. at the start of a frame.
+    pub fn reset(&mut self) {
+        self.blocks.clear();
+    }
+}
@@ -0,0 +1,181 @@
+use crate::devices::Htif;
+use crate::emulator::{Emulator, ExitReason};
+use crate::memory::MemoryFault;
+
+/// Devices, in bits 63:56 of a command.
+pub const DEV_SYSCALL: u8 = 0;
+pub const DEV_CONSOLE: u8 = 1;
+/// Console commands, in bits 55:48.
+pub const CONSOLE_GETCHAR: u8 = 0;
+pub const CONSOLE_PUTCHAR: u8 = 1;
+
+const PAYLOAD_MASK: u64 = (1 << 48) - 1;
+
+/// Command or response word with `payload` for `device`.
+pub fn command(device: u8, cmd: u8, payload: u64) -> u64 {
+    (device as u64) << 56 | (cmd as u64) << 48 | payload & PAYLOAD_MASK
+}
+
+/// Carry out the command the guest just wrote to `tohost`, as the front-end server would.
+///
+/// Device 0 exits when bit 0 of the payload is set, with the rest as the exit code, and
+/// otherwise proxies the Linux system call in the eight words the payload points at, the
+/// result replacing the first. Device 1 is the console. Returns the reason to stop if the
+/// guest exited or a proxied read has to wait for input.
+pub(crate) fn handle(emu: &mut Emulator) -> Option<ExitReason> {
+    let tohost = emu.bus_mut().device_mut::<Htif>()?.take_command();
+    let (device, cmd, payload) = (
+        (tohost >> 56) as u8,
+        (tohost >> 48) as u8,
+        tohost & PAYLOAD_MASK,
+    );
+
+    let response = match (device, cmd) {
+        (DEV_SYSCALL, 0) if payload & 1 != 0 => {
+            return Some(ExitReason::Exit((payload >> 1) as i32));
+        }
+        (DEV_SYSCALL, 0) => {
+            let magic = match magic_mem(emu, payload) {
+                Ok(magic) => magic,
+                Err(fault) => return Some(ExitReason::Fault(fault)),
+            };
+            let args = magic[1..7].try_into().unwrap();
+            let ret = match crate::syscalls::dispatch(emu, magic[0], args) {
+                Ok(ret) => ret,
+                Err(reason) => return Some(reason),
+            };
+            if let Err(fault) = emu.host_write(payload, &ret.to_le_bytes()) {
+                return Some(ExitReason::Fault(fault));
+            }
+            1
+        }
+        (DEV_CONSOLE, CONSOLE_PUTCHAR) => {
+            emu.console_mut().push(payload as u8);
+            0x100 | payload & 0xFF
+        }
+        (DEV_CONSOLE, CONSOLE_GETCHAR) => {
+            emu.bus_mut().device_mut::<Htif>()?.pending_read = true;
+            poll(emu);
+            return None;
+        }
+        _ => {
+            eprintln!("invalid htif command: {tohost:#x}");
+            return None;
+        }
+    };
+    emu.bus_mut()
+        .device_mut::<Htif>()?
+        .set_fromhost(command(device, cmd, response));
+
+    None
+}
+
+/// The eight words of a proxied system call at `addr`, its number and then the arguments.
+fn magic_mem(emu: &Emulator, addr: u64) -> Result<[u64; 8], MemoryFault> {
+    let bytes = emu.memory(addr, 8 * 8)?;
+    Ok(std::array::from_fn(|i| {
+        u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap())
+    }))
+}
+
+/// Answer a pending console read once there is input and the guest has taken the last
+/// response from `fromhost`.
+pub(crate) fn poll(emu: &mut Emulator) {
+    let ready = emu
+        .bus()
+        .device::<Htif>()
+        .is_some_and(|htif| htif.pending_read && htif.fromhost() == 0);
+    if !ready {
+        return;
+    }
+    let Some(&byte) = emu.take_input(1).first() else {
+        return;
+    };
+
+    let htif = emu.bus_mut().device_mut::<Htif>().unwrap();
+    htif.pending_read = false;
+    htif.set_fromhost(command(DEV_CONSOLE, CONSOLE_GETCHAR, 0x100 | byte as u64));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::*;
+    use crate::symbols::{Symbol, Symbolizer};
+    use crate::testing::machine;
+
+    const TOHOST: u64 = 0x4000_1000;
+
+    /// `sd t0, 0(t1)`, storing to `tohost`.
+    const STORE: [u8; 4] = [0x23, 0x30, 0x53, 0x00];
+
+    /// Give `emu` an HTIF with `tohost` and `fromhost` 64 bytes apart, as in riscv-tests.
+    fn attach(emu: &mut Emulator) {
+        let symbol = |name: &str, addr| Symbol {
+            name: name.into(),
+            addr,
+            size: 8,
+        };
+        emu.set_symbols(Some(Symbolizer::new(vec![
+            symbol("tohost", TOHOST),
+            symbol("fromhost", TOHOST + 0x40),
+        ])));
+        emu.attach_htif();
+        emu.set(Reg::T(1), TOHOST);
+    }
+
+    fn send(emu: &mut Emulator, tohost: u64) -> ExitReason {
+        emu.set(Reg::T(0), tohost);
+        emu.set_pc(0x4000_0000);
+        emu.run_for(1)
+    }
+
+    fn fromhost(emu: &Emulator) -> u64 {
+        emu.bus().device::<Htif>().unwrap().fromhost()
+    }
+
+    #[test]
+    fn console_and_exit() {
+        let (mut emu, _) = machine(&STORE);
+        attach(&mut emu);
+        assert_eq!(
+            Some(TOHOST..TOHOST + 0x48),
+            emu.bus().device_range::<Htif>()
+        );
+
+        send(&mut emu, command(DEV_CONSOLE, CONSOLE_PUTCHAR, b'A' as u64));
+        assert_eq!(b"A", emu.console());
+        assert_eq!(command(DEV_CONSOLE, CONSOLE_PUTCHAR, 0x141), fromhost(&emu));
+
+        emu.bus_mut().device_mut::<Htif>().unwrap().set_fromhost(0);
+        send(&mut emu, command(DEV_CONSOLE, CONSOLE_GETCHAR, 0));
+        assert_eq!(0, fromhost(&emu));
+        emu.push_input(b"x");
+        emu.tick();
+        assert_eq!(command(DEV_CONSOLE, CONSOLE_GETCHAR, 0x178), fromhost(&emu));
+
+        assert_eq!(ExitReason::Exit(3), send(&mut emu, 3 << 1 | 1));
+    }
+
+    #[test]
+    fn proxies_system_calls() {
+        let (mut emu, _) = machine(&STORE);
+        attach(&mut emu);
+        let (magic, buf) = (0x4000_2000, 0x4000_3000);
+        emu.host_write(buf, b"ok").unwrap();
+        let words: Vec<u8> = [64, 1, buf, 2, 0, 0, 0, 0]
+            .iter()
+            .flat_map(|word: &u64| word.to_le_bytes())
+            .collect();
+        emu.host_write(magic, &words).unwrap();
+
+        assert_eq!(ExitReason::BudgetExhausted, send(&mut emu, magic));
+        assert_eq!(b"ok", emu.console());
+        assert_eq!(&2u64.to_le_bytes(), &emu.memory(magic, 8).unwrap()[..]);
+        assert_eq!(1, fromhost(&emu));
+        assert_eq!(
+            0,
+            emu.bus_mut().device_mut::<Htif>().unwrap().take_command()
+        );
+    }
+}
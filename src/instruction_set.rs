@@ -40,6 +40,7 @@ pub enum Instr {
     Bltu(Reg, Reg, Imm),
     Bgeu(Reg, Reg, Imm),
     Ecall,
+    Ebreak,
 
     Lw(Reg, Offset),
     Lwu(Reg, Offset),
@@ -56,10 +57,58 @@ pub enum Instr {
     Addw(Reg, Reg, Reg),
 
     Mul(Reg, Reg, Reg),
+    Mulh(Reg, Reg, Reg),
+    Mulhsu(Reg, Reg, Reg),
+    Mulhu(Reg, Reg, Reg),
     Div(Reg, Reg, Reg),
+    Divu(Reg, Reg, Reg),
     Rem(Reg, Reg, Reg),
+    Remu(Reg, Reg, Reg),
 
     Mulw(Reg, Reg, Reg),
     Divw(Reg, Reg, Reg),
+    Divuw(Reg, Reg, Reg),
     Remw(Reg, Reg, Reg),
+    Remuw(Reg, Reg, Reg),
+
+    /// `csrr`, reading one of the counters user mode can see.
+    Csrr(Reg, Counter),
+
+    /// `fence` and `fence.i`, nothing to order or flush with one hart and no caches.
+    Fence,
+
+    /// `lr` and `sc`, address in the second register, the `aq` and `rl` bits are ignored.
+    LrW(Reg, Reg),
+    LrD(Reg, Reg),
+    ScW(Reg, Reg, Reg),
+    ScD(Reg, Reg, Reg),
+    /// Atomic read-modify-write of the word or doubleword at the address in the second
+    /// register with the third, the old value goes to the first.
+    AmoW(AmoOp, Reg, Reg, Reg),
+    AmoD(AmoOp, Reg, Reg, Reg),
+
+    /// An encoding that is not implemented.
+    Illegal(u32),
+}
+
+/// Operation of an AMO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmoOp {
+    Swap,
+    Add,
+    Xor,
+    And,
+    Or,
+    Min,
+    Max,
+    Minu,
+    Maxu,
+}
+
+/// Read-only counter CSRs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Counter {
+    Cycle = 0xC00,
+    Time = 0xC01,
+    Instret = 0xC02,
 }
@@ -1,7 +1,9 @@
+use std::ops::Range;
 use std::time::Duration;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 
+use crate::devices::SCREEN_SIZE;
 use crate::emulator;
 use crate::emulator::*;
 use crate::instruction_set::*;
@@ -9,7 +11,10 @@ use crate::primitives::*;
 use bevy::window::PresentMode;
 use bevy::window::WindowResolution;
 use bevy::{
-    input::{keyboard::KeyboardInput, ButtonState},
+    input::{
+        keyboard::{Key, KeyboardInput},
+        ButtonState,
+    },
     prelude::*,
     render::{
         render_asset::RenderAssetUsages,
@@ -43,16 +48,19 @@ pub fn start(emulator: Emulator) {
         ))
         .insert_resource(Emu(emulator))
         .insert_resource(Profiler::default())
+        .insert_resource(InputLine::default())
         .add_systems(Startup, startup)
-        .add_systems(Update, (close_on_escape, update_buttons, exit).chain())
+        .add_systems(Update, (close_on_escape, update_keyboard, exit).chain())
         .add_systems(
             PostUpdate,
             (
                 display_registers,
                 display_instructions.run_if(resource_exists::<StepTimer>),
                 display_console,
+                display_input_line,
                 display_screen,
                 display_buttons,
+                display_heatmap.run_if(resource_exists::<HeatmapOverlay>),
             ),
         )
         .add_systems(FixedUpdate, (update_tick, step).chain())
@@ -62,7 +70,10 @@ pub fn start(emulator: Emulator) {
 }
 
 fn update_tick(mut emulator: ResMut<Emu>) {
-    emulator.0.tick();
+    // A virtual clock ticks as the guest retires instructions instead.
+    if !emulator.0.clock().is_virtual() {
+        emulator.0.tick();
+    }
 }
 
 #[derive(Resource)]
@@ -127,7 +138,7 @@ fn startup(
                             ..default()
                         },
                     ),
-                    Instruction(Instr::Ecall),
+                    Instruction(None),
                 ));
             }
         });
@@ -172,12 +183,30 @@ fn startup(
         .with_style(Style {
             position_type: PositionType::Absolute,
             left: Val::Percent(30.),
-            bottom: Val::Percent(0.),
+            bottom: Val::Percent(4.),
             ..Default::default()
         }),
         Console,
     ));
 
+    // Input line, under the console
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: (window.resolution.physical_height() / 32) as f32,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            left: Val::Percent(30.),
+            bottom: Val::Percent(0.),
+            ..Default::default()
+        }),
+        InputLineText,
+    ));
+
     // Screen
     let im = Image::new(
         Extent3d {
@@ -199,6 +228,31 @@ fn startup(
             ..Default::default()
         },
     ));
+
+    // Heatmap, above the screen
+    if let Some(window) = heatmap_window(&emulator.0) {
+        let data = heatmap_to_texture_data(&emulator.0, window.clone());
+        let height = (data.len() / 4) as u32 / HEATMAP_WIDTH;
+        let im = Image::new(
+            Extent3d {
+                width: HEATMAP_WIDTH,
+                height,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            data,
+            TextureFormat::Rgba8Unorm,
+            RenderAssetUsages::all(),
+        );
+        let texture = textures.add(im);
+        commands.spawn(SpriteBundle {
+            texture: texture.clone(),
+            transform: Transform::from_xyz(0., 310. + height as f32 * 3., 1.)
+                .with_scale(Vec3::splat(6.)),
+            ..Default::default()
+        });
+        commands.insert_resource(HeatmapOverlay { texture, window });
+    }
 }
 
 #[derive(Resource)]
@@ -242,12 +296,9 @@ fn step(
         //     .duration_since(prof.bevy)
         //     .unwrap_or_default();
         // let start = SystemTime::now();
-        while !emulator.0.should_render() {
-            emulator.0.run_next();
-            if emulator.0.finished() {
-                writer.send(AppExit::Success);
-                break;
-            }
+        emulator.0.run();
+        if emulator.0.finished() {
+            writer.send(AppExit::Success);
         }
         // prof.frame = SystemTime::now().duration_since(start).unwrap();
         // prof.bevy = SystemTime::now();
@@ -283,8 +334,9 @@ fn display_registers(
     }
 }
 
+/// An executed instruction and its address.
 #[derive(Component)]
-struct Instruction(Instr);
+struct Instruction(Option<(u64, Instr)>);
 
 fn display_instructions(
     mut instrs: Query<(&mut Text, &mut Instruction)>,
@@ -295,14 +347,17 @@ fn display_instructions(
     if timer.0.just_finished() {
         let window = window.single();
 
-        let mut tmp1 = *emulator.0.current_instruction();
+        let mut tmp1 = Some((emulator.0.current_pc(), *emulator.0.current_instruction()));
         let mut tmp2;
         for (mut text, mut instr) in instrs.iter_mut() {
             text.sections[0].style.font_size = (window.resolution.physical_height() / 32) as f32;
             tmp2 = instr.0;
             instr.0 = tmp1;
             tmp1 = tmp2;
-            text.sections[0].value = format!("{:?}", instr.0,);
+            text.sections[0].value = match instr.0 {
+                Some((pc, instr)) => format!("{}: {:?}", emulator.0.describe(pc), instr),
+                None => String::new(),
+            };
         }
     }
 }
@@ -310,24 +365,13 @@ fn display_instructions(
 #[derive(Component)]
 struct Button(crate::io::Button);
 
-fn update_buttons(mut reader: EventReader<KeyboardInput>, mut emulator: ResMut<Emu>) {
-    for input in reader.read() {
-        match input.state {
-            ButtonState::Pressed => match input.key_code {
-                KeyCode::KeyW => emulator.0.press_button(crate::io::Button::Zero),
-                KeyCode::KeyA => emulator.0.press_button(crate::io::Button::One),
-                KeyCode::KeyS => emulator.0.press_button(crate::io::Button::Two),
-                KeyCode::KeyD => emulator.0.press_button(crate::io::Button::Three),
-                _ => {}
-            },
-            ButtonState::Released => match input.key_code {
-                KeyCode::KeyW => emulator.0.release_button(crate::io::Button::Zero),
-                KeyCode::KeyA => emulator.0.release_button(crate::io::Button::One),
-                KeyCode::KeyS => emulator.0.release_button(crate::io::Button::Two),
-                KeyCode::KeyD => emulator.0.release_button(crate::io::Button::Three),
-                _ => {}
-            },
-        }
+fn button(key: KeyCode) -> Option<crate::io::Button> {
+    match key {
+        KeyCode::KeyW => Some(crate::io::Button::Zero),
+        KeyCode::KeyA => Some(crate::io::Button::One),
+        KeyCode::KeyS => Some(crate::io::Button::Two),
+        KeyCode::KeyD => Some(crate::io::Button::Three),
+        _ => None,
     }
 }
 
@@ -355,6 +399,71 @@ fn display_console(mut console: Query<&mut Text, With<Console>>, emulator: Res<E
     console.sections[0].value = String::from_utf8_lossy(emulator.0.console()).into();
 }
 
+/// Text typed for the guest's standard input, sent a line at a time.
+///
+/// Tab moves the keyboard between the input line and the buttons.
+#[derive(Resource, Default)]
+struct InputLine {
+    text: String,
+    focused: bool,
+}
+
+#[derive(Component)]
+struct InputLineText;
+
+/// Keys go to the input line or the buttons, whichever has focus when they are pressed.
+fn update_keyboard(
+    mut reader: EventReader<KeyboardInput>,
+    mut line: ResMut<InputLine>,
+    mut emulator: ResMut<Emu>,
+) {
+    for input in reader.read() {
+        if input.state == ButtonState::Released {
+            // Buttons held while switching to the input line still come up.
+            if let Some(button) = button(input.key_code) {
+                emulator.0.release_button(button);
+            }
+            continue;
+        }
+        if input.logical_key == Key::Tab {
+            line.focused = !line.focused;
+            continue;
+        }
+        if !line.focused {
+            if let Some(button) = button(input.key_code) {
+                emulator.0.press_button(button);
+            }
+            continue;
+        }
+
+        match &input.logical_key {
+            Key::Character(text) => line.text.push_str(text),
+            Key::Space => line.text.push(' '),
+            Key::Backspace => {
+                line.text.pop();
+            }
+            Key::Enter => {
+                line.text.push('\n');
+                emulator.0.push_input(line.text.as_bytes());
+                line.text.clear();
+            }
+            _ => {}
+        }
+    }
+}
+
+fn display_input_line(mut text: Query<&mut Text, With<InputLineText>>, line: Res<InputLine>) {
+    let mut text = text.single_mut();
+    let section = &mut text.sections[0];
+    if line.focused {
+        section.value = format!("> {}_", line.text);
+        section.style.color = Color::xyz(1., 1., 1.);
+    } else {
+        section.value = format!("> {} (tab to type)", line.text);
+        section.style.color = Color::xyz(0.2, 0.2, 0.2);
+    }
+}
+
 #[derive(Component)]
 struct Screen(Handle<Image>);
 
@@ -365,9 +474,15 @@ fn display_screen(screen: Query<&Screen>, mut images: ResMut<Assets<Image>>, emu
 }
 
 fn emu_screen_to_texture_data(emulator: &Emu) -> Vec<u8> {
-    emulator
+    let blank = [0; SCREEN_SIZE / 8];
+    let framebuffer = emulator
         .0
-        .memory(SCREEN_OFFSET, SCREEN_SIZE / 8)
+        .bus()
+        .device::<crate::devices::Screen>()
+        .map(|screen| screen.framebuffer())
+        .unwrap_or(&blank);
+
+    framebuffer
         .iter()
         .map(|byte| {
             let mut bit_field = [0; 8];
@@ -399,6 +514,64 @@ fn emu_screen_to_texture_data(emulator: &Emu) -> Vec<u8> {
         .collect()
 }
 
+/// Cells per row of the heatmap overlay.
+const HEATMAP_WIDTH: u32 = 64;
+
+#[derive(Resource)]
+struct HeatmapOverlay {
+    texture: Handle<Image>,
+    window: Range<u64>,
+}
+
+/// Memory shown by the heatmap overlay, from the heap through the screen buffer.
+fn heatmap_window(emulator: &Emulator) -> Option<Range<u64>> {
+    emulator.heatmap()?;
+    let heap = emulator.guest_memory().region("heap")?.range.clone();
+    let screen = emulator.bus().device_range::<crate::devices::Screen>()?;
+
+    Some(heap.start.min(screen.start)..heap.end.max(screen.end))
+}
+
+/// Shows the accesses of the last frame, then starts counting the next one.
+fn display_heatmap(
+    overlay: Res<HeatmapOverlay>,
+    mut images: ResMut<Assets<Image>>,
+    mut emulator: ResMut<Emu>,
+) {
+    let image = images.get_mut(&overlay.texture).unwrap();
+    image.data = heatmap_to_texture_data(&emulator.0, overlay.window.clone());
+
+    if let Some(heatmap) = emulator.0.heatmap_mut() {
+        heatmap.reset();
+    }
+}
+
+/// One pixel per block: red for writes, green for reads and blue for executes.
+fn heatmap_to_texture_data(emulator: &Emulator, window: Range<u64>) -> Vec<u8> {
+    let Some(heatmap) = emulator.heatmap() else {
+        return Vec::new();
+    };
+
+    let mut blocks = heatmap.window(window);
+    let rows = blocks.len().div_ceil(HEATMAP_WIDTH as usize);
+    blocks.resize(rows * HEATMAP_WIDTH as usize, Default::default());
+
+    // Log scale so a handful of accesses still shows up next to hot loops.
+    let intensity = |count: u64| ((64 - count.leading_zeros()) * 24).min(0xFF) as u8;
+
+    blocks
+        .iter()
+        .flat_map(|counts| {
+            [
+                intensity(counts.writes),
+                intensity(counts.reads),
+                intensity(counts.executes),
+                if counts.total() > 0 { 0xFF } else { 0x40 },
+            ]
+        })
+        .collect()
+}
+
 fn close_on_escape(mut reader: EventReader<KeyboardInput>, mut writer: EventWriter<AppExit>) {
     for input in reader.read() {
         if input.key_code == KeyCode::Escape && input.state == ButtonState::Pressed {
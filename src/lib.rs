@@ -1,6 +1,28 @@
+pub mod boot;
+pub mod bus;
+pub mod clock;
 mod decoding;
+pub mod devices;
+pub mod elf;
 pub mod emulator;
+pub mod fdt;
+pub mod firmware;
+pub mod heatmap;
+pub mod htif;
 mod instruction_set;
 mod interface;
 mod io;
+pub mod machine;
+pub mod memcheck;
+pub mod memory;
 mod primitives;
+pub mod process;
+pub mod sbi;
+pub mod semihosting;
+pub mod signals;
+pub mod strace;
+pub mod symbols;
+pub mod syscalls;
+#[cfg(test)]
+mod testing;
+pub mod threads;
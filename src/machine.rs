@@ -0,0 +1,510 @@
+use crate::bus::Bus;
+use crate::devices::*;
+use crate::emulator::Emulator;
+use crate::fdt::FdtWriter;
+use crate::memory::{Memory, Perms, Region, RegionKind};
+use serde::{Deserialize, Deserializer};
+use std::fmt::{self, Write};
+use std::ops::Range;
+use std::path::Path;
+
+/// Extensions the decoder understands, after the `rv64` base.
+const SUPPORTED_EXTENSIONS: &str = "ima";
+
+/// Machine layout, loaded from a TOML description such as `machine.toml`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MachineConfig {
+    pub isa: String,
+    pub harts: u32,
+    pub phys_addr_bits: u32,
+    pub reset_pc: u64,
+    #[serde(default, rename = "region")]
+    pub regions: Vec<RegionConfig>,
+    #[serde(default, rename = "device")]
+    pub devices: Vec<DeviceConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegionConfig {
+    pub name: String,
+    pub base: u64,
+    pub size: u64,
+    #[serde(default = "default_region_kind", deserialize_with = "region_kind")]
+    pub kind: RegionKind,
+    /// Defaults to `rwx` for RAM and `rx` for ROM.
+    #[serde(default, deserialize_with = "perms")]
+    pub perms: Option<Perms>,
+}
+
+impl RegionConfig {
+    pub fn perms(&self) -> Perms {
+        self.perms.unwrap_or(match self.kind {
+            RegionKind::Ram => Perms::RWX,
+            RegionKind::Rom => Perms::RX,
+        })
+    }
+}
+
+fn default_region_kind() -> RegionKind {
+    RegionKind::Ram
+}
+
+fn region_kind<'de, D: Deserializer<'de>>(deserializer: D) -> Result<RegionKind, D::Error> {
+    match String::deserialize(deserializer)?.as_str() {
+        "ram" => Ok(RegionKind::Ram),
+        "rom" => Ok(RegionKind::Rom),
+        kind => Err(serde::de::Error::custom(format!(
+            "unknown region kind `{kind}`, expected `ram` or `rom`"
+        ))),
+    }
+}
+
+fn perms<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Perms>, D::Error> {
+    String::deserialize(deserializer)?
+        .parse()
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub name: String,
+    pub kind: DeviceKind,
+    pub base: u64,
+    /// Interrupt line, routed through the PLIC.
+    #[serde(default)]
+    pub irq: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceKind {
+    NullGuard,
+    Exit,
+    Render,
+    Console,
+    Screen,
+    Buttons,
+    Tick,
+    Clint,
+    Plic,
+    /// `tohost` at the base and `fromhost` at [`Htif::FROMHOST`], for programs without the
+    /// symbols.
+    Htif,
+}
+
+impl DeviceKind {
+    /// Size of the device's register window in bytes.
+    pub fn size(&self) -> u64 {
+        match self {
+            Self::NullGuard | Self::Exit => 1,
+            Self::Render => 2,
+            Self::Console | Self::Buttons | Self::Tick => 4,
+            Self::Screen => SCREEN_SIZE as u64 / 8,
+            Self::Clint => Clint::SIZE,
+            Self::Plic => Plic::SIZE,
+            Self::Htif => Htif::SIZE,
+        }
+    }
+
+    /// Device tree `compatible` string.
+    pub fn compatible(&self) -> &'static str {
+        match self {
+            Self::NullGuard => "emu,null-guard",
+            Self::Exit => "emu,exit",
+            Self::Render => "emu,render",
+            Self::Console => "emu,console",
+            Self::Screen => "emu,screen",
+            Self::Buttons => "emu,buttons",
+            Self::Tick => "emu,tick",
+            Self::Clint => "sifive,clint0",
+            Self::Plic => "sifive,plic-1.0.0",
+            Self::Htif => "ucb,htif0",
+        }
+    }
+
+    fn attach(&self, bus: &mut Bus, base: u64, irq: Option<u32>, harts: usize) {
+        let size = self.size();
+        match self {
+            Self::NullGuard => bus.attach(base, size, irq, NullGuard),
+            Self::Exit => bus.attach(base, size, irq, ExitPort),
+            Self::Render => bus.attach(base, size, irq, RenderPort),
+            Self::Console => bus.attach(base, size, irq, ConsolePort),
+            Self::Screen => bus.attach(base, size, irq, Screen::default()),
+            Self::Buttons => bus.attach(base, size, irq, Buttons::default()),
+            Self::Tick => bus.attach(base, size, irq, Tick::default()),
+            Self::Clint => bus.attach(base, size, irq, Clint::new(harts)),
+            Self::Plic => bus.attach(base, size, irq, Plic::new(harts)),
+            Self::Htif => bus.attach(base, size, irq, Htif::new(Htif::FROMHOST)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "failed to read machine config: {err}"),
+            Self::Parse(err) => write!(f, "failed to parse machine config: {err}"),
+            Self::Invalid(msg) => write!(f, "invalid machine config: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Default for MachineConfig {
+    /// The machine described by `machine.toml`.
+    fn default() -> Self {
+        Self::parse(include_str!("../machine.toml")).expect("machine.toml is valid")
+    }
+}
+
+impl MachineConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let src = std::fs::read_to_string(path).map_err(ConfigError::Io)?;
+        Self::parse(&src)
+    }
+
+    pub fn parse(src: &str) -> Result<Self, ConfigError> {
+        let config: Self = toml::from_str(src).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: String| Err(ConfigError::Invalid(msg));
+
+        match self.isa.strip_prefix("rv64") {
+            Some(ext) if ext.starts_with('i') => {
+                if let Some(c) = ext.chars().find(|c| !SUPPORTED_EXTENSIONS.contains(*c)) {
+                    return invalid(format!("unsupported ISA extension `{c}`"));
+                }
+            }
+            _ => return invalid(format!("ISA must be rv64i based, found `{}`", self.isa)),
+        }
+
+        if self.harts != 1 {
+            return invalid(format!(
+                "{} harts requested, only 1 is supported",
+                self.harts
+            ));
+        }
+
+        if !(12..=64).contains(&self.phys_addr_bits) {
+            return invalid(format!(
+                "physical address width of {} bits",
+                self.phys_addr_bits
+            ));
+        }
+
+        for region in self.regions.iter() {
+            if region.kind == RegionKind::Rom && region.perms().write {
+                return invalid(format!("ROM region `{}` is writable", region.name));
+            }
+        }
+
+        for device in self.devices.iter() {
+            if let Some(irq) = device.irq {
+                if irq == 0 || irq as usize >= Plic::SOURCES {
+                    return invalid(format!("`{}` has invalid interrupt {irq}", device.name));
+                }
+            }
+        }
+
+        let mut ranges: Vec<(&str, u64, u64)> = self
+            .regions
+            .iter()
+            .map(|region| (region.name.as_str(), region.base, region.size))
+            .chain(
+                self.devices
+                    .iter()
+                    .map(|dev| (dev.name.as_str(), dev.base, dev.kind.size())),
+            )
+            .collect();
+        ranges.sort_by_key(|(_, base, _)| *base);
+
+        let limit = 1u128 << self.phys_addr_bits;
+        for (name, base, size) in ranges.iter() {
+            if *base as u128 + *size as u128 > limit {
+                return invalid(format!("`{name}` lies outside the physical address space"));
+            }
+        }
+        for pair in ranges.windows(2) {
+            let (a, a_base, a_size) = pair[0];
+            let (b, b_base, _) = pair[1];
+            if a_base + a_size > b_base {
+                return invalid(format!("`{a}` overlaps `{b}`"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Memory region called `name`.
+    pub fn region(&self, name: &str) -> Option<&RegionConfig> {
+        self.regions.iter().find(|region| region.name == name)
+    }
+
+    /// Build an emulator with this memory map, with pc at the reset vector.
+    pub fn build(&self) -> Result<Emulator, ConfigError> {
+        self.validate()?;
+
+        let regions = self
+            .regions
+            .iter()
+            .map(|region| Region {
+                name: region.name.clone(),
+                range: region.base..region.base + region.size,
+                kind: region.kind,
+                perms: region.perms(),
+            })
+            .collect();
+
+        let mut bus = Bus::new(Memory::with_regions(self.phys_addr_bits, regions));
+        for device in self.devices.iter() {
+            device
+                .kind
+                .attach(&mut bus, device.base, device.irq, self.harts as usize);
+        }
+
+        let mut emulator = Emulator::with_bus(bus);
+        emulator.set_pc(self.reset_pc);
+        Ok(emulator)
+    }
+
+    /// C header with the address and size of every region and device.
+    pub fn c_header(&self) -> String {
+        let mut header = String::new();
+        writeln!(header, "// Generated from machine.toml, do not edit.").unwrap();
+        writeln!(header, "#pragma once\n").unwrap();
+        writeln!(header, "#define RESET_PC {:#x}", self.reset_pc).unwrap();
+
+        for region in self.regions.iter() {
+            let name = region.name.to_uppercase();
+            writeln!(header, "\n#define {name}_OFFSET {:#x}", region.base).unwrap();
+            writeln!(header, "#define {name}_SIZE {:#x}", region.size).unwrap();
+        }
+
+        for device in self.devices.iter() {
+            let name = device.name.to_uppercase();
+            writeln!(header, "\n#define {name}_OFFSET {:#x}", device.base).unwrap();
+            writeln!(header, "#define {name}_SIZE {:#x}", device.kind.size()).unwrap();
+        }
+
+        header
+    }
+
+    /// Flattened device tree describing the RAM regions, harts, CLINT, PLIC and every
+    /// other device, with `initrd` in `/chosen` for a Linux boot.
+    pub fn device_tree(&self, initrd: Option<Range<u64>>) -> Vec<u8> {
+        // Phandles, each hart's interrupt controller then the PLIC.
+        let intc = |hart: u32| hart + 1;
+        let plic = self.harts + 1;
+
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        fdt.property_str("compatible", "emu,machine");
+        fdt.property_str("model", "emu");
+
+        fdt.begin_node("chosen");
+        if let Some(initrd) = initrd {
+            fdt.property_u64s("linux,initrd-start", &[initrd.start]);
+            fdt.property_u64s("linux,initrd-end", &[initrd.end]);
+        }
+        fdt.end_node();
+
+        for region in self.regions.iter() {
+            if region.kind == RegionKind::Ram {
+                fdt.begin_node(&format!("memory@{:x}", region.base));
+                fdt.property_str("device_type", "memory");
+                fdt.property_u64s("reg", &[region.base, region.size]);
+                fdt.end_node();
+            }
+        }
+
+        fdt.begin_node("cpus");
+        fdt.property_u32("#address-cells", 1);
+        fdt.property_u32("#size-cells", 0);
+        fdt.property_u32("timebase-frequency", TICK_HZ as u32);
+        let extensions: Vec<String> = self.isa[4..].chars().map(String::from).collect();
+        let extensions: Vec<&str> = extensions.iter().map(String::as_str).collect();
+        for hart in 0..self.harts {
+            fdt.begin_node(&format!("cpu@{hart}"));
+            fdt.property_str("device_type", "cpu");
+            fdt.property_u32("reg", hart);
+            fdt.property_str("status", "okay");
+            fdt.property_str("compatible", "riscv");
+            fdt.property_str("riscv,isa", &self.isa);
+            fdt.property_str("riscv,isa-base", "rv64i");
+            fdt.property_strs("riscv,isa-extensions", &extensions);
+            fdt.property_str("mmu-type", "riscv,none");
+
+            fdt.begin_node("interrupt-controller");
+            fdt.property_u32("#interrupt-cells", 1);
+            fdt.property_empty("interrupt-controller");
+            fdt.property_str("compatible", "riscv,cpu-intc");
+            fdt.property_u32("phandle", intc(hart));
+            fdt.end_node();
+
+            fdt.end_node();
+        }
+        fdt.end_node();
+
+        fdt.begin_node("soc");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        fdt.property_str("compatible", "simple-bus");
+        fdt.property_empty("ranges");
+        for device in self.devices.iter() {
+            let name = match device.kind {
+                DeviceKind::Clint => "clint",
+                DeviceKind::Plic => "plic",
+                _ => &device.name,
+            };
+            fdt.begin_node(&format!("{name}@{:x}", device.base));
+            fdt.property_u64s("reg", &[device.base, device.kind.size()]);
+            // Machine and supervisor lines of every hart, software and timer for the
+            // CLINT, external for the PLIC.
+            let lines = |m: u32, s: u32| -> Vec<u32> {
+                (0..self.harts)
+                    .flat_map(|hart| [intc(hart), m, intc(hart), s])
+                    .collect()
+            };
+            match device.kind {
+                DeviceKind::Clint => {
+                    fdt.property_strs("compatible", &[device.kind.compatible(), "riscv,clint0"]);
+                    fdt.property_cells("interrupts-extended", &lines(3, 7));
+                }
+                DeviceKind::Plic => {
+                    fdt.property_strs("compatible", &[device.kind.compatible(), "riscv,plic0"]);
+                    fdt.property_u32("#address-cells", 0);
+                    fdt.property_u32("#interrupt-cells", 1);
+                    fdt.property_empty("interrupt-controller");
+                    fdt.property_u32("riscv,ndev", Plic::SOURCES as u32 - 1);
+                    fdt.property_cells("interrupts-extended", &lines(11, 9));
+                    fdt.property_u32("phandle", plic);
+                }
+                kind => fdt.property_str("compatible", kind.compatible()),
+            }
+            if let Some(irq) = device.irq {
+                fdt.property_u32("interrupt-parent", plic);
+                fdt.property_u32("interrupts", irq);
+            }
+            fdt.end_node();
+        }
+        fdt.end_node();
+
+        fdt.end_node();
+        fdt.finish(0)
+    }
+
+    /// Linker script `MEMORY` block with one entry per memory region.
+    pub fn linker_memory(&self) -> String {
+        let mut script = String::new();
+        writeln!(script, "/* Generated from machine.toml, do not edit. */").unwrap();
+        writeln!(script, "MEMORY\n{{").unwrap();
+        for region in self.regions.iter() {
+            writeln!(
+                script,
+                "  {} ({}) : ORIGIN = {:#x}, LENGTH = {:#x}",
+                region.name.to_uppercase(),
+                region.perms(),
+                region.base,
+                region.size
+            )
+            .unwrap();
+        }
+        writeln!(script, "}}").unwrap();
+
+        script
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_machine_matches_guest_headers() {
+        let config = MachineConfig::default();
+
+        assert_eq!(include_str!("../emu/machine.h"), config.c_header());
+        assert_eq!(include_str!("../emu/memory.ld"), config.linker_memory());
+    }
+
+    #[test]
+    fn rejects_overlapping_regions() {
+        let src = r#"
+            isa = "rv64im"
+            harts = 1
+            phys_addr_bits = 32
+            reset_pc = 0x1000
+
+            [[region]]
+            name = "ram"
+            base = 0x0
+            size = 0x2000
+
+            [[device]]
+            name = "console"
+            kind = "console"
+            base = 0x1ffe
+        "#;
+
+        assert!(matches!(
+            MachineConfig::parse(src),
+            Err(ConfigError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn device_tree_describes_machine() {
+        let dtb = MachineConfig::default().device_tree(Some(0x4400_0000..0x4400_1000));
+        let contains = |needle: &[u8]| dtb.windows(needle.len()).any(|w| w == needle);
+
+        assert_eq!(0xd00d_feed_u32.to_be_bytes(), dtb[..4]);
+        assert!(contains(b"memory@40000000\0"));
+        assert!(contains(b"cpu@0\0"));
+        assert!(contains(b"rv64ima\0"));
+        assert!(contains(b"clint@2000000\0"));
+        assert!(contains(b"plic@c000000\0"));
+        assert!(contains(b"console@4\0"));
+        assert!(contains(b"emu,console\0"));
+        assert!(contains(b"linux,initrd-start\0"));
+        assert!(contains(&[0, 0, 0, 0, 0x44, 0, 0x10, 0]));
+    }
+
+    #[test]
+    fn rejects_writable_rom() {
+        let src = r#"
+            isa = "rv64im"
+            harts = 1
+            phys_addr_bits = 32
+            reset_pc = 0x1000
+
+            [[region]]
+            name = "boot"
+            kind = "rom"
+            perms = "rwx"
+            base = 0x1000
+            size = 0x1000
+        "#;
+
+        assert!(matches!(
+            MachineConfig::parse(src),
+            Err(ConfigError::Invalid(_))
+        ));
+    }
+}
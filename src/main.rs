@@ -1,6 +1,64 @@
+use emu::emulator::RunOptions;
+use emu::machine::MachineConfig;
 use std::process::Command;
 
 fn main() {
+    // emu [--memcheck] [--heatmap] [--trace] [--strace] [--strace-file file] [--seed n]
+    //     [--headless] [--env KEY=VALUE]... [--root dir] [--dtb file] [--initrd file]
+    //     [program [args...]]
+    // Everything after the program, or after `--` when compiling the guest, is its argv.
+    let mut options = RunOptions::default();
+    let mut program = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--memcheck" => options.memcheck = true,
+            "--heatmap" => options.heatmap = true,
+            "--trace" => options.trace = true,
+            "--strace" => options.strace = true,
+            "--strace-file" => {
+                options.strace_file = Some(args.next().expect("--strace-file takes a file").into())
+            }
+            "--seed" => {
+                let seed = args.next().expect("--seed takes a number");
+                options.seed = Some(seed.parse().unwrap_or_else(|err| panic!("{seed}: {err}")));
+            }
+            "--headless" => options.headless = true,
+            "--env" => options
+                .env
+                .push(args.next().expect("--env takes KEY=VALUE")),
+            "--root" => options.root = Some(args.next().expect("--root takes a directory").into()),
+            "--dtb" | "--initrd" => {
+                let path = args.next().unwrap_or_else(|| panic!("{arg} takes a file"));
+                let data = std::fs::read(&path).unwrap_or_else(|err| panic!("{path}: {err}"));
+                match arg.as_str() {
+                    "--dtb" => options.dtb = Some(data),
+                    _ => options.initrd = Some(data),
+                }
+            }
+            "--" => break,
+            _ => {
+                program = Some(arg);
+                break;
+            }
+        }
+    }
+    options
+        .args
+        .push(program.clone().unwrap_or_else(|| "emu".into()));
+    options.args.extend(args);
+    let config = MachineConfig::load("./machine.toml").unwrap_or_else(|err| panic!("{err}"));
+
+    // Run a prebuilt ELF or raw binary instead of compiling the guest.
+    if let Some(path) = program {
+        let prgm = std::fs::read(&path).unwrap_or_else(|err| panic!("{path}: {err}"));
+        emu::emulator::run_emulator(&prgm, &config, &options);
+        return;
+    }
+
+    std::fs::write("./emu/machine.h", config.c_header()).unwrap();
+    std::fs::write("./emu/memory.ld", config.linker_memory()).unwrap();
+
     let _ = std::fs::create_dir("./emu/build");
 
     for file in std::fs::read_dir("./emu").unwrap() {
@@ -16,7 +74,8 @@ fn main() {
                     .arg("-fno-exceptions")
                     .arg("-fno-rtti")
                     .arg("-ffreestanding")
-                    .arg("-march=rv64g")
+                    .arg("-march=rv64ima")
+                    .arg("-mabi=lp64")
                     .arg("-Wall")
                     .arg("-O3")
                     .arg("-o")
@@ -51,10 +110,10 @@ fn main() {
     let mut output = Command::new("riscv64-unknown-elf-gcc");
     output
         .arg("-nostdlib")
-        .arg("-emain")
         .arg("-ffreestanding")
         .arg("-Wl,-Tmain_linker.ld")
-        .arg("-march=rv64g")
+        .arg("-march=rv64ima")
+        .arg("-mabi=lp64")
         .arg("-o")
         .arg("./emu/build/emu.o");
     for arg in sfiles.iter() {
@@ -68,21 +127,6 @@ fn main() {
         println!("assembler: {}", String::from_utf8_lossy(&output.stderr));
     }
 
-    let output = Command::new("riscv64-unknown-elf-objcopy")
-        .arg("-O")
-        .arg("binary")
-        .arg("./emu/build/emu.o")
-        .arg("./emu/build/emu")
-        //.arg("./emu/build/pgrm")
-        .output()
-        .unwrap();
-    if !output.stdout.is_empty() {
-        println!("{}", String::from_utf8_lossy(&output.stdout));
-    }
-    if !output.stderr.is_empty() {
-        println!("{}", String::from_utf8_lossy(&output.stderr));
-    }
-
     let objdump_output = Command::new("riscv64-unknown-elf-objdump")
         .arg("-d")
         .arg("./emu/build/emu.o")
@@ -94,8 +138,8 @@ fn main() {
         String::from_utf8_lossy(&objdump_output.stdout)
     );
 
-    let raw = std::fs::read("./emu/build/emu").unwrap();
-    emu::emulator::run_emulator(&raw);
+    let raw = std::fs::read("./emu/build/emu.o").unwrap();
+    emu::emulator::run_emulator(&raw, &config, &options);
 
     std::fs::remove_dir_all("./emu/build").unwrap();
 }
@@ -0,0 +1,346 @@
+use crate::bus::Bus;
+use crate::instruction_set::Instr;
+use crate::memory::PAGE_SIZE;
+use crate::primitives::*;
+use crate::symbols::{self, Symbolizer};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// One definedness bit per byte of a page.
+type ShadowPage = [u8; PAGE_SIZE / 8];
+
+/// How undefined data was used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UninitUse {
+    /// A branch compared an undefined register.
+    Branch,
+    /// A load or store address was computed from an undefined register.
+    Address { size: usize },
+    /// An indirect jump target was undefined.
+    Jump,
+    /// A syscall read undefined bytes from a guest buffer.
+    SyscallParam { name: &'static str },
+}
+
+/// A use of undefined data, reported once per pc.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UninitReport {
+    pub kind: UninitUse,
+    pub pc: u64,
+    /// Return addresses of the active calls, innermost first.
+    pub backtrace: Vec<u64>,
+}
+
+impl UninitReport {
+    /// The report with addresses resolved through `symbols`.
+    pub fn symbolized<'a>(&'a self, symbols: Option<&'a Symbolizer>) -> impl fmt::Display + 'a {
+        Symbolized {
+            report: self,
+            symbols,
+        }
+    }
+}
+
+struct Symbolized<'a> {
+    report: &'a UninitReport,
+    symbols: Option<&'a Symbolizer>,
+}
+
+impl fmt::Display for UninitReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.symbolized(None).fmt(f)
+    }
+}
+
+impl fmt::Display for Symbolized<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Symbolized { report, symbols } = self;
+        match report.kind {
+            UninitUse::Branch => writeln!(
+                f,
+                "Conditional jump or move depends on uninitialised value(s)"
+            )?,
+            UninitUse::Address { size } => {
+                writeln!(f, "Use of uninitialised value of size {size}")?
+            }
+            UninitUse::Jump => writeln!(f, "Jump to the invalid address stated on the next line")?,
+            UninitUse::SyscallParam { name } => {
+                writeln!(f, "Syscall param {name} points to uninitialised byte(s)")?
+            }
+        }
+
+        write!(f, "   at {}", symbols::describe(*symbols, report.pc))?;
+        for ret in report.backtrace.iter() {
+            // Report the call site rather than the return address.
+            let call = ret.wrapping_sub(4);
+            write!(f, "\n   by {}", symbols::describe(*symbols, call))?;
+        }
+        Ok(())
+    }
+}
+
+/// Shadow state for uninitialised memory detection.
+///
+/// Every byte of guest memory carries a defined bit and every register a defined flag.
+/// Bytes start out undefined until the host flashes them or the guest stores a defined
+/// register, device reads are always defined.
+#[derive(Debug, Clone)]
+pub struct Memcheck {
+    pages: HashMap<u64, Arc<ShadowPage>>,
+    /// Bit mask of registers holding undefined data.
+    undefined_regs: u32,
+    /// Shadow call stack of return addresses.
+    calls: Vec<u64>,
+    reports: Vec<UninitReport>,
+    symbols: Option<Arc<Symbolizer>>,
+}
+
+impl Default for Memcheck {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memcheck {
+    /// All registers start out defined, all memory undefined.
+    pub fn new() -> Self {
+        Self {
+            pages: HashMap::new(),
+            undefined_regs: 0,
+            calls: Vec::new(),
+            reports: Vec::new(),
+            symbols: None,
+        }
+    }
+
+    /// Symbols used to resolve addresses in printed reports.
+    pub fn set_symbols(&mut self, symbols: Option<Arc<Symbolizer>>) {
+        self.symbols = symbols;
+    }
+
+    pub fn reports(&self) -> &[UninitReport] {
+        &self.reports
+    }
+
+    /// Return addresses of the active calls, innermost first.
+    pub fn backtrace(&self) -> Vec<u64> {
+        self.calls.iter().rev().copied().collect()
+    }
+
+    pub fn is_defined(&self, addr: u64) -> bool {
+        let bit = (addr as usize % PAGE_SIZE) as u64;
+        self.pages
+            .get(&(addr / PAGE_SIZE as u64))
+            .is_some_and(|page| page[bit as usize / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    pub fn is_range_defined(&self, addr: u64, len: usize) -> bool {
+        (0..len as u64).all(|i| self.is_defined(addr.wrapping_add(i)))
+    }
+
+    /// Mark `addr..addr + len` as defined or undefined.
+    pub fn set_defined(&mut self, addr: u64, len: usize, defined: bool) {
+        for addr in (0..len as u64).map(|i| addr.wrapping_add(i)) {
+            let bit = addr as usize % PAGE_SIZE;
+            let page = self
+                .pages
+                .entry(addr / PAGE_SIZE as u64)
+                .or_insert_with(|| Arc::new([0; PAGE_SIZE / 8]));
+            let byte = &mut Arc::make_mut(page)[bit / 8];
+            if defined {
+                *byte |= 1 << (bit % 8);
+            } else {
+                *byte &= !(1 << (bit % 8));
+            }
+        }
+    }
+
+    pub fn is_reg_defined(&self, reg: Reg) -> bool {
+        self.undefined_regs & (1 << reg.reg_index()) == 0
+    }
+
+    /// Bit mask of registers holding undefined data, saved per thread.
+    pub(crate) fn undefined_regs(&self) -> u32 {
+        self.undefined_regs
+    }
+
+    pub(crate) fn set_undefined_regs(&mut self, undefined_regs: u32) {
+        self.undefined_regs = undefined_regs & !1;
+    }
+
+    pub fn set_reg_defined(&mut self, reg: Reg, defined: bool) {
+        if defined || reg.reg_index() == 0 {
+            self.undefined_regs &= !(1 << reg.reg_index());
+        } else {
+            self.undefined_regs |= 1 << reg.reg_index();
+        }
+    }
+
+    fn report(&mut self, kind: UninitUse, pc: u64) {
+        if self.reports.iter().any(|r| r.pc == pc && r.kind == kind) {
+            return;
+        }
+
+        let report = UninitReport {
+            kind,
+            pc,
+            backtrace: self.backtrace(),
+        };
+        eprintln!("{}\n", report.symbolized(self.symbols.as_deref()));
+        self.reports.push(report);
+    }
+
+    /// Check the syscall buffer `addr..addr + len` is fully defined.
+    pub fn check_syscall_buf(&mut self, name: &'static str, addr: u64, len: usize, pc: u64) {
+        if !self.is_range_defined(addr, len) {
+            self.report(UninitUse::SyscallParam { name }, pc);
+        }
+    }
+
+    fn check_address(&mut self, offset: Offset, size: usize, regs: &[u64; 32], pc: u64) -> u64 {
+        if !self.is_reg_defined(offset.0) {
+            self.report(UninitUse::Address { size }, pc);
+        }
+
+        regs[offset.0].wrapping_add(offset.1.val())
+    }
+
+    fn load(
+        &mut self,
+        dst: Reg,
+        offset: Offset,
+        size: usize,
+        regs: &[u64; 32],
+        bus: &Bus,
+        pc: u64,
+    ) {
+        let addr = self.check_address(offset, size, regs, pc);
+        let defined = bus.has_device(addr) || self.is_range_defined(addr, size);
+        self.set_reg_defined(dst, defined);
+    }
+
+    fn store(&mut self, src: Reg, offset: Offset, size: usize, regs: &[u64; 32], pc: u64) {
+        let addr = self.check_address(offset, size, regs, pc);
+        self.set_defined(addr, size, self.is_reg_defined(src));
+    }
+
+    /// The old value goes to `dst`, memory stays defined only if both operands are.
+    fn amo(&mut self, dst: Reg, addr: Reg, src: Reg, size: usize, regs: &[u64; 32], pc: u64) {
+        let addr = self.check_address(Offset(addr, Imm::new(0)), size, regs, pc);
+        let defined = self.is_range_defined(addr, size);
+        self.set_defined(addr, size, defined && self.is_reg_defined(src));
+        self.set_reg_defined(dst, defined);
+    }
+
+    fn branch(&mut self, src1: Reg, src2: Reg, pc: u64) {
+        if !self.is_reg_defined(src1) || !self.is_reg_defined(src2) {
+            self.report(UninitUse::Branch, pc);
+        }
+    }
+
+    /// Propagate definedness for `instr`, called before it executes.
+    pub(crate) fn step(&mut self, instr: Instr, regs: &[u64; 32], bus: &Bus, pc: u64) {
+        match instr {
+            Instr::Lui(dst, _) | Instr::Auipc(dst, _) => self.set_reg_defined(dst, true),
+            Instr::Addi(dst, src, _)
+            | Instr::Addiw(dst, src, _)
+            | Instr::Slti(dst, src, _)
+            | Instr::Sltiu(dst, src, _)
+            | Instr::Xori(dst, src, _)
+            | Instr::Ori(dst, src, _)
+            | Instr::Andi(dst, src, _)
+            | Instr::Slli(dst, src, _)
+            | Instr::Srli(dst, src, _)
+            | Instr::Srai(dst, src, _)
+            | Instr::Slliw(dst, src, _)
+            | Instr::Srliw(dst, src, _)
+            | Instr::Sraiw(dst, src, _) => self.set_reg_defined(dst, self.is_reg_defined(src)),
+            Instr::Add(dst, src1, src2)
+            | Instr::Sub(dst, src1, src2)
+            | Instr::Sll(dst, src1, src2)
+            | Instr::Slt(dst, src1, src2)
+            | Instr::Sltu(dst, src1, src2)
+            | Instr::Xor(dst, src1, src2)
+            | Instr::Srl(dst, src1, src2)
+            | Instr::Sra(dst, src1, src2)
+            | Instr::Or(dst, src1, src2)
+            | Instr::And(dst, src1, src2)
+            | Instr::Subw(dst, src1, src2)
+            | Instr::Srlw(dst, src1, src2)
+            | Instr::Sraw(dst, src1, src2)
+            | Instr::Sltw(dst, src1, src2)
+            | Instr::Sllw(dst, src1, src2)
+            | Instr::Addw(dst, src1, src2)
+            | Instr::Mul(dst, src1, src2)
+            | Instr::Mulh(dst, src1, src2)
+            | Instr::Mulhsu(dst, src1, src2)
+            | Instr::Mulhu(dst, src1, src2)
+            | Instr::Div(dst, src1, src2)
+            | Instr::Divu(dst, src1, src2)
+            | Instr::Rem(dst, src1, src2)
+            | Instr::Remu(dst, src1, src2)
+            | Instr::Mulw(dst, src1, src2)
+            | Instr::Divw(dst, src1, src2)
+            | Instr::Divuw(dst, src1, src2)
+            | Instr::Remw(dst, src1, src2)
+            | Instr::Remuw(dst, src1, src2) => {
+                let defined = self.is_reg_defined(src1) && self.is_reg_defined(src2);
+                self.set_reg_defined(dst, defined);
+            }
+            Instr::Lb(dst, offset) | Instr::Lbu(dst, offset) => {
+                self.load(dst, offset, 1, regs, bus, pc)
+            }
+            Instr::Lh(dst, offset) | Instr::Lhu(dst, offset) => {
+                self.load(dst, offset, 2, regs, bus, pc)
+            }
+            Instr::Lw(dst, offset) | Instr::Lwu(dst, offset) => {
+                self.load(dst, offset, 4, regs, bus, pc)
+            }
+            Instr::Ld(dst, offset) => self.load(dst, offset, 8, regs, bus, pc),
+            Instr::Sb(src, offset) => self.store(src, offset, 1, regs, pc),
+            Instr::Sh(src, offset) => self.store(src, offset, 2, regs, pc),
+            Instr::Sw(src, offset) => self.store(src, offset, 4, regs, pc),
+            Instr::Sd(src, offset) => self.store(src, offset, 8, regs, pc),
+            Instr::LrW(dst, addr) => self.load(dst, Offset(addr, Imm::new(0)), 4, regs, bus, pc),
+            Instr::LrD(dst, addr) => self.load(dst, Offset(addr, Imm::new(0)), 8, regs, bus, pc),
+            // Assumes the store goes through, a failed `sc` is retried by the guest anyway.
+            Instr::ScW(dst, addr, src) => {
+                self.store(src, Offset(addr, Imm::new(0)), 4, regs, pc);
+                self.set_reg_defined(dst, true);
+            }
+            Instr::ScD(dst, addr, src) => {
+                self.store(src, Offset(addr, Imm::new(0)), 8, regs, pc);
+                self.set_reg_defined(dst, true);
+            }
+            Instr::AmoW(_, dst, addr, src) => self.amo(dst, addr, src, 4, regs, pc),
+            Instr::AmoD(_, dst, addr, src) => self.amo(dst, addr, src, 8, regs, pc),
+            Instr::Jal(dst, _) => {
+                if matches!(dst, Reg::Ra) {
+                    self.calls.push(pc + 4);
+                }
+                self.set_reg_defined(dst, true);
+            }
+            Instr::Jalr(dst, src, _) => {
+                if !self.is_reg_defined(src) {
+                    self.report(UninitUse::Jump, pc);
+                }
+                if matches!(dst, Reg::Ra) {
+                    self.calls.push(pc + 4);
+                } else if matches!((dst, src), (Reg::Zero, Reg::Ra)) {
+                    self.calls.pop();
+                }
+                self.set_reg_defined(dst, true);
+            }
+            Instr::Beq(src1, src2, _)
+            | Instr::Bne(src1, src2, _)
+            | Instr::Blt(src1, src2, _)
+            | Instr::Bge(src1, src2, _)
+            | Instr::Bltu(src1, src2, _)
+            | Instr::Bgeu(src1, src2, _) => self.branch(src1, src2, pc),
+            Instr::Ecall => self.set_reg_defined(Reg::A(0), true),
+            Instr::Csrr(dst, _) => self.set_reg_defined(dst, true),
+            Instr::Fence | Instr::Ebreak | Instr::Illegal(_) => {}
+        }
+    }
+}
@@ -0,0 +1,471 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+
+pub const PAGE_SIZE: usize = 4096;
+
+static ZERO_PAGE: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+
+/// The kind of guest access that produced a [`MemoryFault`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl fmt::Display for AccessKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fetch => write!(f, "fetch"),
+            Self::Load => write!(f, "load"),
+            Self::Store => write!(f, "store"),
+        }
+    }
+}
+
+/// Why a [`MemoryFault`] was raised.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultCause {
+    /// Nothing is mapped at the address.
+    Unmapped,
+    /// The region does not permit this kind of access.
+    Permission,
+    /// The address is not aligned as the instruction requires.
+    Misaligned,
+}
+
+/// A guest access that is not backed by the address space or not permitted by its region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryFault {
+    pub addr: u64,
+    pub size: usize,
+    pub kind: AccessKind,
+    pub cause: FaultCause,
+    pub pc: u64,
+}
+
+impl fmt::Display for MemoryFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cause = match self.cause {
+            FaultCause::Unmapped => "access",
+            FaultCause::Permission => "permission",
+            FaultCause::Misaligned => "misaligned",
+        };
+
+        write!(
+            f,
+            "{} {} fault: {} bytes at {:#x}, pc: {:#x}",
+            self.kind, cause, self.size, self.addr, self.pc
+        )
+    }
+}
+
+impl std::error::Error for MemoryFault {}
+
+/// Read, write and execute permissions of a region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Perms {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Perms {
+    pub const RWX: Self = Self::new(true, true, true);
+    pub const RW: Self = Self::new(true, true, false);
+    pub const RX: Self = Self::new(true, false, true);
+    pub const R: Self = Self::new(true, false, false);
+    pub const NONE: Self = Self::new(false, false, false);
+
+    pub const fn new(read: bool, write: bool, execute: bool) -> Self {
+        Self {
+            read,
+            write,
+            execute,
+        }
+    }
+
+    pub fn allows(&self, kind: AccessKind) -> bool {
+        match kind {
+            AccessKind::Fetch => self.execute,
+            AccessKind::Load => self.read,
+            AccessKind::Store => self.write,
+        }
+    }
+}
+
+impl std::ops::BitAnd for Perms {
+    type Output = Self;
+
+    /// Permissions both sides have.
+    fn bitand(self, other: Self) -> Self {
+        Self::new(
+            self.read && other.read,
+            self.write && other.write,
+            self.execute && other.execute,
+        )
+    }
+}
+
+impl fmt::Display for Perms {
+    /// Linker script notation, e.g. `rx`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (set, c) in [(self.read, 'r'), (self.write, 'w'), (self.execute, 'x')] {
+            if set {
+                write!(f, "{c}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Perms {
+    type Err = String;
+
+    /// Parses any combination of `r`, `w` and `x`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut perms = Self::new(false, false, false);
+        for c in s.chars() {
+            let bit = match c {
+                'r' => &mut perms.read,
+                'w' => &mut perms.write,
+                'x' => &mut perms.execute,
+                _ => return Err(format!("invalid permission `{c}` in `{s}`")),
+            };
+            if *bit {
+                return Err(format!("repeated permission `{c}` in `{s}`"));
+            }
+            *bit = true;
+        }
+        Ok(perms)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Ram,
+    /// Immutable to the guest, only the host can write it.
+    Rom,
+}
+
+/// A named range of RAM or ROM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    pub range: Range<u64>,
+    pub kind: RegionKind,
+    pub perms: Perms,
+}
+
+impl Region {
+    /// Read/write/execute RAM.
+    pub fn ram(name: impl Into<String>, range: Range<u64>) -> Self {
+        Self {
+            name: name.into(),
+            range,
+            kind: RegionKind::Ram,
+            perms: Perms::RWX,
+        }
+    }
+}
+
+/// Sparse guest memory.
+///
+/// Pages are allocated on first write, untouched pages read as zero. Pages are shared
+/// between clones and copied on write, so cloning only costs a pointer per touched page.
+#[derive(Debug, Clone)]
+pub struct Memory {
+    pages: HashMap<u64, Arc<[u8; PAGE_SIZE]>>,
+    phys_addr_bits: u32,
+    regions: Vec<Region>,
+}
+
+impl Memory {
+    /// Memory with the whole physical address space mapped as RAM.
+    pub fn new(phys_addr_bits: u32) -> Self {
+        let size = 1u64.checked_shl(phys_addr_bits).unwrap_or(u64::MAX);
+        Self::with_regions(phys_addr_bits, vec![Region::ram("ram", 0..size)])
+    }
+
+    /// Memory with only `regions` mapped as RAM.
+    pub fn with_regions(phys_addr_bits: u32, regions: Vec<Region>) -> Self {
+        assert!(
+            (12..=64).contains(&phys_addr_bits),
+            "invalid physical address width: {phys_addr_bits}"
+        );
+
+        Self {
+            pages: HashMap::new(),
+            phys_addr_bits,
+            regions,
+        }
+    }
+
+    /// Size of the physical address space in bytes, saturating at [`u64::MAX`].
+    pub fn size(&self) -> u64 {
+        1u64.checked_shl(self.phys_addr_bits).unwrap_or(u64::MAX)
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    pub fn region(&self, name: &str) -> Option<&Region> {
+        self.regions.iter().find(|region| region.name == name)
+    }
+
+    /// Whether `addr..addr + len` lies within the physical address space.
+    pub fn in_address_space(&self, addr: u64, len: usize) -> bool {
+        addr as u128 + len as u128 <= 1u128 << self.phys_addr_bits
+    }
+
+    /// Region that `addr..addr + len` lies within entirely.
+    pub fn region_at(&self, addr: u64, len: usize) -> Option<&Region> {
+        if !self.in_address_space(addr, len) {
+            return None;
+        }
+
+        let end = addr as u128 + len as u128;
+        self.regions
+            .iter()
+            .find(|region| region.range.start <= addr && end <= region.range.end as u128)
+    }
+
+    /// Permissions every byte of `addr..addr + len` has, `None` unless all of it is mapped.
+    ///
+    /// The range may span adjacent regions, such as the pieces [`Memory::protect`] leaves.
+    pub fn perms_at(&self, addr: u64, len: usize) -> Option<Perms> {
+        if len == 0 || !self.in_address_space(addr, len) {
+            return self.region_at(addr, len).map(|region| region.perms);
+        }
+
+        let end = addr as u128 + len as u128;
+        let mut perms = Perms::RWX;
+        let mut at = addr;
+        loop {
+            let region = self
+                .regions
+                .iter()
+                .find(|region| region.range.contains(&at))?;
+            perms = perms & region.perms;
+            if end <= region.range.end as u128 {
+                return Some(perms);
+            }
+            at = region.range.end;
+        }
+    }
+
+    /// Whether all of `addr..addr + len` is mapped.
+    pub fn contains(&self, addr: u64, len: usize) -> bool {
+        self.perms_at(addr, len).is_some()
+    }
+
+    /// Change the permissions of `range`, splitting the region that contains it. Pieces of
+    /// the same region that end up with the same permissions are joined again.
+    ///
+    /// Returns `false` if `range` does not lie within a single region.
+    pub fn protect(&mut self, range: Range<u64>, perms: Perms) -> bool {
+        let Some(index) = self
+            .regions
+            .iter()
+            .position(|region| region.range.start <= range.start && range.end <= region.range.end)
+        else {
+            return false;
+        };
+
+        let region = self.regions.remove(index);
+        let pieces = [
+            (region.range.start..range.start, region.perms),
+            (range.clone(), perms),
+            (range.end..region.range.end, region.perms),
+        ];
+        for (i, (range, perms)) in pieces
+            .into_iter()
+            .filter(|(r, _)| !r.is_empty())
+            .enumerate()
+        {
+            self.regions.insert(
+                index + i,
+                Region {
+                    range,
+                    perms,
+                    ..region.clone()
+                },
+            );
+        }
+
+        let mut i = index.saturating_sub(1);
+        while i + 1 < self.regions.len() && i <= index + 2 {
+            let (a, b) = (&self.regions[i], &self.regions[i + 1]);
+            if a.range.end == b.range.start
+                && (&a.name, a.kind, a.perms) == (&b.name, b.kind, b.perms)
+            {
+                let next = self.regions.remove(i + 1);
+                self.regions[i].range.end = next.range.end;
+            } else {
+                i += 1;
+            }
+        }
+
+        true
+    }
+
+    /// Number of pages that have been materialised by a write.
+    pub fn pages_touched(&self) -> usize {
+        self.pages.len()
+    }
+
+    fn page(&self, addr: u64) -> &[u8; PAGE_SIZE] {
+        self.pages
+            .get(&(addr / PAGE_SIZE as u64))
+            .map(|page| page.as_ref())
+            .unwrap_or(&ZERO_PAGE)
+    }
+
+    fn page_mut(&mut self, addr: u64) -> &mut [u8; PAGE_SIZE] {
+        let page = self
+            .pages
+            .entry(addr / PAGE_SIZE as u64)
+            .or_insert_with(|| Arc::new([0; PAGE_SIZE]));
+
+        Arc::make_mut(page)
+    }
+
+    /// Base addresses of the pages whose contents differ from `other`, in ascending order.
+    ///
+    /// Pages still shared between the two are skipped without comparing them.
+    pub fn diff(&self, other: &Memory) -> Vec<u64> {
+        let mut pages: Vec<u64> = self
+            .pages
+            .keys()
+            .chain(
+                other
+                    .pages
+                    .keys()
+                    .filter(|page| !self.pages.contains_key(page)),
+            )
+            .copied()
+            .filter(|page| match (self.pages.get(page), other.pages.get(page)) {
+                (Some(a), Some(b)) => !Arc::ptr_eq(a, b) && a != b,
+                (Some(p), None) | (None, Some(p)) => p.as_ref() != &ZERO_PAGE,
+                (None, None) => false,
+            })
+            .map(|page| page * PAGE_SIZE as u64)
+            .collect();
+        pages.sort_unstable();
+
+        pages
+    }
+
+    /// Bytes at `addr..addr + len`.
+    ///
+    /// Borrows directly from the page when the range does not cross a page boundary.
+    pub fn slice(&self, addr: u64, len: usize) -> Cow<'_, [u8]> {
+        let offset = addr as usize % PAGE_SIZE;
+        if offset + len <= PAGE_SIZE {
+            Cow::Borrowed(&self.page(addr)[offset..offset + len])
+        } else {
+            let mut buf = vec![0; len];
+            self.read(addr, &mut buf);
+            Cow::Owned(buf)
+        }
+    }
+
+    pub fn read(&self, mut addr: u64, mut buf: &mut [u8]) {
+        while !buf.is_empty() {
+            let offset = addr as usize % PAGE_SIZE;
+            let len = buf.len().min(PAGE_SIZE - offset);
+            buf[..len].copy_from_slice(&self.page(addr)[offset..offset + len]);
+            buf = &mut buf[len..];
+            addr += len as u64;
+        }
+    }
+
+    pub fn write(&mut self, mut addr: u64, mut data: &[u8]) {
+        while !data.is_empty() {
+            let offset = addr as usize % PAGE_SIZE;
+            let len = data.len().min(PAGE_SIZE - offset);
+            self.page_mut(addr)[offset..offset + len].copy_from_slice(&data[..len]);
+            data = &data[len..];
+            addr += len as u64;
+        }
+    }
+
+    pub fn byte(&self, addr: u64) -> u8 {
+        self.page(addr)[addr as usize % PAGE_SIZE]
+    }
+
+    pub fn byte_mut(&mut self, addr: u64) -> &mut u8 {
+        &mut self.page_mut(addr)[addr as usize % PAGE_SIZE]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn untouched_pages_read_as_zero() {
+        let memory = Memory::new(32);
+
+        assert_eq!(&[0; 16], memory.slice(0x4000_0000, 16).as_ref());
+        assert_eq!(0, memory.pages_touched());
+    }
+
+    #[test]
+    fn write_across_page_boundary() {
+        let mut memory = Memory::new(32);
+
+        let addr = PAGE_SIZE as u64 - 2;
+        memory.write(addr, &[1, 2, 3, 4]);
+
+        assert_eq!(2, memory.pages_touched());
+        assert!(matches!(memory.slice(addr, 4), Cow::Owned(_)));
+        assert_eq!(&[1, 2, 3, 4], memory.slice(addr, 4).as_ref());
+        assert!(matches!(memory.slice(addr, 2), Cow::Borrowed(&[1, 2])));
+    }
+
+    #[test]
+    fn clones_share_pages_until_written() {
+        let mut memory = Memory::new(32);
+        memory.write(0x1000, &[1]);
+        memory.write(0x3000, &[2]);
+
+        let mut fork = memory.clone();
+        assert!(fork.diff(&memory).is_empty());
+
+        fork.write(0x3000, &[3]);
+        fork.write(0x5000, &[4]);
+        fork.write(0x7000, &[0]);
+
+        assert_eq!(2, memory.byte(0x3000));
+        assert_eq!(3, fork.byte(0x3000));
+        assert_eq!(vec![0x3000, 0x5000], fork.diff(&memory));
+        assert_eq!(fork.diff(&memory), memory.diff(&fork));
+    }
+
+    #[test]
+    fn protect_splits_region() {
+        let mut memory = Memory::new(16);
+
+        assert!(memory.protect(0x1000..0x2000, Perms::RX));
+        assert!(!memory.protect(0xF000..0x1_1000, Perms::R));
+
+        let perms: Vec<_> = memory.regions().iter().map(|r| r.perms).collect();
+        assert_eq!(vec![Perms::RWX, Perms::RX, Perms::RWX], perms);
+        assert_eq!(Perms::RX, memory.region_at(0x1ffc, 4).unwrap().perms);
+        assert_eq!(Some(Perms::RX), memory.perms_at(0xffe, 4));
+
+        // Splitting again and restoring the permissions leaves a single region.
+        assert!(memory.protect(0x1800..0x1900, Perms::R));
+        assert_eq!(Some(Perms::R), memory.perms_at(0x17fe, 0x200));
+        assert!(memory.protect(0x1800..0x1900, Perms::RX));
+        assert!(memory.protect(0x1000..0x2000, Perms::RWX));
+        assert_eq!(1, memory.regions().len());
+        assert_eq!(Ok(Perms::RW), "rw".parse());
+        assert!("rwr".parse::<Perms>().is_err());
+    }
+}
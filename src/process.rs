@@ -0,0 +1,386 @@
+use crate::elf::ElfImage;
+use std::fs::File;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Auxiliary vector tags from `<elf.h>`.
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_RANDOM: u64 = 25;
+
+/// What a user-mode program is started with.
+#[derive(Debug, Clone, Default)]
+pub struct StartInfo {
+    /// `argv`, including the program name.
+    pub args: Vec<String>,
+    /// `KEY=VALUE` pairs for `envp`.
+    pub env: Vec<String>,
+    /// Bytes `AT_RANDOM` points at, libc seeds its stack protector from them.
+    pub random: [u8; 16],
+}
+
+impl StartInfo {
+    /// Auxiliary vector for `image`, or for a raw binary entered at `entry`.
+    ///
+    /// `AT_RANDOM` and `AT_NULL` are added by [`initial_stack`].
+    pub fn auxv(image: Option<&ElfImage>, entry: u64) -> Vec<(u64, u64)> {
+        let mut auxv = Vec::new();
+        if let Some(image) = image {
+            auxv.push((AT_PHDR, image.phdr));
+            auxv.push((AT_PHENT, image.phent as u64));
+            auxv.push((AT_PHNUM, image.phnum as u64));
+        }
+        auxv.push((AT_PAGESZ, crate::memory::PAGE_SIZE as u64));
+        auxv.push((AT_ENTRY, entry));
+
+        auxv
+    }
+}
+
+/// Fill `buf` from the host's per-process hash keys, a source of entropy without a
+/// dependency.
+pub fn host_entropy(buf: &mut [u8]) {
+    use std::hash::{BuildHasher, RandomState};
+
+    let state = RandomState::new();
+    for (i, chunk) in buf.chunks_mut(8).enumerate() {
+        let bytes = state.hash_one(i).to_le_bytes();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
+
+/// What a thread of a user-mode guest is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Runnable,
+    /// Blocked in `FUTEX_WAIT` on the word at `addr`, failing with `ETIMEDOUT` once the
+    /// guest's uptime reaches `deadline`, if it has one.
+    Futex {
+        addr: u64,
+        deadline: Option<Duration>,
+    },
+    /// Gone, removed at the next switch.
+    Exited,
+}
+
+/// A thread of a user-mode guest, registers and pc are saved here while it is not running.
+#[derive(Debug, Clone)]
+pub struct Thread {
+    pub tid: u64,
+    pub state: ThreadState,
+    pub(crate) regs: [u64; 32],
+    pub(crate) pc: u64,
+    /// Memcheck's undefined register mask, see [`crate::memcheck::Memcheck`].
+    pub(crate) undefined_regs: u32,
+    /// Zeroed and woken as a futex when the thread exits, see `set_tid_address`.
+    pub(crate) clear_child_tid: u64,
+    /// Blocked signals, bit `n - 1` for signal `n`.
+    pub(crate) sigmask: u64,
+    /// Signals sent to the thread and not yet delivered.
+    pub(crate) pending: u64,
+}
+
+impl Thread {
+    pub fn new(tid: u64) -> Self {
+        Self {
+            tid,
+            state: ThreadState::Runnable,
+            regs: [0; 32],
+            pc: 0,
+            undefined_regs: 0,
+            clear_child_tid: 0,
+            sigmask: 0,
+            pending: 0,
+        }
+    }
+}
+
+/// `struct sigaction` as `rt_sigaction` takes it on riscv64, which has no restorer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SigAction {
+    /// `SIG_DFL`, `SIG_IGN` or the handler's address.
+    pub handler: u64,
+    pub flags: u64,
+    /// Signals blocked while the handler runs.
+    pub mask: u64,
+}
+
+/// An open file descriptor of a user-mode guest.
+#[derive(Debug, Clone)]
+pub enum FileDesc {
+    /// Host input, reads wait for it unless `nonblocking` (`O_NONBLOCK`) is set.
+    Stdin { nonblocking: bool },
+    /// stdout and stderr, both end up on the console.
+    Console,
+    /// A file in the sandbox, shared between forks like a Unix open file description.
+    Host { file: Arc<File>, path: PathBuf },
+}
+
+/// Linux process state behind the system calls of a user-mode guest.
+#[derive(Debug, Clone)]
+pub struct Process {
+    files: Vec<Option<FileDesc>>,
+    /// Host directory the guest sees as `/`, there is no file access without one.
+    root: Option<PathBuf>,
+    /// Memory `brk` and `mmap` share, the break grows up from `brk_start` and mappings are
+    /// handed out downwards from the end.
+    pub(crate) heap: Range<u64>,
+    pub(crate) brk_start: u64,
+    pub(crate) brk: u64,
+    /// Lowest address handed out by `mmap` so far.
+    pub(crate) mmap_bottom: u64,
+    /// Gaps `munmap` left above `mmap_bottom`, lowest first.
+    pub(crate) unmapped: Vec<Range<u64>>,
+    /// `argv` the program was started with, for semihosting's `SYS_GET_CMDLINE`.
+    pub(crate) args: Vec<String>,
+    /// Error of the last failed semihosting call, for `SYS_ERRNO`.
+    pub(crate) errno: i64,
+    /// Every thread, the running one's registers are in the emulator, see [`crate::threads`].
+    pub(crate) threads: Vec<Thread>,
+    /// Index of the running thread in `threads`.
+    pub(crate) current: usize,
+    pub(crate) next_tid: u64,
+    /// Switch threads after the current instruction.
+    pub(crate) reschedule: bool,
+    /// Disposition of each signal, see [`crate::signals`].
+    pub(crate) sigactions: [SigAction; 64],
+    /// Code signal handlers return to, mapped when the first one runs.
+    pub(crate) sigreturn: Option<u64>,
+    /// Started from an ELF by [`Emulator::start_process`](crate::emulator::Emulator::start_process),
+    /// rather than a bare-metal image that may return to address 0 to exit.
+    pub(crate) linux: bool,
+}
+
+impl Default for Process {
+    fn default() -> Self {
+        Self {
+            files: vec![
+                Some(FileDesc::Stdin { nonblocking: false }),
+                Some(FileDesc::Console),
+                Some(FileDesc::Console),
+            ],
+            root: None,
+            heap: 0..0,
+            brk_start: 0,
+            brk: 0,
+            mmap_bottom: 0,
+            unmapped: Vec::new(),
+            args: Vec::new(),
+            errno: 0,
+            // The main thread leads the thread group, its id is the process id.
+            threads: vec![Thread::new(1)],
+            current: 0,
+            next_tid: 2,
+            reschedule: false,
+            sigactions: [SigAction::default(); 64],
+            sigreturn: None,
+            linux: false,
+        }
+    }
+}
+
+impl Process {
+    pub fn thread(&self) -> &Thread {
+        &self.threads[self.current]
+    }
+
+    pub(crate) fn thread_mut(&mut self) -> &mut Thread {
+        &mut self.threads[self.current]
+    }
+
+    pub fn root(&self) -> Option<&Path> {
+        self.root.as_deref()
+    }
+
+    /// Serve guest file accesses from the host directory `root`.
+    pub fn set_root(&mut self, root: Option<PathBuf>) {
+        self.root = root;
+    }
+
+    /// Hand `heap` to `brk` and `mmap`, with the break starting at `brk_start`.
+    pub fn set_heap(&mut self, heap: Range<u64>, brk_start: u64) {
+        self.brk_start = brk_start;
+        self.brk = brk_start;
+        self.mmap_bottom = heap.end;
+        self.unmapped.clear();
+        self.heap = heap;
+    }
+
+    pub fn file(&self, fd: u64) -> Option<&FileDesc> {
+        self.files.get(fd as usize)?.as_ref()
+    }
+
+    pub fn file_mut(&mut self, fd: u64) -> Option<&mut FileDesc> {
+        self.files.get_mut(fd as usize)?.as_mut()
+    }
+
+    /// Install `desc` at the lowest free descriptor.
+    pub fn open(&mut self, desc: FileDesc) -> u64 {
+        let fd = match self.files.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+        };
+        self.files[fd] = Some(desc);
+
+        fd as u64
+    }
+
+    /// Whether `fd` was open.
+    pub fn close(&mut self, fd: u64) -> bool {
+        self.files
+            .get_mut(fd as usize)
+            .and_then(Option::take)
+            .is_some()
+    }
+
+    /// Host path for the guest path `path`, relative paths start from `/`.
+    ///
+    /// `..` stops at the root and symbolic links leading outside of it are refused, so the
+    /// guest only ever sees the sandbox. `None` without a root or for an escaping path.
+    pub fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let root = self.root.as_ref()?;
+        let mut resolved = root.clone();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(name) => resolved.push(name),
+                Component::ParentDir => {
+                    if resolved != *root {
+                        resolved.pop();
+                    }
+                }
+                Component::RootDir => resolved = root.clone(),
+                Component::CurDir | Component::Prefix(_) => {}
+            }
+        }
+
+        // Follow links for whatever part of the path exists, a new file's parent must. A
+        // dangling link is refused, creating a file through it could land anywhere.
+        let existing = match resolved.canonicalize() {
+            Ok(path) => path,
+            Err(_) if resolved.symlink_metadata().is_ok() => return None,
+            Err(_) => resolved.parent()?.canonicalize().ok()?,
+        };
+        existing
+            .starts_with(root.canonicalize().ok()?)
+            .then_some(resolved)
+    }
+}
+
+/// Lay out `argc`, `argv`, `envp` and the auxiliary vector below `top` as the RISC-V
+/// Linux psABI expects at process entry.
+///
+/// Returns the initial stack pointer, which points at `argc` and is 16 byte aligned, and
+/// the bytes to write from there up to `top`.
+pub fn initial_stack(top: u64, info: &StartInfo, auxv: &[(u64, u64)]) -> (u64, Vec<u8>) {
+    // Strings and random bytes go at the very top, pointers are filled in below them.
+    let mut cursor = top - info.random.len() as u64;
+    let random = cursor;
+    let mut strings = Vec::new();
+    for string in info.args.iter().chain(info.env.iter()) {
+        cursor -= string.len() as u64 + 1;
+        strings.push((cursor, string.as_bytes()));
+    }
+    let (argv, envp) = strings.split_at(info.args.len());
+
+    let mut words = vec![info.args.len() as u64];
+    words.extend(argv.iter().map(|(addr, _)| *addr));
+    words.push(0);
+    words.extend(envp.iter().map(|(addr, _)| *addr));
+    words.push(0);
+    for (key, val) in auxv.iter().chain(&[(AT_RANDOM, random), (AT_NULL, 0)]) {
+        words.extend([*key, *val]);
+    }
+
+    let sp = (cursor - words.len() as u64 * 8) & !0xF;
+    let mut stack = vec![0; (top - sp) as usize];
+    for (i, word) in words.iter().enumerate() {
+        stack[i * 8..i * 8 + 8].copy_from_slice(&word.to_le_bytes());
+    }
+    for (addr, string) in strings.iter() {
+        let offset = (addr - sp) as usize;
+        stack[offset..offset + string.len()].copy_from_slice(string);
+    }
+    let offset = (random - sp) as usize;
+    stack[offset..offset + info.random.len()].copy_from_slice(&info.random);
+
+    (sp, stack)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn word(stack: &[u8], index: usize) -> u64 {
+        u64::from_le_bytes(stack[index * 8..index * 8 + 8].try_into().unwrap())
+    }
+
+    fn c_str(stack: &[u8], sp: u64, addr: u64) -> &str {
+        let start = (addr - sp) as usize;
+        let len = stack[start..].iter().position(|b| *b == 0).unwrap();
+        std::str::from_utf8(&stack[start..start + len]).unwrap()
+    }
+
+    #[test]
+    fn resolve_stays_in_root() {
+        let dir = TempDir::new("resolve");
+        let root = dir.path().to_path_buf();
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        let mut process = Process::default();
+        assert_eq!(None, process.resolve("/etc"));
+
+        process.set_root(Some(root.clone()));
+        assert_eq!(
+            Some(root.join("etc/passwd")),
+            process.resolve("/etc/passwd")
+        );
+        assert_eq!(Some(root.join("etc")), process.resolve("../../etc"));
+        assert_eq!(None, process.resolve("/missing/file"));
+        #[cfg(unix)]
+        {
+            let outside = std::env::temp_dir().join("emu-resolve-outside");
+            std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+            assert_eq!(None, process.resolve("/link"));
+            assert_eq!(None, process.resolve("/etc/../link"));
+        }
+
+        assert_eq!(3, process.open(FileDesc::Stdin { nonblocking: false }));
+        assert!(process.close(1));
+        assert!(!process.close(1));
+        assert_eq!(1, process.open(FileDesc::Console));
+    }
+
+    #[test]
+    fn argv_envp_and_auxv() {
+        let info = StartInfo {
+            args: vec!["prog".into(), "-v".into()],
+            env: vec!["HOME=/".into()],
+            random: [7; 16],
+        };
+        let top = 0x1_0000;
+        let (sp, stack) = initial_stack(top, &info, &[(AT_ENTRY, 0x4000_0000)]);
+
+        assert_eq!(0, sp % 16);
+        assert_eq!(top - sp, stack.len() as u64);
+        assert_eq!(2, word(&stack, 0));
+        assert_eq!("prog", c_str(&stack, sp, word(&stack, 1)));
+        assert_eq!("-v", c_str(&stack, sp, word(&stack, 2)));
+        assert_eq!(0, word(&stack, 3));
+        assert_eq!("HOME=/", c_str(&stack, sp, word(&stack, 4)));
+        assert_eq!(0, word(&stack, 5));
+        assert_eq!([AT_ENTRY, 0x4000_0000], [word(&stack, 6), word(&stack, 7)]);
+        assert_eq!(AT_RANDOM, word(&stack, 8));
+        let random = (word(&stack, 9) - sp) as usize;
+        assert_eq!([7; 16], stack[random..random + 16]);
+        assert_eq!([AT_NULL, 0], [word(&stack, 10), word(&stack, 11)]);
+    }
+}
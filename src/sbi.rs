@@ -0,0 +1,282 @@
+use crate::devices::Clint;
+use crate::emulator::{Emulator, ExitReason};
+use crate::primitives::*;
+use crate::syscalls::{read_guest, read_u64, writable, write_guest};
+
+/// Extension IDs, passed in `a7`.
+pub const EXT_SET_TIMER: u64 = 0x00;
+pub const EXT_CONSOLE_PUTCHAR: u64 = 0x01;
+pub const EXT_CONSOLE_GETCHAR: u64 = 0x02;
+pub const EXT_CLEAR_IPI: u64 = 0x03;
+pub const EXT_SEND_IPI: u64 = 0x04;
+pub const EXT_REMOTE_FENCE_I: u64 = 0x05;
+pub const EXT_REMOTE_SFENCE_VMA: u64 = 0x06;
+pub const EXT_REMOTE_SFENCE_VMA_ASID: u64 = 0x07;
+pub const EXT_SHUTDOWN: u64 = 0x08;
+pub const EXT_BASE: u64 = 0x10;
+pub const EXT_TIME: u64 = 0x5449_4d45;
+pub const EXT_IPI: u64 = 0x73_5049;
+pub const EXT_RFENCE: u64 = 0x5246_4e43;
+pub const EXT_HSM: u64 = 0x48_534d;
+pub const EXT_SRST: u64 = 0x5352_5354;
+pub const EXT_DBCN: u64 = 0x4442_434e;
+
+/// Error codes, returned in `a0`.
+pub const SUCCESS: i64 = 0;
+pub const ERR_NOT_SUPPORTED: i64 = -2;
+pub const ERR_INVALID_PARAM: i64 = -3;
+pub const ERR_ALREADY_AVAILABLE: i64 = -6;
+
+const SPEC_VERSION: u64 = 2 << 24;
+/// Implementation ID reported by `sbi_get_impl_id`, not one of the registered ones.
+pub const IMPL_ID: u64 = 0x656d75;
+const HART_STARTED: u64 = 0;
+
+/// Result of a call, the pair returned in `a0` and `a1`.
+type SbiRet = (i64, u64);
+
+/// What a call leaves in `a0` and, unless it is a legacy one, `a1`, or the reason to stop.
+pub(crate) type Outcome = Result<(i64, Option<u64>), ExitReason>;
+
+fn supported(ext: u64) -> bool {
+    ext <= EXT_SHUTDOWN
+        || matches!(
+            ext,
+            EXT_BASE | EXT_TIME | EXT_IPI | EXT_RFENCE | EXT_HSM | EXT_SRST | EXT_DBCN
+        )
+}
+
+/// Whether `hart_mask` relative to `hart_mask_base` selects hart 0, the only one there is.
+/// A base of `-1` means every hart.
+fn selects_boot_hart(hart_mask: u64, hart_mask_base: u64) -> Result<bool, i64> {
+    match hart_mask_base {
+        u64::MAX => Ok(true),
+        0 => Ok(hart_mask & 1 != 0),
+        _ if hart_mask == 0 => Ok(false),
+        _ => Err(ERR_INVALID_PARAM),
+    }
+}
+
+/// Service an `ecall` from S-mode as M-mode firmware would.
+///
+/// Implements version 2.0 of the SBI for a single hart: base, legacy console and timer,
+/// TIME, IPI, RFENCE, HSM, SRST and DBCN. There is no MMU or cache to maintain, so remote
+/// fences succeed without doing anything.
+///
+/// Arguments are in `a0..a5`, the function ID in `a6` and the extension ID in `a7`. Returns the reason to stop if the guest asked for a shutdown.
+/// Bad pointers fail the call with `SBI_ERR_INVALID_PARAM` rather than faulting.
+///
+/// Logs the call to the emulator's [`Strace`](crate::strace::Strace) if it has one.
+pub(crate) fn call(emu: &mut Emulator) -> Option<ExitReason> {
+    let ext = emu.reg(Reg::A(7));
+    let fid = emu.reg(Reg::A(6));
+    let args = [0, 1, 2, 3, 4, 5].map(|i| emu.reg(Reg::A(i)));
+
+    let ret = execute(emu, ext, fid, &args);
+    if let Some(strace) = emu.strace() {
+        strace.log_sbi(emu, ext, fid, &args, &ret);
+    }
+    match ret {
+        Ok((error, value)) => {
+            emu.set_signed(Reg::A(0), error);
+            if let Some(value) = value {
+                emu.set(Reg::A(1), value);
+            }
+            None
+        }
+        Err(reason) => Some(reason),
+    }
+}
+
+fn execute(emu: &mut Emulator, ext: u64, fid: u64, args: &[u64; 6]) -> Outcome {
+    let [a0, a1, a2, ..] = *args;
+
+    // Legacy extensions return a single value in a0 and leave a1 alone.
+    let legacy = match ext {
+        EXT_SET_TIMER => Some(set_timer(emu, a0).0),
+        EXT_CONSOLE_PUTCHAR => {
+            emu.console_mut().push(a0 as u8);
+            Some(SUCCESS)
+        }
+        EXT_CONSOLE_GETCHAR => Some(match emu.take_input(1).first() {
+            Some(byte) => *byte as i64,
+            None => -1,
+        }),
+        EXT_CLEAR_IPI => {
+            if let Some(clint) = emu.bus_mut().device_mut::<Clint>() {
+                clint.set_msip(0, false);
+            }
+            Some(SUCCESS)
+        }
+        EXT_SEND_IPI => Some(match read_u64(emu, a0) {
+            Ok(mask) => send_ipi(emu, mask, 0).0,
+            Err(_) => ERR_INVALID_PARAM,
+        }),
+        EXT_REMOTE_FENCE_I | EXT_REMOTE_SFENCE_VMA | EXT_REMOTE_SFENCE_VMA_ASID => Some(SUCCESS),
+        EXT_SHUTDOWN => return Err(ExitReason::Exit(0)),
+        _ => None,
+    };
+    if let Some(ret) = legacy {
+        return Ok((ret, None));
+    }
+
+    let (error, value) = match (ext, fid) {
+        (EXT_BASE, 0) => (SUCCESS, SPEC_VERSION),
+        (EXT_BASE, 1) => (SUCCESS, IMPL_ID),
+        (EXT_BASE, 2) => (SUCCESS, 1),
+        (EXT_BASE, 3) => (SUCCESS, supported(a0) as u64),
+        // mvendorid, marchid and mimpid.
+        (EXT_BASE, 4..=6) => (SUCCESS, 0),
+
+        (EXT_TIME, 0) => set_timer(emu, a0),
+
+        (EXT_IPI, 0) => send_ipi(emu, a0, a1),
+
+        // fence.i and sfence.vma with and without an ASID, the hypervisor fences are not.
+        (EXT_RFENCE, 0..=2) => match selects_boot_hart(a0, a1) {
+            Ok(_) => (SUCCESS, 0),
+            Err(error) => (error, 0),
+        },
+
+        (EXT_HSM, 0) if a0 == 0 => (ERR_ALREADY_AVAILABLE, 0),
+        (EXT_HSM, 2) if a0 == 0 => (SUCCESS, HART_STARTED),
+        // A retentive suspend returns straight away as if an interrupt had woken the hart.
+        (EXT_HSM, 3) if a0 == 0 => (SUCCESS, 0),
+        (EXT_HSM, 0 | 2) => (ERR_INVALID_PARAM, 0),
+        // Stopping the only hart leaves nothing running.
+        (EXT_HSM, 1) => return Err(ExitReason::Exit(0)),
+
+        (EXT_SRST, 0) => match (a0, a1) {
+            // Shutdown, cold and warm reboot all stop the machine, a system failure is an error.
+            (0..=2, reason) => return Err(ExitReason::Exit((reason == 1) as i32)),
+            _ => (ERR_INVALID_PARAM, 0),
+        },
+
+        // The high half of the address in `a2` is always zero on RV64.
+        (EXT_DBCN, 0 | 1) if a2 != 0 => (ERR_INVALID_PARAM, 0),
+        (EXT_DBCN, 0) => match read_guest(emu, a1, a0 as usize) {
+            Ok(data) => {
+                emu.console_mut().extend_from_slice(&data);
+                (SUCCESS, data.len() as u64)
+            }
+            Err(_) => (ERR_INVALID_PARAM, 0),
+        },
+        // Only what has already arrived, the call does not wait.
+        (EXT_DBCN, 1) => match writable(emu, a1, a0) {
+            Ok(()) => {
+                let data = emu.take_input(a0 as usize);
+                write_guest(emu, a1, &data).unwrap();
+                (SUCCESS, data.len() as u64)
+            }
+            Err(_) => (ERR_INVALID_PARAM, 0),
+        },
+        (EXT_DBCN, 2) => {
+            emu.console_mut().push(a0 as u8);
+            (SUCCESS, 0)
+        }
+
+        _ => (ERR_NOT_SUPPORTED, 0),
+    };
+
+    Ok((error, Some(value)))
+}
+
+fn set_timer(emu: &mut Emulator, stime: u64) -> SbiRet {
+    match emu.bus_mut().device_mut::<Clint>() {
+        Some(clint) => {
+            clint.set_mtimecmp(0, stime);
+            (SUCCESS, 0)
+        }
+        None => (ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+fn send_ipi(emu: &mut Emulator, hart_mask: u64, hart_mask_base: u64) -> SbiRet {
+    let selected = match selects_boot_hart(hart_mask, hart_mask_base) {
+        Ok(selected) => selected,
+        Err(error) => return (error, 0),
+    };
+    match emu.bus_mut().device_mut::<Clint>() {
+        Some(clint) => {
+            if selected {
+                clint.set_msip(0, true);
+            }
+            (SUCCESS, 0)
+        }
+        None => (ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{supervisor, syscall, ECALL};
+
+    fn ecall(emu: &mut Emulator, ext: u64, fid: u64, args: &[u64]) -> Option<ExitReason> {
+        emu.set(Reg::A(6), fid);
+        let pc = emu.pc();
+        let reason = syscall(emu, ext, args);
+        emu.set_pc(pc);
+        (reason != ExitReason::BudgetExhausted).then_some(reason)
+    }
+
+    #[test]
+    fn base_and_console() {
+        let (mut emu, _) = supervisor(&ECALL);
+
+        ecall(&mut emu, EXT_BASE, 3, &[EXT_DBCN]);
+        assert_eq!((0, 1), (emu.reg(Reg::A(0)), emu.reg(Reg::A(1))));
+        ecall(&mut emu, EXT_BASE, 3, &[0x0A00_0000]);
+        assert_eq!((0, 0), (emu.reg(Reg::A(0)), emu.reg(Reg::A(1))));
+
+        emu.flash_prgm(b"hi", 0x4000_1000).unwrap();
+        emu.set_pc(0x4000_0000);
+        ecall(&mut emu, EXT_DBCN, 0, &[2, 0x4000_1000, 0]);
+        ecall(&mut emu, EXT_CONSOLE_PUTCHAR, 0, &[b'!' as u64]);
+        assert_eq!(b"hi!", emu.console());
+
+        emu.push_input(b"abc");
+        ecall(&mut emu, EXT_CONSOLE_GETCHAR, 0, &[]);
+        assert_eq!(b'a' as u64, emu.reg(Reg::A(0)));
+        ecall(&mut emu, EXT_DBCN, 1, &[8, 0x4000_1000, 0]);
+        assert_eq!((0, 2), (emu.reg(Reg::A(0)), emu.reg(Reg::A(1))));
+        assert_eq!(b"bc", emu.memory(0x4000_1000, 2).unwrap().as_ref());
+        ecall(&mut emu, EXT_CONSOLE_GETCHAR, 0, &[]);
+        assert_eq!(-1, emu.reg_signed(Reg::A(0)));
+
+        // Bad buffers fail the call and leave the input alone.
+        emu.push_input(b"d");
+        emu.bus_mut()
+            .memory_mut()
+            .protect(0x4000_1000..0x4000_2000, crate::memory::Perms::R);
+        for (fid, args) in [(1, [1, 0x4000_1000, 0]), (0, [1, 0x4000_1000, 1])] {
+            ecall(&mut emu, EXT_DBCN, fid, &args);
+            assert_eq!(ERR_INVALID_PARAM, emu.reg_signed(Reg::A(0)));
+        }
+        ecall(&mut emu, EXT_SEND_IPI, 0, &[0xF000_0000]);
+        assert_eq!(ERR_INVALID_PARAM, emu.reg_signed(Reg::A(0)));
+        ecall(&mut emu, EXT_CONSOLE_GETCHAR, 0, &[]);
+        assert_eq!(b'd' as u64, emu.reg(Reg::A(0)));
+
+        ecall(&mut emu, EXT_HSM, 2, &[0]);
+        assert_eq!((0, HART_STARTED), (emu.reg(Reg::A(0)), emu.reg(Reg::A(1))));
+        ecall(&mut emu, EXT_RFENCE, 4, &[]);
+        assert_eq!(ERR_NOT_SUPPORTED, emu.reg_signed(Reg::A(0)));
+    }
+
+    #[test]
+    fn timer_and_reset() {
+        let (mut emu, _) = supervisor(&ECALL);
+
+        ecall(&mut emu, EXT_TIME, 0, &[1]);
+        let clint = emu.bus().device::<Clint>().unwrap();
+        assert!(!clint.timer_pending(0));
+        emu.tick();
+        assert!(emu.bus().device::<Clint>().unwrap().timer_pending(0));
+
+        assert_eq!(
+            Some(ExitReason::Exit(1)),
+            ecall(&mut emu, EXT_SRST, 0, &[0, 1])
+        );
+    }
+}
@@ -0,0 +1,280 @@
+use crate::emulator::{Emulator, ExitReason};
+use crate::primitives::*;
+use crate::process::FileDesc;
+use crate::syscalls::{self, SysResult, EAGAIN, EBADF, EINVAL, ENOENT, ENOSYS, ERANGE};
+use std::fs::OpenOptions;
+use std::sync::Arc;
+
+/// Operation numbers, passed in `a0`.
+pub const SYS_OPEN: u64 = 0x01;
+pub const SYS_CLOSE: u64 = 0x02;
+pub const SYS_WRITEC: u64 = 0x03;
+pub const SYS_WRITE0: u64 = 0x04;
+pub const SYS_WRITE: u64 = 0x05;
+pub const SYS_READ: u64 = 0x06;
+pub const SYS_ISTTY: u64 = 0x09;
+pub const SYS_SEEK: u64 = 0x0A;
+pub const SYS_FLEN: u64 = 0x0C;
+pub const SYS_CLOCK: u64 = 0x10;
+pub const SYS_TIME: u64 = 0x11;
+pub const SYS_ERRNO: u64 = 0x13;
+pub const SYS_GET_CMDLINE: u64 = 0x15;
+pub const SYS_EXIT: u64 = 0x18;
+pub const SYS_EXIT_EXTENDED: u64 = 0x20;
+
+/// `slli x0, x0, 0x1f` and `srai x0, x0, 7`, the instructions around the `ebreak` of a call.
+pub const ENTRY: u32 = 0x01f0_1013;
+pub const EXIT: u32 = 0x4070_5013;
+
+/// `ADP_Stopped_ApplicationExit`, the reason given for a normal exit.
+const APPLICATION_EXIT: u64 = 0x20026;
+/// Longest string written by `SYS_WRITE0`.
+const WRITE0_MAX: usize = 1 << 16;
+
+/// Whether the `ebreak` at `pc` is a semihosting call.
+pub(crate) fn is_call(emu: &Emulator, pc: u64) -> bool {
+    let word = |addr| {
+        emu.memory(addr, 4)
+            .ok()
+            .map(|bytes| u32::from_le_bytes(bytes.as_ref().try_into().unwrap()))
+    };
+    pc >= 4 && word(pc - 4) == Some(ENTRY) && word(pc + 4) == Some(EXIT)
+}
+
+/// Service a semihosting call as a debugger would.
+///
+/// The operation is in `a0` and its argument in `a1`, usually a pointer to a block of 64
+/// bit fields, the result goes back in `a0`. Handles are descriptors of the Linux process,
+/// so `:tt` opens as 0, 1 or 2 and files are only found inside its root. Returns the reason
+/// to stop if the guest exited or a read of standard input has to wait for the host.
+pub(crate) fn call(emu: &mut Emulator) -> Option<ExitReason> {
+    let op = emu.reg(Reg::A(0));
+    let arg = emu.reg(Reg::A(1));
+    let field = |emu: &Emulator, i: u64| syscalls::read_u64(emu, arg + i * 8);
+
+    let ret = match op {
+        SYS_OPEN => open(emu, arg),
+        SYS_CLOSE => field(emu, 0).and_then(|handle| match handle {
+            // The console stays open for the runtime's other streams.
+            0..=2 => Ok(0),
+            _ if emu.process_mut().close(handle) => Ok(0),
+            _ => Err(EBADF),
+        }),
+        SYS_WRITEC => syscalls::write(emu, 1, arg, 1),
+        SYS_WRITE0 => write0(emu, arg),
+        SYS_WRITE | SYS_READ => match transfer(emu, op, arg) {
+            Some(ret) => ret,
+            None => return Some(ExitReason::WaitingForInput),
+        },
+        SYS_ISTTY => field(emu, 0).and_then(|handle| match emu.process().file(handle) {
+            Some(FileDesc::Stdin { .. } | FileDesc::Console) => Ok(1),
+            Some(FileDesc::Host { .. }) => Ok(0),
+            None => Err(EBADF),
+        }),
+        SYS_SEEK => match (field(emu, 0), field(emu, 1)) {
+            (Ok(handle), Ok(pos)) => syscalls::lseek(emu, handle, pos as i64, 0).map(|_| 0),
+            (Err(errno), _) | (_, Err(errno)) => Err(errno),
+        },
+        SYS_FLEN => field(emu, 0).and_then(|handle| {
+            let file = syscalls::host_file(emu.process(), handle)?;
+            let metadata = file.metadata().map_err(syscalls::io_errno)?;
+            Ok(metadata.len())
+        }),
+        SYS_CLOCK => Ok(emu.uptime().as_millis() as u64 / 10),
+        SYS_TIME => Ok(emu.wall_time().as_secs()),
+        SYS_ERRNO => Ok(emu.process().errno as u64),
+        SYS_GET_CMDLINE => get_cmdline(emu, arg),
+        SYS_EXIT | SYS_EXIT_EXTENDED => {
+            let code = match (field(emu, 0), field(emu, 1)) {
+                (Ok(APPLICATION_EXIT), Ok(code)) => code as i32,
+                _ => 1,
+            };
+            return Some(ExitReason::Exit(code));
+        }
+        _ => {
+            eprintln!("invalid semihosting call: {op:#x}");
+            Err(ENOSYS)
+        }
+    };
+    match ret {
+        Ok(val) => emu.set(Reg::A(0), val),
+        Err(errno) => {
+            emu.process_mut().errno = errno;
+            emu.set_signed(Reg::A(0), -1);
+        }
+    }
+
+    None
+}
+
+/// Open the file named by `block` with one of the twelve `fopen` modes, `r` to `a+b`.
+fn open(emu: &mut Emulator, block: u64) -> SysResult {
+    let name = syscalls::read_u64(emu, block)?;
+    let mode = syscalls::read_u64(emu, block + 8)?;
+    let len = syscalls::read_u64(emu, block + 16)?;
+    let name = syscalls::read_guest(emu, name, len as usize)?;
+    let name = String::from_utf8(name).map_err(|_| EINVAL)?;
+
+    if name == ":tt" {
+        return match mode {
+            0..=3 => Ok(0),
+            4..=7 => Ok(1),
+            8..=11 => Ok(2),
+            _ => Err(EINVAL),
+        };
+    }
+
+    let host = emu.process().resolve(&name).ok_or(ENOENT)?;
+    let update = mode & 2 != 0;
+    let mut options = OpenOptions::new();
+    match mode {
+        0..=3 => options.read(true).write(update),
+        4..=7 => options.write(true).create(true).truncate(true).read(update),
+        8..=11 => options.append(true).create(true).read(update),
+        _ => return Err(EINVAL),
+    };
+    let file = options.open(&host).map_err(syscalls::io_errno)?;
+
+    Ok(emu.process_mut().open(FileDesc::Host {
+        file: Arc::new(file),
+        path: host,
+    }))
+}
+
+/// `SYS_WRITE` or `SYS_READ`, both return how many bytes were not transferred.
+///
+/// `None` when a read of standard input has to wait for the host.
+fn transfer(emu: &mut Emulator, op: u64, block: u64) -> Option<SysResult> {
+    let fields = || -> Result<[u64; 3], i64> {
+        Ok([
+            syscalls::read_u64(emu, block)?,
+            syscalls::read_u64(emu, block + 8)?,
+            syscalls::read_u64(emu, block + 16)?,
+        ])
+    };
+    let [handle, buf, len] = match fields() {
+        Ok(fields) => fields,
+        Err(errno) => return Some(Err(errno)),
+    };
+
+    let done = match op {
+        SYS_WRITE => syscalls::write(emu, handle, buf, len),
+        _ => syscalls::read(emu, handle, buf, len),
+    };
+    let blocking = matches!(
+        emu.process().file(handle),
+        Some(FileDesc::Stdin { nonblocking: false })
+    );
+    Some(match done {
+        Ok(done) => Ok(len - done),
+        Err(EAGAIN) if blocking => return None,
+        Err(errno) => {
+            emu.process_mut().errno = errno;
+            Ok(len)
+        }
+    })
+}
+
+fn write0(emu: &mut Emulator, addr: u64) -> SysResult {
+    let mut data = Vec::new();
+    for i in 0..WRITE0_MAX as u64 {
+        match syscalls::read_guest(emu, addr + i, 1)?[0] {
+            0 => {
+                emu.console_mut().extend_from_slice(&data);
+                return Ok(0);
+            }
+            byte => data.push(byte),
+        }
+    }
+
+    Err(ERANGE)
+}
+
+/// Write `argv` joined by spaces to the buffer in `block` and its length back to the block.
+fn get_cmdline(emu: &mut Emulator, block: u64) -> SysResult {
+    let buf = syscalls::read_u64(emu, block)?;
+    let len = syscalls::read_u64(emu, block + 8)?;
+    let mut cmdline = emu.process().args.join(" ").into_bytes();
+    if cmdline.len() as u64 >= len {
+        return Err(ERANGE);
+    }
+
+    let cmdline_len = cmdline.len() as u64;
+    cmdline.push(0);
+    syscalls::write_guest(emu, buf, &cmdline)?;
+    syscalls::write_guest(emu, block + 8, &cmdline_len.to_le_bytes())?;
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::machine;
+
+    const BLOCK: u64 = 0x4000_1000;
+    const BUF: u64 = 0x4000_2000;
+
+    /// [`ENTRY`], `ebreak` and [`EXIT`], a semihosting call.
+    const CALL: [u8; 12] = [
+        0x13, 0x10, 0xf0, 0x01, 0x73, 0x00, 0x10, 0x00, 0x13, 0x50, 0x70, 0x40,
+    ];
+
+    fn semihost(emu: &mut Emulator, pc: u64, op: u64, block: &[u64]) -> ExitReason {
+        let data: Vec<u8> = block.iter().flat_map(|field| field.to_le_bytes()).collect();
+        emu.host_write(BLOCK, &data).unwrap();
+        emu.set(Reg::A(0), op);
+        emu.set(Reg::A(1), BLOCK);
+        emu.set_pc(pc);
+        emu.run_for(3)
+    }
+
+    #[test]
+    fn console_cmdline_and_exit() {
+        let (mut emu, pc) = machine(&CALL);
+
+        emu.host_write(BUF, b"hi\0").unwrap();
+        emu.set(Reg::A(0), SYS_WRITE0);
+        emu.set(Reg::A(1), BUF);
+        assert_eq!(ExitReason::BudgetExhausted, emu.run_for(3));
+        assert_eq!(pc + 12, emu.pc());
+
+        emu.host_write(BUF + 16, b":tt").unwrap();
+        semihost(&mut emu, pc, SYS_OPEN, &[BUF + 16, 4, 3]);
+        assert_eq!(1, emu.reg(Reg::A(0)));
+        semihost(&mut emu, pc, SYS_WRITE, &[1, BUF, 2]);
+        assert_eq!(0, emu.reg(Reg::A(0)));
+        assert_eq!(b"hihi", emu.console());
+
+        emu.process_mut().args = vec!["prog".into(), "-v".into()];
+        semihost(&mut emu, pc, SYS_GET_CMDLINE, &[BUF, 64]);
+        assert_eq!(0, emu.reg(Reg::A(0)));
+        assert_eq!(b"prog -v\0", emu.memory(BUF, 8).unwrap().as_ref());
+        assert_eq!(&7u64.to_le_bytes(), &emu.memory(BLOCK + 8, 8).unwrap()[..]);
+
+        assert_eq!(
+            ExitReason::Exit(3),
+            semihost(&mut emu, pc, SYS_EXIT_EXTENDED, &[APPLICATION_EXIT, 3])
+        );
+    }
+
+    #[test]
+    fn read_waits_for_input() {
+        let (mut emu, pc) = machine(&CALL);
+
+        assert_eq!(
+            ExitReason::WaitingForInput,
+            semihost(&mut emu, pc, SYS_READ, &[0, BUF, 4])
+        );
+        assert_eq!(pc + 4, emu.pc());
+
+        emu.push_input(b"ab");
+        assert_eq!(ExitReason::BudgetExhausted, emu.run_for(2));
+        assert_eq!(2, emu.reg(Reg::A(0)));
+        assert_eq!(b"ab", emu.memory(BUF, 2).unwrap().as_ref());
+
+        semihost(&mut emu, pc, SYS_CLOSE, &[7]);
+        assert_eq!(-1, emu.reg_signed(Reg::A(0)));
+        semihost(&mut emu, pc, SYS_ERRNO, &[]);
+        assert_eq!(EBADF as u64, emu.reg(Reg::A(0)));
+    }
+}
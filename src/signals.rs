@@ -0,0 +1,454 @@
+use crate::emulator::{Emulator, ExitReason};
+use crate::memory::{FaultCause, MemoryFault, PAGE_SIZE};
+use crate::primitives::*;
+use crate::process::{Process, SigAction, ThreadState};
+use crate::syscalls::{
+    self, read_u64, write_guest, SysResult, EINTR, EINVAL, ESRCH, MAP_ANONYMOUS, PROT_EXEC,
+    PROT_READ,
+};
+
+/// Signal numbers.
+pub const SIGILL: u64 = 4;
+pub const SIGABRT: u64 = 6;
+pub const SIGBUS: u64 = 7;
+pub const SIGKILL: u64 = 9;
+pub const SIGUSR1: u64 = 10;
+pub const SIGSEGV: u64 = 11;
+pub const SIGUSR2: u64 = 12;
+pub const SIGCHLD: u64 = 17;
+pub const SIGCONT: u64 = 18;
+pub const SIGSTOP: u64 = 19;
+pub const SIGTSTP: u64 = 20;
+pub const SIGTTIN: u64 = 21;
+pub const SIGTTOU: u64 = 22;
+pub const SIGURG: u64 = 23;
+pub const SIGWINCH: u64 = 28;
+const NSIG: u64 = 64;
+
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+pub const SA_NODEFER: u64 = 0x4000_0000;
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+/// `how` of `rt_sigprocmask`.
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+/// `si_code` values.
+pub const SI_USER: i32 = 0;
+pub const ILL_ILLOPC: i32 = 1;
+pub const BUS_ADRALN: i32 = 1;
+pub const SEGV_MAPERR: i32 = 1;
+pub const SEGV_ACCERR: i32 = 2;
+
+/// Layout of `struct rt_sigframe`, a `siginfo_t` followed by a `ucontext_t`.
+const SIGINFO_SIZE: u64 = 128;
+const UC_STACK_FLAGS: u64 = 24;
+const UC_SIGMASK: u64 = 40;
+const UC_MCONTEXT: u64 = 176;
+/// Up to the end of the F and D state after the registers, which stays zero.
+const UCONTEXT_SIZE: u64 = UC_MCONTEXT + 32 * 8 + 528;
+const FRAME_SIZE: u64 = SIGINFO_SIZE + UCONTEXT_SIZE;
+const SS_DISABLE: u32 = 2;
+
+/// `li a7, 139` and `ecall`, where handlers return to call `rt_sigreturn`.
+const TRAMPOLINE: [u32; 2] = [0x08b0_0893, 0x0000_0073];
+
+const fn bit(sig: u64) -> u64 {
+    1 << (sig - 1)
+}
+
+/// Signals that can be neither caught nor blocked.
+const UNBLOCKABLE: u64 = bit(SIGKILL) | bit(SIGSTOP);
+
+/// What happens when a signal is delivered.
+enum Action {
+    Handle(SigAction),
+    Ignore,
+    Terminate,
+}
+
+/// Stop signals are ignored along with the rest, there is no job control.
+fn action(process: &Process, sig: u64) -> Action {
+    let act = process.sigactions[sig as usize - 1];
+    match act.handler {
+        SIG_IGN => Action::Ignore,
+        SIG_DFL => match sig {
+            SIGCHLD | SIGCONT | SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU | SIGURG | SIGWINCH => {
+                Action::Ignore
+            }
+            _ => Action::Terminate,
+        },
+        _ => Action::Handle(act),
+    }
+}
+
+pub(crate) fn sigaction(
+    emu: &mut Emulator,
+    sig: u64,
+    act: u64,
+    oldact: u64,
+    setsize: u64,
+) -> SysResult {
+    if setsize != 8 || !(1..=NSIG).contains(&sig) {
+        return Err(EINVAL);
+    }
+
+    let new = match act {
+        0 => None,
+        _ if UNBLOCKABLE & bit(sig) != 0 => return Err(EINVAL),
+        _ => Some(SigAction {
+            handler: read_u64(emu, act)?,
+            flags: read_u64(emu, act + 8)?,
+            mask: read_u64(emu, act + 16)?,
+        }),
+    };
+    let old = emu.process().sigactions[sig as usize - 1];
+    if oldact != 0 {
+        let words = [old.handler, old.flags, old.mask];
+        write_guest(emu, oldact, &words.map(u64::to_le_bytes).concat())?;
+    }
+    if let Some(new) = new {
+        emu.process_mut().sigactions[sig as usize - 1] = new;
+    }
+
+    Ok(0)
+}
+
+/// Block, unblock or replace the running thread's signal mask.
+pub(crate) fn sigprocmask(
+    emu: &mut Emulator,
+    how: u64,
+    set: u64,
+    oldset: u64,
+    setsize: u64,
+) -> SysResult {
+    if setsize != 8 {
+        return Err(EINVAL);
+    }
+
+    let old = emu.process().thread().sigmask;
+    if set != 0 {
+        let set = read_u64(emu, set)?;
+        let mask = match how {
+            SIG_BLOCK => old | set,
+            SIG_UNBLOCK => old & !set,
+            SIG_SETMASK => set,
+            _ => return Err(EINVAL),
+        };
+        emu.process_mut().thread_mut().sigmask = mask & !UNBLOCKABLE;
+    }
+    if oldset != 0 {
+        write_guest(emu, oldset, &old.to_le_bytes())?;
+    }
+
+    Ok(0)
+}
+
+/// `kill` to the guest's own process, the only one there is.
+pub(crate) fn kill(emu: &mut Emulator, pid: u64, sig: u64) -> SysResult {
+    match pid as i64 {
+        -1..=1 => send(emu, None, sig),
+        _ => Err(ESRCH),
+    }
+}
+
+/// `tgkill`, and `tkill` with the thread group left out.
+pub(crate) fn tgkill(emu: &mut Emulator, tgid: u64, tid: u64, sig: u64) -> SysResult {
+    let thread = emu
+        .process()
+        .threads
+        .iter()
+        .position(|thread| thread.tid == tid && thread.state != ThreadState::Exited);
+    match thread {
+        Some(thread) if tgid == 1 => send(emu, Some(thread), sig),
+        _ => Err(ESRCH),
+    }
+}
+
+/// Make `sig` pending on thread `target`, or the first thread not blocking it, signal 0 only
+/// checks the target exists.
+///
+/// A thread waiting on a futex wakes up to handle it, the wait failing with `EINTR`.
+fn send(emu: &mut Emulator, target: Option<usize>, sig: u64) -> SysResult {
+    if sig > NSIG {
+        return Err(EINVAL);
+    }
+    if sig == 0 {
+        return Ok(0);
+    }
+
+    let process = emu.process_mut();
+    let index = match target {
+        // Nobody handles it and it ends the process, whichever thread gets it.
+        _ if sig == SIGKILL => process.current,
+        Some(index) => index,
+        None => process
+            .threads
+            .iter()
+            .position(|thread| {
+                thread.sigmask & bit(sig) == 0 && thread.state != ThreadState::Exited
+            })
+            .unwrap_or(process.current),
+    };
+    let ignored = matches!(action(process, sig), Action::Ignore);
+    let thread = &mut process.threads[index];
+    thread.pending |= bit(sig);
+    if matches!(thread.state, ThreadState::Futex { .. })
+        && thread.sigmask & bit(sig) == 0
+        && !ignored
+    {
+        thread.state = ThreadState::Runnable;
+        thread.regs[Reg::A(0)] = -EINTR as u64;
+    }
+
+    Ok(0)
+}
+
+/// Deliver the lowest pending signal the running thread does not block, at an instruction
+/// boundary.
+///
+/// Returns the reason to stop if it ends the process.
+pub(crate) fn deliver_pending(emu: &mut Emulator) -> Option<ExitReason> {
+    let thread = emu.process().thread();
+    let ready = thread.pending & !thread.sigmask;
+    if ready == 0 {
+        return None;
+    }
+    let sig = ready.trailing_zeros() as u64 + 1;
+    emu.process_mut().thread_mut().pending &= !bit(sig);
+
+    match action(emu.process(), sig) {
+        Action::Handle(act) => push_frame(emu, sig, SI_USER, 1, act),
+        Action::Ignore => None,
+        Action::Terminate => Some(ExitReason::Signal(sig as i32)),
+    }
+}
+
+/// Run the guest's handler for `sig`, caused by the instruction at pc, which it returns to.
+///
+/// Without a handler, or with the signal blocked, the guest dies with `reason` as it would
+/// have without signals.
+pub(crate) fn raise(
+    emu: &mut Emulator,
+    sig: u64,
+    code: i32,
+    addr: u64,
+    reason: ExitReason,
+) -> Option<ExitReason> {
+    let blocked = emu.process().thread().sigmask & bit(sig) != 0;
+    match action(emu.process(), sig) {
+        Action::Handle(act) if !blocked => push_frame(emu, sig, code, addr, act),
+        _ => Some(reason),
+    }
+}
+
+/// `SIGSEGV`, or `SIGBUS` for a misaligned atomic, for a guest access `fault`, see [`raise`].
+pub(crate) fn raise_fault(emu: &mut Emulator, fault: MemoryFault) -> Option<ExitReason> {
+    let (sig, code) = match fault.cause {
+        FaultCause::Unmapped => (SIGSEGV, SEGV_MAPERR),
+        FaultCause::Permission => (SIGSEGV, SEGV_ACCERR),
+        FaultCause::Misaligned => (SIGBUS, BUS_ADRALN),
+    };
+    raise(emu, sig, code, fault.addr, ExitReason::Fault(fault))
+}
+
+/// Save the registers and mask below `sp` as Linux does and enter the handler with the
+/// signal, `siginfo_t` and `ucontext_t` as arguments.
+///
+/// `field` fills the first word of the union in `siginfo_t`, the faulting address or the
+/// sender's pid. A frame that does not fit kills the guest with `SIGSEGV`.
+fn push_frame(
+    emu: &mut Emulator,
+    sig: u64,
+    code: i32,
+    field: u64,
+    act: SigAction,
+) -> Option<ExitReason> {
+    let Ok(trampoline) = trampoline(emu) else {
+        return Some(ExitReason::Signal(SIGSEGV as i32));
+    };
+    let frame = (emu.reg(Reg::Sp).wrapping_sub(FRAME_SIZE)) & !0xF;
+    let uc = SIGINFO_SIZE as usize;
+
+    let mut data = vec![0; FRAME_SIZE as usize];
+    let mut put = |offset: usize, bytes: &[u8]| {
+        data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    };
+    put(0, &(sig as u32).to_le_bytes());
+    put(8, &code.to_le_bytes());
+    put(16, &field.to_le_bytes());
+    put(uc + UC_STACK_FLAGS as usize, &SS_DISABLE.to_le_bytes());
+    let mask = emu.process().thread().sigmask;
+    put(uc + UC_SIGMASK as usize, &mask.to_le_bytes());
+    // The pc takes the place of x0.
+    let mut regs = emu.regs();
+    regs[0] = emu.pc();
+    for (i, reg) in regs.iter().enumerate() {
+        put(uc + UC_MCONTEXT as usize + i * 8, &reg.to_le_bytes());
+    }
+    if write_guest(emu, frame, &data).is_err() {
+        return Some(ExitReason::Signal(SIGSEGV as i32));
+    }
+
+    let process = emu.process_mut();
+    let thread = process.thread_mut();
+    if act.flags & SA_NODEFER == 0 {
+        thread.sigmask |= bit(sig);
+    }
+    thread.sigmask |= act.mask & !UNBLOCKABLE;
+    if act.flags & SA_RESETHAND != 0 {
+        process.sigactions[sig as usize - 1] = SigAction::default();
+    }
+
+    emu.set(Reg::Sp, frame);
+    emu.set(Reg::A(0), sig);
+    emu.set(Reg::A(1), frame);
+    emu.set(Reg::A(2), frame + SIGINFO_SIZE);
+    emu.set(Reg::Ra, trampoline);
+    emu.set_pc(act.handler);
+
+    None
+}
+
+/// Address of the code handlers return to, mapped on first use like a vDSO.
+fn trampoline(emu: &mut Emulator) -> Result<u64, i64> {
+    if let Some(addr) = emu.process().sigreturn {
+        return Ok(addr);
+    }
+
+    let prot = PROT_READ | PROT_EXEC;
+    let addr = syscalls::mmap(emu, 0, PAGE_SIZE as u64, prot, MAP_ANONYMOUS, u64::MAX, 0)?;
+    let code: Vec<u8> = TRAMPOLINE
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect();
+    emu.host_write(addr, &code).map_err(|_| syscalls::EFAULT)?;
+    emu.process_mut().sigreturn = Some(addr);
+    Ok(addr)
+}
+
+/// Restore the registers and mask saved in the frame at `sp` as a handler returns.
+///
+/// Returns the restored `a0`, the `ecall` moves pc past itself as usual so the saved pc is
+/// set one instruction early.
+pub(crate) fn sigreturn(emu: &mut Emulator) -> Result<i64, ExitReason> {
+    let uc = emu.reg(Reg::Sp) + SIGINFO_SIZE;
+    let saved = syscalls::read_guest(emu, uc + UC_MCONTEXT, 32 * 8)
+        .and_then(|regs| Ok((regs, read_u64(emu, uc + UC_SIGMASK)?)));
+    let Ok((saved, mask)) = saved else {
+        return Err(ExitReason::Signal(SIGSEGV as i32));
+    };
+
+    let mut regs: [u64; 32] =
+        std::array::from_fn(|i| u64::from_le_bytes(saved[i * 8..i * 8 + 8].try_into().unwrap()));
+    let pc = std::mem::take(&mut regs[0]);
+    emu.set_regs(regs);
+    emu.set_pc(pc.wrapping_sub(4));
+    emu.process_mut().thread_mut().sigmask = mask & !UNBLOCKABLE;
+
+    Ok(regs[Reg::A(0)] as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscalls::*;
+    use crate::testing::{syscall, user, ECALL};
+
+    const HANDLER: u64 = 0x4000_0800;
+    const ACT: u64 = 0x4000_1000;
+    const STACK: u64 = 0x4000_8000;
+
+    fn handle(emu: &mut Emulator, sig: u64, mask: u64) {
+        let act = [HANDLER, 0, mask].map(u64::to_le_bytes).concat();
+        emu.host_write(ACT, &act).unwrap();
+        assert_eq!(Ok(0), sigaction(emu, sig, ACT, 0, 8));
+    }
+
+    fn word(emu: &Emulator, addr: u64) -> u64 {
+        u64::from_le_bytes(emu.memory(addr, 8).unwrap().as_ref().try_into().unwrap())
+    }
+
+    #[test]
+    fn handler_runs_and_returns() {
+        let (mut emu, pc) = user(&ECALL);
+        emu.set(Reg::Sp, STACK);
+        handle(&mut emu, SIGUSR1, bit(SIGUSR2));
+
+        emu.set(Reg::T(0), 0x1234);
+        syscall(&mut emu, SYS_KILL, &[1, SIGUSR1]);
+        assert_eq!(HANDLER, emu.pc());
+        let frame = emu.reg(Reg::Sp);
+        assert!(frame < STACK && frame % 16 == 0);
+        assert_eq!(
+            [SIGUSR1, frame, frame + SIGINFO_SIZE],
+            [0, 1, 2].map(|i| emu.reg(Reg::A(i)))
+        );
+        assert_eq!(SIGUSR1, word(&emu, frame) & 0xFFFF_FFFF);
+        assert_eq!(pc + 4, word(&emu, frame + SIGINFO_SIZE + UC_MCONTEXT));
+        assert_eq!(bit(SIGUSR1) | bit(SIGUSR2), emu.process().thread().sigmask);
+
+        // Return straight away.
+        emu.set(Reg::T(0), 0);
+        emu.set_pc(emu.reg(Reg::Ra));
+        emu.run_for(2);
+        assert_eq!(pc + 4, emu.pc());
+        assert_eq!((0, 0x1234), (emu.reg(Reg::A(0)), emu.reg(Reg::T(0))));
+        assert_eq!(STACK, emu.reg(Reg::Sp));
+        assert_eq!(0, emu.process().thread().sigmask);
+    }
+
+    #[test]
+    fn faults_and_illegal_instructions() {
+        let prgm = [
+            0x83, 0x32, 0x03, 0x00, // ld t0, 0(t1)
+            0x00, 0x00, 0x00, 0x00, // illegal
+        ];
+        let (mut emu, pc) = user(&prgm);
+        emu.set(Reg::Sp, STACK);
+        emu.set(Reg::T(1), 0xdead_0000);
+        let mut fault = emu.clone();
+        assert!(matches!(fault.run_for(1), ExitReason::Fault(_)));
+
+        handle(&mut emu, SIGSEGV, 0);
+        emu.run_for(1);
+        assert_eq!(HANDLER, emu.pc());
+        let frame = emu.reg(Reg::Sp);
+        assert_eq!(SEGV_MAPERR as u64, word(&emu, frame + 8) & 0xFFFF_FFFF);
+        assert_eq!(0xdead_0000, word(&emu, frame + 16));
+        assert_eq!(pc, word(&emu, frame + SIGINFO_SIZE + UC_MCONTEXT));
+
+        emu.set_pc(pc + 4);
+        assert_eq!(
+            ExitReason::IllegalInstruction { pc: pc + 4, raw: 0 },
+            emu.run_for(1)
+        );
+    }
+
+    #[test]
+    fn default_actions_and_masks() {
+        let (mut emu, pc) = user(&ECALL);
+        emu.set(Reg::Sp, STACK);
+
+        assert_eq!(
+            ExitReason::BudgetExhausted,
+            syscall(&mut emu, SYS_KILL, &[1, SIGCHLD])
+        );
+        emu.set_pc(pc);
+        emu.host_write(ACT, &bit(SIGABRT).to_le_bytes()).unwrap();
+        syscall(&mut emu, SYS_RT_SIGPROCMASK, &[SIG_BLOCK, ACT, 0, 8]);
+        emu.set_pc(pc);
+        syscall(&mut emu, SYS_TGKILL, &[1, 1, SIGABRT]);
+        assert_eq!(bit(SIGABRT), emu.process().thread().pending);
+        emu.set_pc(pc);
+        syscall(&mut emu, SYS_KILL, &[7, SIGABRT]);
+        assert_eq!(-ESRCH, emu.reg_signed(Reg::A(0)));
+
+        emu.set_pc(pc);
+        let reason = syscall(&mut emu, SYS_RT_SIGPROCMASK, &[SIG_UNBLOCK, ACT, 0, 8]);
+        assert_eq!(ExitReason::Signal(SIGABRT as i32), reason);
+        assert_eq!(Some(134), reason.exit_code());
+    }
+}
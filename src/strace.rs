@@ -0,0 +1,408 @@
+use crate::emulator::{Emulator, ExitReason};
+use crate::sbi::{self, *};
+use crate::syscalls::*;
+use std::fmt::{self, Write as _};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+/// Bytes of a string or buffer argument shown before eliding the rest.
+const SHOWN_BYTES: usize = 32;
+
+/// How an argument is rendered.
+#[derive(Debug, Clone, Copy)]
+enum Arg {
+    Dec,
+    Hex,
+    Fd,
+    /// NUL terminated string.
+    Path,
+    /// Buffer read by the call, its length is the argument at the index.
+    InBuf(usize),
+    /// Buffer filled by the call, as long as the return value.
+    OutBuf,
+}
+
+use Arg::*;
+
+/// Name and arguments of system call `nr`.
+fn signature(nr: u64) -> Option<(&'static str, &'static [Arg])> {
+    Some(match nr {
+        SYS_GETCWD => ("getcwd", &[OutBuf, Dec]),
+        SYS_FCNTL => ("fcntl", &[Fd, Dec, Hex]),
+        SYS_IOCTL => ("ioctl", &[Fd, Hex, Hex]),
+        SYS_OPENAT => ("openat", &[Fd, Path, Hex, Hex]),
+        SYS_CLOSE => ("close", &[Fd]),
+        SYS_LSEEK => ("lseek", &[Fd, Dec, Dec]),
+        SYS_READ => ("read", &[Fd, OutBuf, Dec]),
+        SYS_WRITE => ("write", &[Fd, InBuf(2), Dec]),
+        SYS_READV => ("readv", &[Fd, Hex, Dec]),
+        SYS_WRITEV => ("writev", &[Fd, Hex, Dec]),
+        SYS_NEWFSTATAT => ("newfstatat", &[Fd, Path, Hex, Hex]),
+        SYS_FSTAT => ("fstat", &[Fd, Hex]),
+        SYS_EXIT => ("exit", &[Dec]),
+        SYS_EXIT_GROUP => ("exit_group", &[Dec]),
+        SYS_SET_TID_ADDRESS => ("set_tid_address", &[Hex]),
+        SYS_FUTEX => ("futex", &[Hex, Dec, Dec, Hex, Hex, Dec]),
+        SYS_SET_ROBUST_LIST => ("set_robust_list", &[Hex, Dec]),
+        SYS_CLOCK_GETTIME => ("clock_gettime", &[Dec, Hex]),
+        SYS_SCHED_YIELD => ("sched_yield", &[]),
+        SYS_KILL => ("kill", &[Dec, Dec]),
+        SYS_TKILL => ("tkill", &[Dec, Dec]),
+        SYS_TGKILL => ("tgkill", &[Dec, Dec, Dec]),
+        SYS_RT_SIGACTION => ("rt_sigaction", &[Dec, Hex, Hex, Dec]),
+        SYS_RT_SIGPROCMASK => ("rt_sigprocmask", &[Dec, Hex, Hex, Dec]),
+        SYS_RT_SIGRETURN => ("rt_sigreturn", &[]),
+        SYS_UNAME => ("uname", &[Hex]),
+        SYS_GETPID => ("getpid", &[]),
+        SYS_GETPPID => ("getppid", &[]),
+        SYS_GETUID => ("getuid", &[]),
+        SYS_GETEUID => ("geteuid", &[]),
+        SYS_GETGID => ("getgid", &[]),
+        SYS_GETEGID => ("getegid", &[]),
+        SYS_GETTID => ("gettid", &[]),
+        SYS_BRK => ("brk", &[Hex]),
+        SYS_MUNMAP => ("munmap", &[Hex, Dec]),
+        SYS_CLONE => ("clone", &[Hex, Hex, Hex, Hex, Hex]),
+        SYS_MMAP => ("mmap", &[Hex, Dec, Hex, Hex, Fd, Hex]),
+        SYS_MPROTECT => ("mprotect", &[Hex, Dec, Hex]),
+        SYS_GETRANDOM => ("getrandom", &[Hex, Dec, Hex]),
+        _ => return None,
+    })
+}
+
+/// Name and arguments of function `fid` of SBI extension `ext`.
+fn sbi_signature(ext: u64, fid: u64) -> Option<(&'static str, &'static [Arg])> {
+    Some(match (ext, fid) {
+        (EXT_SET_TIMER, _) => ("sbi_set_timer", &[Dec]),
+        (EXT_CONSOLE_PUTCHAR, _) => ("sbi_console_putchar", &[Hex]),
+        (EXT_CONSOLE_GETCHAR, _) => ("sbi_console_getchar", &[]),
+        (EXT_CLEAR_IPI, _) => ("sbi_clear_ipi", &[]),
+        (EXT_SEND_IPI, _) => ("sbi_send_ipi", &[Hex]),
+        (EXT_REMOTE_FENCE_I, _) => ("sbi_remote_fence_i", &[Hex]),
+        (EXT_REMOTE_SFENCE_VMA, _) => ("sbi_remote_sfence_vma", &[Hex, Hex, Dec]),
+        (EXT_REMOTE_SFENCE_VMA_ASID, _) => ("sbi_remote_sfence_vma_asid", &[Hex, Hex, Dec, Dec]),
+        (EXT_SHUTDOWN, _) => ("sbi_shutdown", &[]),
+        (EXT_BASE, 0) => ("sbi_get_spec_version", &[]),
+        (EXT_BASE, 1) => ("sbi_get_impl_id", &[]),
+        (EXT_BASE, 2) => ("sbi_get_impl_version", &[]),
+        (EXT_BASE, 3) => ("sbi_probe_extension", &[Hex]),
+        (EXT_BASE, 4) => ("sbi_get_mvendorid", &[]),
+        (EXT_BASE, 5) => ("sbi_get_marchid", &[]),
+        (EXT_BASE, 6) => ("sbi_get_mimpid", &[]),
+        (EXT_TIME, 0) => ("sbi_set_timer", &[Dec]),
+        (EXT_IPI, 0) => ("sbi_send_ipi", &[Hex, Hex]),
+        (EXT_RFENCE, 0) => ("sbi_remote_fence_i", &[Hex, Hex]),
+        (EXT_RFENCE, 1) => ("sbi_remote_sfence_vma", &[Hex, Hex, Hex, Dec]),
+        (EXT_RFENCE, 2) => ("sbi_remote_sfence_vma_asid", &[Hex, Hex, Hex, Dec, Dec]),
+        (EXT_HSM, 0) => ("sbi_hart_start", &[Dec, Hex, Hex]),
+        (EXT_HSM, 1) => ("sbi_hart_stop", &[]),
+        (EXT_HSM, 2) => ("sbi_hart_get_status", &[Dec]),
+        (EXT_HSM, 3) => ("sbi_hart_suspend", &[Hex, Hex, Hex]),
+        (EXT_SRST, 0) => ("sbi_system_reset", &[Dec, Dec]),
+        (EXT_DBCN, 0) => ("sbi_debug_console_write", &[Dec, InBuf(0), Hex]),
+        (EXT_DBCN, 1) => ("sbi_debug_console_read", &[Dec, Hex, Hex]),
+        (EXT_DBCN, 2) => ("sbi_debug_console_write_byte", &[Hex]),
+        _ => return None,
+    })
+}
+
+fn sbi_error_name(error: i64) -> Option<&'static str> {
+    Some(match error {
+        -1 => "SBI_ERR_FAILED",
+        ERR_NOT_SUPPORTED => "SBI_ERR_NOT_SUPPORTED",
+        ERR_INVALID_PARAM => "SBI_ERR_INVALID_PARAM",
+        -4 => "SBI_ERR_DENIED",
+        -5 => "SBI_ERR_INVALID_ADDRESS",
+        ERR_ALREADY_AVAILABLE => "SBI_ERR_ALREADY_AVAILABLE",
+        _ => return None,
+    })
+}
+
+fn errno_name(errno: i64) -> Option<&'static str> {
+    Some(match errno {
+        ENOENT => "ENOENT",
+        ESRCH => "ESRCH",
+        EINTR => "EINTR",
+        EIO => "EIO",
+        EBADF => "EBADF",
+        EAGAIN => "EAGAIN",
+        ENOMEM => "ENOMEM",
+        EACCES => "EACCES",
+        EFAULT => "EFAULT",
+        EEXIST => "EEXIST",
+        ENOTDIR => "ENOTDIR",
+        EISDIR => "EISDIR",
+        EINVAL => "EINVAL",
+        ENOTTY => "ENOTTY",
+        ESPIPE => "ESPIPE",
+        ERANGE => "ERANGE",
+        ENOSYS => "ENOSYS",
+        ETIMEDOUT => "ETIMEDOUT",
+        _ => return None,
+    })
+}
+
+/// `data` as a quoted C string, escaping anything unprintable.
+fn quote(out: &mut String, data: &[u8], elided: bool) {
+    out.push('"');
+    for byte in data.iter().take(SHOWN_BYTES) {
+        match byte {
+            b'\n' => out.push_str("\\n"),
+            b'\t' => out.push_str("\\t"),
+            b'\r' => out.push_str("\\r"),
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            0x20..=0x7e => out.push(*byte as char),
+            _ => write!(out, "\\x{byte:02x}").unwrap(),
+        }
+    }
+    out.push('"');
+    if elided || data.len() > SHOWN_BYTES {
+        out.push_str("...");
+    }
+}
+
+/// Guest C string at `addr`, and whether it went on past what is shown.
+fn c_str(emu: &Emulator, addr: u64) -> Option<(Vec<u8>, bool)> {
+    let mut data = Vec::new();
+    for i in 0..=SHOWN_BYTES as u64 {
+        match emu.memory(addr + i, 1).ok()?[0] {
+            0 => return Some((data, false)),
+            byte => data.push(byte),
+        }
+    }
+    Some((data, true))
+}
+
+/// Logs every system call of a user-mode guest like `strace -i`, and every SBI call of a
+/// supervisor-mode one.
+///
+/// Each line shows the address of the `ecall`, the call with its arguments and the result,
+/// errors as `-1` and the `errno` name. Strings and buffers are shown quoted, cut short
+/// after 32 bytes. Clones log to the same place.
+#[derive(Clone)]
+pub struct Strace {
+    out: Arc<Mutex<dyn Write + Send>>,
+}
+
+impl fmt::Debug for Strace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Strace").finish_non_exhaustive()
+    }
+}
+
+impl Strace {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Arc::new(Mutex::new(out)),
+        }
+    }
+
+    pub fn stderr() -> Self {
+        Self::new(std::io::stderr())
+    }
+
+    /// Log call `nr` the current instruction made with `args`, which returned `ret`.
+    pub(crate) fn log(
+        &self,
+        emu: &Emulator,
+        nr: u64,
+        args: &[u64; 6],
+        ret: &Result<i64, ExitReason>,
+    ) {
+        let line = format_call(emu, nr, args, ret);
+        let _ = writeln!(self.out.lock().unwrap(), "{line}");
+    }
+
+    /// Log function `fid` of SBI extension `ext` the current instruction called with
+    /// `args`, which returned `ret`.
+    pub(crate) fn log_sbi(
+        &self,
+        emu: &Emulator,
+        ext: u64,
+        fid: u64,
+        args: &[u64; 6],
+        ret: &sbi::Outcome,
+    ) {
+        let line = format_sbi_call(emu, ext, fid, args, ret);
+        let _ = writeln!(self.out.lock().unwrap(), "{line}");
+    }
+}
+
+fn format_call(emu: &Emulator, nr: u64, args: &[u64; 6], ret: &Result<i64, ExitReason>) -> String {
+    let (name, kinds): (String, &[Arg]) = match signature(nr) {
+        Some((name, kinds)) => (name.into(), kinds),
+        None => (format!("syscall_{nr}"), &[Hex; 6]),
+    };
+    let mut line = format_prefix(emu, &name, kinds, args, ret.as_ref().ok().copied());
+
+    match ret {
+        Ok(ret @ -4095..=-1) => match errno_name(-ret) {
+            Some(name) => write!(line, "-1 {name}").unwrap(),
+            None => write!(line, "-1 errno {}", -ret).unwrap(),
+        },
+        // A thread that exited has no return value either.
+        Ok(_) if nr == SYS_EXIT => line.push('?'),
+        Ok(ret) if matches!(nr, SYS_BRK | SYS_MMAP) => write!(line, "{ret:#x}").unwrap(),
+        Ok(ret) => write!(line, "{ret}").unwrap(),
+        // The process is gone, there is no return value.
+        Err(_) => line.push('?'),
+    }
+
+    line
+}
+
+/// Legacy calls show the value they return in `a0`, the others the value in `a1` or the
+/// error in `a0`.
+fn format_sbi_call(
+    emu: &Emulator,
+    ext: u64,
+    fid: u64,
+    args: &[u64; 6],
+    ret: &sbi::Outcome,
+) -> String {
+    let (name, kinds): (String, &[Arg]) = match sbi_signature(ext, fid) {
+        Some((name, kinds)) => (name.into(), kinds),
+        None => (format!("sbi_{ext:#x}_{fid}"), &[Hex; 6]),
+    };
+    let mut line = format_prefix(emu, &name, kinds, args, None);
+
+    match ret {
+        Ok((ret, None)) => write!(line, "{ret}").unwrap(),
+        Ok((SUCCESS, Some(value))) => write!(line, "{value:#x}").unwrap(),
+        Ok((error, Some(_))) => match sbi_error_name(*error) {
+            Some(name) => line.push_str(name),
+            None => write!(line, "{error}").unwrap(),
+        },
+        // The machine stopped, there is no return value.
+        Err(_) => line.push('?'),
+    }
+
+    line
+}
+
+/// `[pc] name(args) = `, with `ret` giving the length of output buffers.
+fn format_prefix(
+    emu: &Emulator,
+    name: &str,
+    kinds: &[Arg],
+    args: &[u64; 6],
+    ret: Option<i64>,
+) -> String {
+    let mut line = format!("[{}] {name}(", emu.describe(emu.current_pc()));
+
+    for (i, (kind, arg)) in kinds.iter().zip(args).enumerate() {
+        if i > 0 {
+            line.push_str(", ");
+        }
+        match kind {
+            Dec => write!(line, "{}", *arg as i64).unwrap(),
+            Hex => write!(line, "{arg:#x}").unwrap(),
+            Fd if *arg as i64 == -100 => line.push_str("AT_FDCWD"),
+            Fd => write!(line, "{}", *arg as i64).unwrap(),
+            Path => match c_str(emu, *arg) {
+                Some((path, elided)) => quote(&mut line, &path, elided),
+                None => write!(line, "{arg:#x}").unwrap(),
+            },
+            InBuf(_) | OutBuf => {
+                let len = match (kind, ret) {
+                    (InBuf(len), _) => Some(args[*len]),
+                    (_, Some(ret)) => u64::try_from(ret).ok(),
+                    _ => None,
+                };
+                let len = len.map(|len| len.min(SHOWN_BYTES as u64 + 1) as usize);
+                match len.and_then(|len| emu.memory(*arg, len).ok()) {
+                    Some(data) => quote(&mut line, &data, false),
+                    None => write!(line, "{arg:#x}").unwrap(),
+                }
+            }
+        }
+    }
+
+    line.push_str(") = ");
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Privilege;
+    use crate::primitives::Reg;
+    use crate::testing::{machine, syscall, ECALL};
+
+    /// A writer tests can read back.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn logs_calls_and_errors() {
+        let (mut emu, pc) = machine(&ECALL);
+        let log = Shared::default();
+        emu.set_strace(Some(Strace::new(log.clone())));
+        emu.host_write(0x4000_1000, b"hi\n\0/missing\0").unwrap();
+
+        let calls: [(u64, &[u64]); 4] = [
+            (SYS_WRITE, &[1, 0x4000_1000, 3]),
+            (SYS_OPENAT, &[-100i64 as u64, 0x4000_1004, 0]),
+            (4321, &[7]),
+            (SYS_EXIT_GROUP, &[0]),
+        ];
+        for (nr, args) in calls {
+            emu.set_pc(pc);
+            syscall(&mut emu, nr, args);
+        }
+
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(r#"[0x40000000] write(1, "hi\n", 3) = 3"#, lines[0]);
+        assert_eq!(
+            r#"[0x40000000] openat(AT_FDCWD, "/missing", 0x0, 0x0) = -1 ENOENT"#,
+            lines[1]
+        );
+        assert!(lines[2].starts_with("[0x40000000] syscall_4321(0x7, "));
+        assert!(lines[2].ends_with(") = -1 ENOSYS"));
+        assert_eq!("[0x40000000] exit_group(0) = ?", lines[3]);
+    }
+
+    #[test]
+    fn logs_sbi_calls() {
+        let (mut emu, pc) = machine(&ECALL);
+        emu.set_privilege(Privilege::Supervisor);
+        let log = Shared::default();
+        emu.set_strace(Some(Strace::new(log.clone())));
+        emu.host_write(0x4000_1000, b"hi").unwrap();
+
+        let calls: [(u64, u64, &[u64]); 4] = [
+            (EXT_DBCN, 0, &[2, 0x4000_1000, 0]),
+            (EXT_CONSOLE_GETCHAR, 0, &[]),
+            (0x1234, 5, &[7]),
+            (EXT_SRST, 0, &[0, 0]),
+        ];
+        for (ext, fid, args) in calls {
+            emu.set_pc(pc);
+            emu.set(Reg::A(6), fid);
+            syscall(&mut emu, ext, args);
+        }
+
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(
+            r#"[0x40000000] sbi_debug_console_write(2, "hi", 0x0) = 0x2"#,
+            lines[0]
+        );
+        assert_eq!("[0x40000000] sbi_console_getchar() = -1", lines[1]);
+        assert!(lines[2].starts_with("[0x40000000] sbi_0x1234_5(0x7, "));
+        assert!(lines[2].ends_with(") = SBI_ERR_NOT_SUPPORTED"));
+        assert_eq!("[0x40000000] sbi_system_reset(0, 0) = ?", lines[3]);
+    }
+}
@@ -0,0 +1,201 @@
+use crate::elf::ElfError;
+use addr2line::gimli;
+use object::read::elf::ElfFile64;
+use object::{LittleEndian, Object, ObjectSection, ObjectSymbol, SymbolKind};
+use std::fmt;
+
+/// A function or object from `.symtab`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u64,
+    pub size: u64,
+}
+
+/// Source position of an address, from `.debug_line`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: String,
+    pub line: u32,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// Line table row covering `start..start + len`.
+#[derive(Debug, Clone)]
+struct LineRange {
+    start: u64,
+    len: u64,
+    location: Location,
+}
+
+/// Maps guest addresses to symbols and source lines.
+///
+/// Everything is resolved up front so lookups are cheap and the tables can be shared
+/// between forks.
+#[derive(Debug, Clone, Default)]
+pub struct Symbolizer {
+    /// Sorted by address.
+    symbols: Vec<Symbol>,
+    /// Sorted by start address.
+    lines: Vec<LineRange>,
+}
+
+impl Symbolizer {
+    /// Symbols without line info, from a map file or the like.
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|sym| sym.addr);
+        Self {
+            symbols,
+            lines: Vec::new(),
+        }
+    }
+
+    /// Load `.symtab` and, if present, DWARF line info from an ELF64 image.
+    pub fn parse(data: &[u8]) -> Result<Self, ElfError> {
+        let file = ElfFile64::<LittleEndian>::parse(data)?;
+
+        let mut symbols: Vec<Symbol> = file
+            .symbols()
+            .filter(|sym| matches!(sym.kind(), SymbolKind::Text | SymbolKind::Data))
+            .filter_map(|sym| {
+                Some(Symbol {
+                    name: sym.name().ok().filter(|name| !name.is_empty())?.to_owned(),
+                    addr: sym.address(),
+                    size: sym.size(),
+                })
+            })
+            .collect();
+        symbols.sort_by_key(|sym| sym.addr);
+
+        Ok(Self {
+            symbols,
+            lines: Self::parse_lines(&file).unwrap_or_default(),
+        })
+    }
+
+    fn parse_lines(file: &ElfFile64<LittleEndian>) -> Result<Vec<LineRange>, gimli::Error> {
+        let dwarf = gimli::Dwarf::load(|id| {
+            let data = file
+                .section_by_name(id.name())
+                .and_then(|section| section.data().ok())
+                .unwrap_or(&[]);
+            Ok::<_, gimli::Error>(gimli::EndianSlice::new(data, gimli::LittleEndian))
+        })?;
+        let ctx = addr2line::Context::from_dwarf(dwarf)?;
+
+        let mut lines = Vec::new();
+        for (start, len, location) in ctx.find_location_range(0, u64::MAX)? {
+            if let (Some(file), Some(line)) = (location.file, location.line) {
+                lines.push(LineRange {
+                    start,
+                    len,
+                    location: Location {
+                        file: file.to_owned(),
+                        line,
+                    },
+                });
+            }
+        }
+        lines.sort_by_key(|range| range.start);
+
+        Ok(lines)
+    }
+
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    /// Address of the symbol called `name`.
+    pub fn lookup(&self, name: &str) -> Option<u64> {
+        self.symbols
+            .iter()
+            .find(|sym| sym.name == name)
+            .map(|sym| sym.addr)
+    }
+
+    /// Symbol containing `addr` and the offset into it.
+    ///
+    /// Symbols without a size are assumed to extend up to the next symbol.
+    pub fn symbol(&self, addr: u64) -> Option<(&Symbol, u64)> {
+        let preceding = &self.symbols[..self.symbols.partition_point(|sym| sym.addr <= addr)];
+        let sym = preceding
+            .iter()
+            .rev()
+            .find(|sym| sym.size != 0 && addr < sym.addr + sym.size)
+            .or_else(|| preceding.last().filter(|sym| sym.size == 0))?;
+
+        Some((sym, addr - sym.addr))
+    }
+
+    pub fn location(&self, addr: u64) -> Option<&Location> {
+        let index = self.lines.partition_point(|range| range.start <= addr);
+        self.lines[..index]
+            .iter()
+            .rev()
+            .find(|range| addr < range.start + range.len.max(1))
+            .map(|range| &range.location)
+    }
+
+    /// `0x40000010 <main+0x10> at emu.cpp:42`, with whatever parts are known.
+    pub fn describe(&self, addr: u64) -> String {
+        let mut desc = format!("{addr:#x}");
+        if let Some((sym, offset)) = self.symbol(addr) {
+            desc.push_str(&format!(" <{}+{offset:#x}>", sym.name));
+        }
+        if let Some(location) = self.location(addr) {
+            desc.push_str(&format!(" at {location}"));
+        }
+
+        desc
+    }
+}
+
+/// [`Symbolizer::describe`], falling back to the bare address without symbols.
+pub fn describe(symbols: Option<&Symbolizer>, addr: u64) -> String {
+    match symbols {
+        Some(symbols) => symbols.describe(addr),
+        None => format!("{addr:#x}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbol_offsets() {
+        let symbols = Symbolizer {
+            symbols: vec![
+                Symbol {
+                    name: "_start".into(),
+                    addr: 0x1000,
+                    size: 0,
+                },
+                Symbol {
+                    name: "main".into(),
+                    addr: 0x1010,
+                    size: 0x20,
+                },
+            ],
+            lines: vec![LineRange {
+                start: 0x1010,
+                len: 8,
+                location: Location {
+                    file: "emu.cpp".into(),
+                    line: 7,
+                },
+            }],
+        };
+
+        assert_eq!("0x1004 <_start+0x4>", symbols.describe(0x1004));
+        assert_eq!("0x1014 <main+0x4> at emu.cpp:7", symbols.describe(0x1014));
+        assert_eq!("0x1018 <main+0x8>", symbols.describe(0x1018));
+        assert_eq!("0x1030", symbols.describe(0x1030));
+        assert_eq!(Some(0x1010), symbols.lookup("main"));
+    }
+}
@@ -0,0 +1,1014 @@
+use crate::emulator::{Emulator, ExitReason};
+use crate::memory::{AccessKind, Perms, PAGE_SIZE};
+use crate::primitives::*;
+use crate::process::{FileDesc, Process};
+use crate::{signals, threads};
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// System call numbers of the riscv64 Linux ABI, passed in `a7`.
+pub const SYS_GETCWD: u64 = 17;
+pub const SYS_FCNTL: u64 = 25;
+pub const SYS_IOCTL: u64 = 29;
+pub const SYS_OPENAT: u64 = 56;
+pub const SYS_CLOSE: u64 = 57;
+pub const SYS_LSEEK: u64 = 62;
+pub const SYS_READ: u64 = 63;
+pub const SYS_WRITE: u64 = 64;
+pub const SYS_READV: u64 = 65;
+pub const SYS_WRITEV: u64 = 66;
+pub const SYS_NEWFSTATAT: u64 = 79;
+pub const SYS_FSTAT: u64 = 80;
+pub const SYS_EXIT: u64 = 93;
+pub const SYS_EXIT_GROUP: u64 = 94;
+pub const SYS_SET_TID_ADDRESS: u64 = 96;
+pub const SYS_FUTEX: u64 = 98;
+pub const SYS_SET_ROBUST_LIST: u64 = 99;
+pub const SYS_CLOCK_GETTIME: u64 = 113;
+pub const SYS_SCHED_YIELD: u64 = 124;
+pub const SYS_KILL: u64 = 129;
+pub const SYS_TKILL: u64 = 130;
+pub const SYS_TGKILL: u64 = 131;
+pub const SYS_RT_SIGACTION: u64 = 134;
+pub const SYS_RT_SIGPROCMASK: u64 = 135;
+pub const SYS_RT_SIGRETURN: u64 = 139;
+pub const SYS_UNAME: u64 = 160;
+pub const SYS_GETPID: u64 = 172;
+pub const SYS_GETPPID: u64 = 173;
+pub const SYS_GETUID: u64 = 174;
+pub const SYS_GETEUID: u64 = 175;
+pub const SYS_GETGID: u64 = 176;
+pub const SYS_GETEGID: u64 = 177;
+pub const SYS_GETTID: u64 = 178;
+pub const SYS_BRK: u64 = 214;
+pub const SYS_MUNMAP: u64 = 215;
+pub const SYS_CLONE: u64 = 220;
+pub const SYS_MMAP: u64 = 222;
+pub const SYS_MPROTECT: u64 = 226;
+pub const SYS_GETRANDOM: u64 = 278;
+
+/// `errno` values, returned negated in `a0`.
+pub const ENOENT: i64 = 2;
+pub const ESRCH: i64 = 3;
+pub const EINTR: i64 = 4;
+pub const EIO: i64 = 5;
+pub const EBADF: i64 = 9;
+pub const EAGAIN: i64 = 11;
+pub const ENOMEM: i64 = 12;
+pub const EACCES: i64 = 13;
+pub const EFAULT: i64 = 14;
+pub const EEXIST: i64 = 17;
+pub const ENOTDIR: i64 = 20;
+pub const EISDIR: i64 = 21;
+pub const EINVAL: i64 = 22;
+pub const ENOTTY: i64 = 25;
+pub const ESPIPE: i64 = 29;
+pub const ERANGE: i64 = 34;
+pub const ENOSYS: i64 = 38;
+pub const ETIMEDOUT: i64 = 110;
+
+const AT_FDCWD: u64 = -100i64 as u64;
+const AT_SYMLINK_NOFOLLOW: u64 = 0x100;
+const AT_EMPTY_PATH: u64 = 0x1000;
+
+const O_ACCMODE: u64 = 0o3;
+const O_WRONLY: u64 = 0o1;
+const O_RDWR: u64 = 0o2;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+const O_NONBLOCK: u64 = 0o4000;
+const O_DIRECTORY: u64 = 0o200000;
+
+pub(crate) const PROT_READ: u64 = 1;
+const PROT_WRITE: u64 = 2;
+pub(crate) const PROT_EXEC: u64 = 4;
+const MAP_FIXED: u64 = 0x10;
+pub(crate) const MAP_ANONYMOUS: u64 = 0x20;
+
+const F_GETFD: u64 = 1;
+const F_SETFD: u64 = 2;
+const F_GETFL: u64 = 3;
+const F_SETFL: u64 = 4;
+
+const TCGETS: u64 = 0x5401;
+const TIOCGWINSZ: u64 = 0x5413;
+const FIONREAD: u64 = 0x541B;
+const FIONBIO: u64 = 0x5421;
+
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+/// `struct stat` from `asm-generic/stat.h`.
+const STAT_SIZE: usize = 128;
+/// Longest path accepted, including the terminator.
+const PATH_MAX: usize = 4096;
+/// Most bytes staged on the host at once, whatever length the guest passes.
+const CHUNK: usize = 64 * 1024;
+
+/// Value for `a0`, or an `errno`.
+pub(crate) type SysResult = Result<u64, i64>;
+
+/// Service an `ecall` from U-mode as the Linux kernel would.
+///
+/// The number is in `a7` and arguments in `a0..a5`, the result or a negated `errno` goes
+/// back in `a0`. Bad guest pointers fail with `EFAULT` rather than faulting the guest.
+/// Returns the reason to stop if the guest exited, or [`ExitReason::WaitingForInput`] when a
+/// blocking read of standard input has to wait for the host, leaving `a0` alone.
+pub(crate) fn call(emu: &mut Emulator) -> Option<ExitReason> {
+    let nr = emu.reg(Reg::A(7));
+    let args: [u64; 6] = std::array::from_fn(|i| emu.reg(Reg::A(i as u8)));
+
+    match dispatch(emu, nr, args) {
+        Ok(ret) => {
+            emu.set_signed(Reg::A(0), ret);
+            None
+        }
+        Err(reason) => Some(reason),
+    }
+}
+
+/// Run system call `nr`, returning the value for `a0` or the reason to stop, see [`call`].
+///
+/// Logs the call to the emulator's [`Strace`](crate::strace::Strace) if it has one.
+pub(crate) fn dispatch(emu: &mut Emulator, nr: u64, args: [u64; 6]) -> Result<i64, ExitReason> {
+    let ret = execute(emu, nr, args);
+    // A blocked read is logged once it completes.
+    if ret != Err(ExitReason::WaitingForInput) {
+        if let Some(strace) = emu.strace() {
+            strace.log(emu, nr, &args, &ret);
+        }
+    }
+    ret
+}
+
+fn execute(emu: &mut Emulator, nr: u64, args: [u64; 6]) -> Result<i64, ExitReason> {
+    let ret = match nr {
+        SYS_EXIT_GROUP => return Err(ExitReason::Exit(args[0] as i32)),
+        SYS_EXIT => return threads::exit(emu, args[0] as i32),
+
+        SYS_READ => read(emu, args[0], args[1], args[2]),
+        SYS_WRITE => write(emu, args[0], args[1], args[2]),
+        SYS_READV => vectored(emu, args[0], args[1], args[2], read),
+        SYS_WRITEV => vectored(emu, args[0], args[1], args[2], write),
+        SYS_OPENAT => openat(emu, args[0], args[1], args[2]),
+        SYS_CLOSE => match emu.process_mut().close(args[0]) {
+            true => Ok(0),
+            false => Err(EBADF),
+        },
+        SYS_LSEEK => lseek(emu, args[0], args[1] as i64, args[2]),
+        SYS_FSTAT => fstat(emu, args[0], args[1]),
+        SYS_NEWFSTATAT => newfstatat(emu, args[0], args[1], args[2], args[3]),
+        SYS_IOCTL => ioctl(emu, args[0], args[1], args[2]),
+        SYS_FCNTL => fcntl(emu, args[0], args[1], args[2]),
+        SYS_GETCWD => getcwd(emu, args[0], args[1]),
+
+        SYS_BRK => Ok(brk(emu, args[0])),
+        SYS_MMAP => mmap(emu, args[0], args[1], args[2], args[3], args[4], args[5]),
+        SYS_MUNMAP => munmap(emu, args[0], args[1]),
+        SYS_MPROTECT => mprotect(emu, args[0], args[1], args[2]),
+
+        SYS_CLOCK_GETTIME => clock_gettime(emu, args[0], args[1]),
+        SYS_GETRANDOM => getrandom(emu, args[0], args[1]),
+        SYS_UNAME => uname(emu, args[0]),
+
+        SYS_CLONE => threads::clone(emu, args[0], args[1], args[2], args[3], args[4]),
+        SYS_FUTEX => threads::futex(emu, args[0], args[1], args[2], args[3], args[4], args[5]),
+        SYS_SET_TID_ADDRESS => threads::set_tid_address(emu, args[0]),
+
+        SYS_RT_SIGACTION => signals::sigaction(emu, args[0], args[1], args[2], args[3]),
+        SYS_RT_SIGPROCMASK => signals::sigprocmask(emu, args[0], args[1], args[2], args[3]),
+        SYS_RT_SIGRETURN => return signals::sigreturn(emu),
+        SYS_KILL => signals::kill(emu, args[0], args[1]),
+        SYS_TKILL => signals::tgkill(emu, 1, args[0], args[1]),
+        SYS_TGKILL => signals::tgkill(emu, args[0], args[1], args[2]),
+
+        SYS_GETTID => Ok(emu.process().thread().tid),
+        SYS_SCHED_YIELD => {
+            emu.process_mut().reschedule = true;
+            Ok(0)
+        }
+        // The main thread leads the only thread group.
+        SYS_GETPID => Ok(1),
+        SYS_GETPPID => Ok(0),
+        SYS_GETUID | SYS_GETEUID | SYS_GETGID | SYS_GETEGID => Ok(0),
+        SYS_SET_ROBUST_LIST => Ok(0),
+
+        _ => {
+            eprintln!("{}: invalid syscall: {nr}", emu.describe(emu.current_pc()));
+            Err(ENOSYS)
+        }
+    };
+    let blocking = matches!(
+        emu.process().file(args[0]),
+        Some(FileDesc::Stdin { nonblocking: false })
+    );
+    if ret == Err(EAGAIN) && matches!(nr, SYS_READ | SYS_READV) && blocking {
+        return Err(ExitReason::WaitingForInput);
+    }
+
+    Ok(match ret {
+        Ok(val) => val as i64,
+        Err(errno) => -errno,
+    })
+}
+
+pub(crate) fn read_guest(emu: &Emulator, addr: u64, len: usize) -> Result<Vec<u8>, i64> {
+    emu.memory(addr, len)
+        .map(|bytes| bytes.to_vec())
+        .map_err(|_| EFAULT)
+}
+
+/// Check the guest can write `len` bytes at `addr` itself.
+pub(crate) fn writable(emu: &Emulator, addr: u64, len: u64) -> Result<(), i64> {
+    match emu.bus().permissions(addr, len as usize) {
+        Some(perms) if perms.allows(AccessKind::Store) => Ok(()),
+        _ if len == 0 => Ok(()),
+        _ => Err(EFAULT),
+    }
+}
+
+/// Copy `data` out to the guest, which must be able to write there itself.
+pub(crate) fn write_guest(emu: &mut Emulator, addr: u64, data: &[u8]) -> Result<(), i64> {
+    writable(emu, addr, data.len() as u64)?;
+    if data.is_empty() {
+        return Ok(());
+    }
+    emu.host_write(addr, data).map_err(|_| EFAULT)
+}
+
+pub(crate) fn read_u64(emu: &Emulator, addr: u64) -> Result<u64, i64> {
+    let bytes = read_guest(emu, addr, 8)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_c_str(emu: &Emulator, addr: u64) -> Result<String, i64> {
+    let mut bytes = Vec::new();
+    for i in 0..PATH_MAX as u64 {
+        match read_guest(emu, addr + i, 1)?[0] {
+            0 => return String::from_utf8(bytes).map_err(|_| EINVAL),
+            byte => bytes.push(byte),
+        }
+    }
+
+    Err(ERANGE)
+}
+
+pub(crate) fn io_errno(err: std::io::Error) -> i64 {
+    match err.kind() {
+        ErrorKind::NotFound => ENOENT,
+        ErrorKind::PermissionDenied => EACCES,
+        ErrorKind::AlreadyExists => EEXIST,
+        ErrorKind::NotADirectory => ENOTDIR,
+        ErrorKind::IsADirectory => EISDIR,
+        ErrorKind::InvalidInput => EINVAL,
+        _ => EIO,
+    }
+}
+
+pub(crate) fn host_file(process: &Process, fd: u64) -> Result<Arc<File>, i64> {
+    match process.file(fd) {
+        Some(FileDesc::Host { file, .. }) => Ok(file.clone()),
+        Some(_) => Err(ESPIPE),
+        None => Err(EBADF),
+    }
+}
+
+pub(crate) fn read(emu: &mut Emulator, fd: u64, buf: u64, len: u64) -> SysResult {
+    match emu.process().file(fd) {
+        // Whatever input there is, like a terminal, `EAGAIN` until the host sends some.
+        Some(FileDesc::Stdin { .. }) => {
+            if len == 0 {
+                return Ok(0);
+            }
+            let data = emu.take_input(len as usize);
+            if data.is_empty() && !emu.input_closed() {
+                return Err(EAGAIN);
+            }
+            write_guest(emu, buf, &data)?;
+            Ok(data.len() as u64)
+        }
+        // Nothing is ever written to the guest's output, it looks like end of file.
+        Some(FileDesc::Console) => Ok(0),
+        Some(FileDesc::Host { file, .. }) => {
+            let file = file.clone();
+            writable(emu, buf, len)?;
+            let mut data = vec![0; CHUNK.min(len as usize)];
+            let mut total = 0;
+            while total < len {
+                let want = (len - total).min(CHUNK as u64) as usize;
+                let read = (&*file).read(&mut data[..want]).map_err(io_errno)?;
+                write_guest(emu, buf + total, &data[..read])?;
+                total += read as u64;
+                if read < want {
+                    break;
+                }
+            }
+            Ok(total)
+        }
+        None => Err(EBADF),
+    }
+}
+
+pub(crate) fn write(emu: &mut Emulator, fd: u64, buf: u64, len: u64) -> SysResult {
+    let desc = emu.process().file(fd).cloned().ok_or(EBADF)?;
+    emu.check_syscall_buf("write(buf)", buf, len as usize);
+    let data = read_guest(emu, buf, len as usize)?;
+    match desc {
+        FileDesc::Stdin { .. } => Err(EBADF),
+        FileDesc::Console => {
+            emu.console_mut().extend_from_slice(&data);
+            Ok(len)
+        }
+        FileDesc::Host { file, .. } => (&*file).write(&data).map(|n| n as u64).map_err(io_errno),
+    }
+}
+
+/// `readv` and `writev` in terms of `read` and `write`, stopping at the first short transfer.
+///
+/// An error after some data was transferred ends the call early instead of failing it.
+fn vectored(
+    emu: &mut Emulator,
+    fd: u64,
+    iov: u64,
+    count: u64,
+    op: fn(&mut Emulator, u64, u64, u64) -> SysResult,
+) -> SysResult {
+    let mut total = 0;
+    for i in 0..count {
+        let base = read_u64(emu, iov + i * 16)?;
+        let len = read_u64(emu, iov + i * 16 + 8)?;
+        let done = match op(emu, fd, base, len) {
+            Ok(done) => done,
+            Err(errno) if total == 0 => return Err(errno),
+            Err(_) => break,
+        };
+        total += done;
+        if done < len {
+            break;
+        }
+    }
+
+    Ok(total)
+}
+
+/// Guest path of `path` relative to the directory `dirfd`.
+fn guest_path(process: &Process, dirfd: u64, path: &str) -> Result<String, i64> {
+    if path.starts_with('/') || dirfd == AT_FDCWD {
+        return Ok(path.to_owned());
+    }
+
+    match process.file(dirfd) {
+        Some(FileDesc::Host { path: dir, .. }) => {
+            let root = process.root().ok_or(EBADF)?;
+            let dir = dir.strip_prefix(root).map_err(|_| EBADF)?;
+            Ok(format!("/{}/{path}", dir.display()))
+        }
+        Some(_) => Err(ENOTDIR),
+        None => Err(EBADF),
+    }
+}
+
+fn openat(emu: &mut Emulator, dirfd: u64, path: u64, flags: u64) -> SysResult {
+    let path = read_c_str(emu, path)?;
+    let path = guest_path(emu.process(), dirfd, &path)?;
+    let host = emu.process().resolve(&path).ok_or(ENOENT)?;
+
+    let mut options = OpenOptions::new();
+    match flags & O_ACCMODE {
+        O_WRONLY => options.write(true),
+        O_RDWR => options.read(true).write(true),
+        _ => options.read(true),
+    };
+    options
+        .append(flags & O_APPEND != 0)
+        .truncate(flags & O_TRUNC != 0)
+        .create(flags & O_CREAT != 0)
+        .create_new(flags & O_CREAT != 0 && flags & O_EXCL != 0);
+    let file = options.open(&host).map_err(io_errno)?;
+    if flags & O_DIRECTORY != 0 && !file.metadata().map_err(io_errno)?.is_dir() {
+        return Err(ENOTDIR);
+    }
+
+    Ok(emu.process_mut().open(FileDesc::Host {
+        file: Arc::new(file),
+        path: host,
+    }))
+}
+
+pub(crate) fn lseek(emu: &mut Emulator, fd: u64, offset: i64, whence: u64) -> SysResult {
+    let file = host_file(emu.process(), fd)?;
+    let pos = match whence {
+        0 => SeekFrom::Start(u64::try_from(offset).map_err(|_| EINVAL)?),
+        1 => SeekFrom::Current(offset),
+        2 => SeekFrom::End(offset),
+        _ => return Err(EINVAL),
+    };
+    (&*file).seek(pos).map_err(io_errno)
+}
+
+/// `struct stat` for a file with `mode`, `size` bytes long and last modified at `mtime`.
+fn stat(mode: u32, size: u64, rdev: u64, mtime: SystemTime) -> [u8; STAT_SIZE] {
+    let mtime = mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    let mut stat = [0; STAT_SIZE];
+    stat[16..20].copy_from_slice(&mode.to_le_bytes());
+    stat[20..24].copy_from_slice(&1u32.to_le_bytes()); // st_nlink
+    stat[32..40].copy_from_slice(&rdev.to_le_bytes());
+    stat[48..56].copy_from_slice(&size.to_le_bytes());
+    stat[56..60].copy_from_slice(&(PAGE_SIZE as u32).to_le_bytes()); // st_blksize
+    stat[64..72].copy_from_slice(&size.div_ceil(512).to_le_bytes()); // st_blocks
+    for time in [72, 88, 104] {
+        stat[time..time + 8].copy_from_slice(&mtime.as_secs().to_le_bytes());
+        stat[time + 8..time + 16].copy_from_slice(&(mtime.subsec_nanos() as u64).to_le_bytes());
+    }
+
+    stat
+}
+
+fn host_stat(metadata: &Metadata) -> [u8; STAT_SIZE] {
+    let mode = match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, _) => S_IFDIR | 0o755,
+        (false, false) => S_IFREG | 0o644,
+        (false, true) => S_IFREG | 0o444,
+    };
+    let mtime = metadata.modified().unwrap_or(UNIX_EPOCH);
+
+    stat(mode, metadata.len(), 0, mtime)
+}
+
+fn fstat(emu: &mut Emulator, fd: u64, buf: u64) -> SysResult {
+    let stat = match emu.process().file(fd) {
+        // A pseudo terminal, major 136.
+        Some(FileDesc::Stdin { .. } | FileDesc::Console) => {
+            stat(S_IFCHR | 0o620, 0, 136 << 8, UNIX_EPOCH)
+        }
+        Some(FileDesc::Host { file, .. }) => host_stat(&file.metadata().map_err(io_errno)?),
+        None => return Err(EBADF),
+    };
+    write_guest(emu, buf, &stat).map(|_| 0)
+}
+
+fn newfstatat(emu: &mut Emulator, dirfd: u64, path: u64, buf: u64, flags: u64) -> SysResult {
+    let path = read_c_str(emu, path)?;
+    if path.is_empty() && flags & AT_EMPTY_PATH != 0 {
+        return fstat(emu, dirfd, buf);
+    }
+
+    let path = guest_path(emu.process(), dirfd, &path)?;
+    let host = emu.process().resolve(&path).ok_or(ENOENT)?;
+    let metadata = match flags & AT_SYMLINK_NOFOLLOW {
+        0 => host.metadata(),
+        _ => host.symlink_metadata(),
+    };
+    let stat = host_stat(&metadata.map_err(io_errno)?);
+    write_guest(emu, buf, &stat).map(|_| 0)
+}
+
+fn ioctl(emu: &mut Emulator, fd: u64, request: u64, arg: u64) -> SysResult {
+    match emu.process().file(fd) {
+        Some(FileDesc::Stdin { .. } | FileDesc::Console) => {}
+        Some(FileDesc::Host { .. }) => return Err(ENOTTY),
+        None => return Err(EBADF),
+    }
+
+    match request {
+        // `struct termios` with every flag clear, a raw terminal.
+        TCGETS => write_guest(emu, arg, &[0; 36]).map(|_| 0),
+        // `struct winsize`, 25 rows of 80 columns.
+        TIOCGWINSZ => write_guest(emu, arg, &[25, 0, 80, 0, 0, 0, 0, 0]).map(|_| 0),
+        FIONREAD => {
+            let waiting = match emu.process().file(fd) {
+                Some(FileDesc::Stdin { .. }) => emu.pending_input() as u32,
+                _ => 0,
+            };
+            write_guest(emu, arg, &waiting.to_le_bytes()).map(|_| 0)
+        }
+        FIONBIO => {
+            let on = read_guest(emu, arg, 4)? != [0; 4];
+            set_nonblocking(emu, fd, on);
+            Ok(0)
+        }
+        _ => Err(ENOTTY),
+    }
+}
+
+fn set_nonblocking(emu: &mut Emulator, fd: u64, on: bool) {
+    if let Some(FileDesc::Stdin { nonblocking }) = emu.process_mut().file_mut(fd) {
+        *nonblocking = on;
+    }
+}
+
+/// Descriptor and status flags, only `O_NONBLOCK` on standard input changes anything.
+fn fcntl(emu: &mut Emulator, fd: u64, cmd: u64, arg: u64) -> SysResult {
+    let flags = match emu.process().file(fd).ok_or(EBADF)? {
+        FileDesc::Stdin { nonblocking: true } => O_NONBLOCK,
+        FileDesc::Stdin { .. } => 0,
+        FileDesc::Console => O_WRONLY,
+        // The access mode is not kept, report the most permissive one.
+        FileDesc::Host { .. } => O_RDWR,
+    };
+
+    match cmd {
+        F_GETFD | F_SETFD => Ok(0),
+        F_GETFL => Ok(flags),
+        F_SETFL => {
+            set_nonblocking(emu, fd, arg & O_NONBLOCK != 0);
+            Ok(0)
+        }
+        _ => Err(EINVAL),
+    }
+}
+
+fn getcwd(emu: &mut Emulator, buf: u64, size: u64) -> SysResult {
+    if size < 2 {
+        return Err(ERANGE);
+    }
+    write_guest(emu, buf, b"/\0").map(|_| 2)
+}
+
+/// Move the break to `addr` if it stays clear of the mappings, returning the break.
+///
+/// Memory between the old and new break is zeroed as it is handed out.
+fn brk(emu: &mut Emulator, addr: u64) -> u64 {
+    let process = emu.process();
+    let (start, old, limit) = (process.brk_start, process.brk, process.mmap_bottom);
+    if addr < start || addr > limit {
+        return old;
+    }
+
+    if addr > old
+        && emu
+            .host_write(old, &vec![0; (addr - old) as usize])
+            .is_err()
+    {
+        return old;
+    }
+    emu.process_mut().brk = addr;
+
+    addr
+}
+
+fn perms(prot: u64) -> Perms {
+    Perms::new(
+        prot & PROT_READ != 0,
+        prot & PROT_WRITE != 0,
+        prot & PROT_EXEC != 0,
+    )
+}
+
+/// Anonymous and private file mappings, carved downwards from the top of the heap.
+///
+/// Mapped memory is a snapshot of the file, writes are never written back.
+pub(crate) fn mmap(
+    emu: &mut Emulator,
+    addr: u64,
+    len: u64,
+    prot: u64,
+    flags: u64,
+    fd: u64,
+    offset: u64,
+) -> SysResult {
+    if len == 0 || !offset.is_multiple_of(PAGE_SIZE as u64) {
+        return Err(EINVAL);
+    }
+    let len = len
+        .checked_next_multiple_of(PAGE_SIZE as u64)
+        .ok_or(ENOMEM)?;
+
+    let process = emu.process_mut();
+    let saved = (process.mmap_bottom, process.unmapped.clone());
+    let start = if flags & MAP_FIXED != 0 {
+        let end = addr.checked_add(len).ok_or(EINVAL)?;
+        let heap = &process.heap;
+        if !addr.is_multiple_of(PAGE_SIZE as u64) || addr < heap.start || end > heap.end {
+            return Err(EINVAL);
+        }
+        claim(process, addr..end);
+        addr
+    } else {
+        place(process, len)?
+    };
+
+    let file = (flags & MAP_ANONYMOUS == 0).then_some(fd);
+    if let Err(errno) = map(emu, start..start + len, file, offset, perms(prot)) {
+        let process = emu.process_mut();
+        (process.mmap_bottom, process.unmapped) = saved;
+        return Err(errno);
+    }
+
+    Ok(start)
+}
+
+/// Room for `len` bytes, in the lowest hole `munmap` left or else below every mapping.
+fn place(process: &mut Process, len: u64) -> Result<u64, i64> {
+    if let Some(hole) = process
+        .unmapped
+        .iter_mut()
+        .find(|hole| hole.end - hole.start >= len)
+    {
+        let start = hole.start;
+        hole.start += len;
+        process.unmapped.retain(|hole| !hole.is_empty());
+        return Ok(start);
+    }
+
+    let brk = process.brk.next_multiple_of(PAGE_SIZE as u64);
+    let start = process.mmap_bottom.checked_sub(len).ok_or(ENOMEM)?;
+    if start < brk {
+        return Err(ENOMEM);
+    }
+    process.mmap_bottom = start;
+    Ok(start)
+}
+
+/// Holes in `holes` with `range` cut out of them.
+fn without(holes: &[Range<u64>], range: &Range<u64>) -> Vec<Range<u64>> {
+    holes
+        .iter()
+        .flat_map(|hole| {
+            [
+                hole.start..hole.end.min(range.start),
+                hole.start.max(range.end)..hole.end,
+            ]
+        })
+        .filter(|hole| !hole.is_empty())
+        .collect()
+}
+
+/// Account for a `MAP_FIXED` mapping at `range`, which may sit below the others.
+fn claim(process: &mut Process, range: Range<u64>) {
+    process.unmapped = without(&process.unmapped, &range);
+    if range.start < process.mmap_bottom {
+        if range.end < process.mmap_bottom {
+            process.unmapped.insert(0, range.end..process.mmap_bottom);
+        }
+        process.mmap_bottom = range.start;
+    }
+}
+
+/// Zero `range`, or fill it from the host file `fd` at `offset`, and protect it.
+fn map(
+    emu: &mut Emulator,
+    range: Range<u64>,
+    fd: Option<u64>,
+    offset: u64,
+    perms: Perms,
+) -> Result<(), i64> {
+    let file = match fd {
+        Some(fd) => Some(host_file(emu.process(), fd).map_err(|_| EBADF)?),
+        None => None,
+    };
+    let mut reader = file.as_deref();
+    let pos = match &mut reader {
+        Some(reader) => {
+            let pos = reader.stream_position().map_err(io_errno)?;
+            reader.seek(SeekFrom::Start(offset)).map_err(io_errno)?;
+            pos
+        }
+        None => 0,
+    };
+
+    let mut data = vec![0; CHUNK.min((range.end - range.start) as usize)];
+    let mut copy = || -> Result<(), i64> {
+        for start in range.clone().step_by(CHUNK) {
+            let data = &mut data[..(range.end - start).min(CHUNK as u64) as usize];
+            data.fill(0);
+            if let Some(reader) = &mut reader {
+                let mut filled = 0;
+                while filled < data.len() {
+                    match reader.read(&mut data[filled..]).map_err(io_errno)? {
+                        0 => break,
+                        n => filled += n,
+                    }
+                }
+            }
+            emu.host_write(start, data).map_err(|_| ENOMEM)?;
+        }
+        Ok(())
+    };
+    let copied = copy();
+    if let Some(reader) = &mut reader {
+        reader.seek(SeekFrom::Start(pos)).map_err(io_errno)?;
+    }
+    copied?;
+
+    protect(emu, range.start, range.end - range.start, perms)
+}
+
+/// Unmap the pages of `addr..addr + len` that `mmap` handed out, they fault until mapped
+/// again.
+fn munmap(emu: &mut Emulator, addr: u64, len: u64) -> SysResult {
+    if !addr.is_multiple_of(PAGE_SIZE as u64) || len == 0 {
+        return Err(EINVAL);
+    }
+    let end = len
+        .checked_next_multiple_of(PAGE_SIZE as u64)
+        .and_then(|len| addr.checked_add(len))
+        .ok_or(EINVAL)?;
+
+    let process = emu.process_mut();
+    let range = addr.max(process.mmap_bottom)..end.min(process.heap.end);
+    if range.is_empty() {
+        return Ok(0);
+    }
+
+    let mut holes = without(&process.unmapped, &range);
+    holes.push(range.clone());
+    holes.sort_by_key(|hole| hole.start);
+    let mut merged: Vec<Range<u64>> = Vec::new();
+    for hole in holes {
+        match merged.last_mut() {
+            Some(last) if last.end == hole.start => last.end = hole.end,
+            _ => merged.push(hole),
+        }
+    }
+    // A hole at the bottom is just more room below the mappings.
+    if merged[0].start == process.mmap_bottom {
+        process.mmap_bottom = merged.remove(0).end;
+    }
+    process.unmapped = merged;
+
+    protect(emu, range.start, range.end - range.start, Perms::NONE).map(|_| 0)
+}
+
+fn mprotect(emu: &mut Emulator, addr: u64, len: u64, prot: u64) -> SysResult {
+    if !addr.is_multiple_of(PAGE_SIZE as u64) {
+        return Err(EINVAL);
+    }
+    let len = len
+        .checked_next_multiple_of(PAGE_SIZE as u64)
+        .ok_or(ENOMEM)?;
+    protect(emu, addr, len, perms(prot)).map(|_| 0)
+}
+
+/// Protect `addr..addr + len` piece by piece across the regions it spans.
+fn protect(emu: &mut Emulator, addr: u64, len: u64, perms: Perms) -> Result<(), i64> {
+    let end = addr.checked_add(len).ok_or(ENOMEM)?;
+    let pieces: Vec<_> = emu
+        .guest_memory()
+        .regions()
+        .iter()
+        .map(|region| region.range.start.max(addr)..region.range.end.min(end))
+        .filter(|piece| !piece.is_empty())
+        .collect();
+    if pieces
+        .iter()
+        .map(|piece| piece.end - piece.start)
+        .sum::<u64>()
+        != len
+    {
+        return Err(ENOMEM);
+    }
+
+    for piece in pieces {
+        emu.bus_mut().memory_mut().protect(piece, perms);
+    }
+
+    Ok(())
+}
+
+fn getrandom(emu: &mut Emulator, buf: u64, len: u64) -> SysResult {
+    writable(emu, buf, len)?;
+    let mut data = vec![0; CHUNK.min(len as usize)];
+    for start in (0..len).step_by(CHUNK) {
+        let data = &mut data[..(len - start).min(CHUNK as u64) as usize];
+        emu.fill_random(data);
+        write_guest(emu, buf + start, data)?;
+    }
+    Ok(len)
+}
+
+fn clock_gettime(emu: &mut Emulator, clock: u64, tp: u64) -> SysResult {
+    let time = match clock {
+        // CLOCK_REALTIME and CLOCK_REALTIME_COARSE.
+        0 | 5 => emu.wall_time(),
+        // Monotonic, boot time and CPU time clocks all count from the start.
+        1..=4 | 6 | 7 => emu.uptime(),
+        _ => return Err(EINVAL),
+    };
+
+    let mut timespec = [0; 16];
+    timespec[..8].copy_from_slice(&time.as_secs().to_le_bytes());
+    timespec[8..].copy_from_slice(&(time.subsec_nanos() as u64).to_le_bytes());
+    write_guest(emu, tp, &timespec).map(|_| 0)
+}
+
+fn uname(emu: &mut Emulator, buf: u64) -> SysResult {
+    const FIELD_LEN: usize = 65;
+
+    let fields = ["Linux", "emu", "6.1.0", "#1", "riscv64", "(none)"];
+    let mut utsname = [0; FIELD_LEN * 6];
+    for (i, field) in fields.iter().enumerate() {
+        utsname[i * FIELD_LEN..i * FIELD_LEN + field.len()].copy_from_slice(field.as_bytes());
+    }
+    write_guest(emu, buf, &utsname).map(|_| 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{user, TempDir, ECALL};
+
+    /// Run the `ecall` at pc, staying there, and return `a0`.
+    fn syscall(emu: &mut Emulator, nr: u64, args: &[u64]) -> i64 {
+        let pc = emu.pc();
+        crate::testing::syscall(emu, nr, args);
+        emu.set_pc(pc);
+        emu.reg_signed(Reg::A(0))
+    }
+
+    #[test]
+    fn files_in_sandbox() {
+        let root = TempDir::new("syscall");
+        std::fs::write(root.path().join("hello.txt"), "hello world").unwrap();
+        let (mut emu, _) = user(&ECALL);
+        emu.process_mut().set_root(Some(root.path().to_path_buf()));
+
+        let (path, buf) = (0x4000_1000, 0x4000_2000);
+        emu.flash_prgm(b"/../hello.txt\0", path).unwrap();
+        emu.set_pc(0x4000_0000);
+        let fd = syscall(&mut emu, SYS_OPENAT, &[AT_FDCWD, path, 0]);
+        assert_eq!(3, fd);
+
+        assert_eq!(6, syscall(&mut emu, SYS_LSEEK, &[3, 6, 0]));
+        assert_eq!(5, syscall(&mut emu, SYS_READ, &[3, buf, 16]));
+        assert_eq!(b"world", emu.memory(buf, 5).unwrap().as_ref());
+
+        assert_eq!(0, syscall(&mut emu, SYS_FSTAT, &[3, buf]));
+        assert_eq!(&11u64.to_le_bytes(), &emu.memory(buf + 48, 8).unwrap()[..]);
+
+        assert_eq!(0, syscall(&mut emu, SYS_CLOSE, &[3]));
+        assert_eq!(-EBADF, syscall(&mut emu, SYS_CLOSE, &[3]));
+
+        emu.flash_prgm(b"/missing\0", path).unwrap();
+        emu.set_pc(0x4000_0000);
+        assert_eq!(-ENOENT, syscall(&mut emu, SYS_OPENAT, &[AT_FDCWD, path, 0]));
+    }
+
+    /// What a libc runs before `main`: fences, a lock taken with `lr`/`sc` and released with
+    /// `amoswap`, an atomic counter, then output and `exit_group`.
+    #[test]
+    fn libc_start_up_sequence() {
+        let prgm = [
+            0x37, 0x14, 0x00, 0x40, // lui s0, 0x40001
+            0x0f, 0x00, 0x30, 0x03, // fence rw, rw
+            0xaf, 0x22, 0x04, 0x14, // 1: lr.w.aq t0, (s0)
+            0xe3, 0x9e, 0x02, 0xfe, // bnez t0, 1b
+            0x13, 0x03, 0x10, 0x00, // li t1, 1
+            0xaf, 0x23, 0x64, 0x1a, // sc.w.rl t2, t1, (s0)
+            0xe3, 0x98, 0x03, 0xfe, // bnez t2, 1b
+            0x93, 0x05, 0x84, 0x00, // addi a1, s0, 8
+            0x13, 0x03, 0x50, 0x00, // li t1, 5
+            0x2f, 0xb0, 0x65, 0x00, // amoadd.d zero, t1, (a1)
+            0x2f, 0x20, 0x04, 0x0a, // amoswap.w.rl zero, zero, (s0)
+            0x0f, 0x10, 0x00, 0x00, // fence.i
+            0x13, 0x05, 0x10, 0x00, // li a0, 1
+            0x93, 0x05, 0x04, 0x01, // addi a1, s0, 16
+            0x13, 0x06, 0x30, 0x00, // li a2, 3
+            0x93, 0x08, 0x00, 0x04, // li a7, SYS_WRITE
+            0x73, 0x00, 0x00, 0x00, // ecall
+            0x03, 0x35, 0x84, 0x00, // ld a0, 8(s0)
+            0x93, 0x08, 0xe0, 0x05, // li a7, SYS_EXIT_GROUP
+            0x73, 0x00, 0x00, 0x00, // ecall
+        ];
+        let (mut emu, pc) = user(&prgm);
+        let mut data = [0; 19];
+        data[8] = 0x10;
+        data[16..].copy_from_slice(b"ok\n");
+        emu.flash_prgm(&data, 0x4000_1000).unwrap();
+        emu.set_pc(pc);
+
+        assert_eq!(ExitReason::Exit(0x15), emu.run_for(100));
+        assert_eq!(b"ok\n", emu.console());
+        assert_eq!(&[0; 4], &emu.memory(0x4000_1000, 4).unwrap()[..]);
+    }
+
+    #[test]
+    fn console_and_memory() {
+        let (mut emu, _) = user(&ECALL);
+
+        emu.flash_prgm(b"ok\n", 0x4000_1000).unwrap();
+        emu.set_pc(0x4000_0000);
+        assert_eq!(3, syscall(&mut emu, SYS_WRITE, &[1, 0x4000_1000, 3]));
+        assert_eq!(b"ok\n", emu.console());
+        assert_eq!(-EFAULT, syscall(&mut emu, SYS_WRITE, &[1, 0xF000_0000, 3]));
+
+        assert_eq!(0x4400_0000, syscall(&mut emu, SYS_BRK, &[0]));
+        assert_eq!(0x4400_2000, syscall(&mut emu, SYS_BRK, &[0x4400_2000]));
+
+        let anon = syscall(
+            &mut emu,
+            SYS_MMAP,
+            &[0, 0x1800, 3, MAP_ANONYMOUS, -1i64 as u64, 0],
+        );
+        assert_eq!(0x47FF_E000, anon);
+        assert_eq!(
+            0,
+            syscall(&mut emu, SYS_MPROTECT, &[anon as u64, 0x1000, 1])
+        );
+        assert_eq!(
+            Perms::R,
+            emu.guest_memory().region_at(anon as u64, 8).unwrap().perms
+        );
+
+        assert_eq!(0, syscall(&mut emu, SYS_UNAME, &[0x4000_1000]));
+        assert_eq!(b"Linux\0", emu.memory(0x4000_1000, 6).unwrap().as_ref());
+        assert_eq!(-ENOSYS, syscall(&mut emu, 1234, &[]));
+        syscall(&mut emu, SYS_EXIT_GROUP, &[7]);
+        assert_eq!(Some(ExitReason::Exit(7)), emu.exit_reason());
+    }
+
+    #[test]
+    fn mappings_reuse_holes() {
+        let (mut emu, _) = user(&ECALL);
+        let anon = |emu: &mut Emulator, len: u64| {
+            syscall(emu, SYS_MMAP, &[0, len, 3, MAP_ANONYMOUS, -1i64 as u64, 0])
+        };
+        let perms =
+            |emu: &Emulator, addr: i64| emu.guest_memory().region_at(addr as u64, 8).unwrap().perms;
+
+        let (a, b, c) = (anon(&mut emu, 1), anon(&mut emu, 1), anon(&mut emu, 1));
+        assert_eq!([0x47FF_F000, 0x47FF_E000, 0x47FF_D000], [a, b, c]);
+        assert_eq!(0, syscall(&mut emu, SYS_MUNMAP, &[b as u64, 0x1000]));
+        assert_eq!(Perms::NONE, perms(&emu, b));
+        assert_eq!(b, anon(&mut emu, 0x1000));
+        assert_eq!(Perms::RW, perms(&emu, b));
+
+        // Unmapping the lowest mapping makes room below the others again.
+        assert_eq!(0, syscall(&mut emu, SYS_MUNMAP, &[c as u64, 0x1000]));
+        assert_eq!(0x47FF_C000, anon(&mut emu, 0x2000));
+
+        // Failed calls leave the mappings as they were.
+        let file = [0, 0x1000, 3, 0, 99, 0];
+        assert_eq!(-EBADF, syscall(&mut emu, SYS_MMAP, &file));
+        assert_eq!(-ENOMEM, anon(&mut emu, u64::MAX));
+        let fixed = [
+            -0x1000i64 as u64,
+            0x2000,
+            3,
+            MAP_FIXED | MAP_ANONYMOUS,
+            0,
+            0,
+        ];
+        assert_eq!(-EINVAL, syscall(&mut emu, SYS_MMAP, &fixed));
+        assert_eq!(0x47FF_B000, anon(&mut emu, 0x1000));
+
+        assert_eq!(
+            -ENOMEM,
+            syscall(&mut emu, SYS_MPROTECT, &[a as u64, u64::MAX, 1])
+        );
+        assert_eq!(
+            -EINVAL,
+            syscall(&mut emu, SYS_MUNMAP, &[a as u64, u64::MAX])
+        );
+        assert_eq!(
+            -EFAULT,
+            syscall(&mut emu, SYS_GETRANDOM, &[a as u64, 1 << 40, 0])
+        );
+        assert_eq!(
+            0x2_0000,
+            syscall(
+                &mut emu,
+                SYS_GETRANDOM,
+                &[0x4400_0000 - 0x2_0000, 0x2_0000, 0]
+            )
+        );
+    }
+
+    #[test]
+    fn stdin_waits_for_host() {
+        let (mut emu, _) = user(&ECALL);
+        let buf = 0x4000_1000;
+        emu.set(Reg::A(7), SYS_READ);
+        for (i, arg) in [0, buf, 16].into_iter().enumerate() {
+            emu.set(Reg::A(i as u8), arg);
+        }
+        assert_eq!(ExitReason::WaitingForInput, emu.run_for(1));
+        assert_eq!((0x4000_0000, 0), (emu.pc(), emu.reg(Reg::A(0))));
+
+        emu.push_input(b"hi\n");
+        assert_eq!(ExitReason::BudgetExhausted, emu.run_for(1));
+        assert_eq!(3, emu.reg(Reg::A(0)));
+        assert_eq!(b"hi\n", emu.memory(buf, 3).unwrap().as_ref());
+        emu.set_pc(0x4000_0000);
+
+        assert_eq!(0, syscall(&mut emu, SYS_FCNTL, &[0, F_SETFL, O_NONBLOCK]));
+        assert_eq!(
+            O_NONBLOCK as i64,
+            syscall(&mut emu, SYS_FCNTL, &[0, F_GETFL, 0])
+        );
+        assert_eq!(-EAGAIN, syscall(&mut emu, SYS_READ, &[0, buf, 16]));
+        emu.push_input(b"x");
+        assert_eq!(0, syscall(&mut emu, SYS_IOCTL, &[0, FIONREAD, buf]));
+        assert_eq!(&1u32.to_le_bytes(), &emu.memory(buf, 4).unwrap()[..]);
+
+        emu.close_input();
+        assert_eq!(1, syscall(&mut emu, SYS_READ, &[0, buf, 16]));
+        assert_eq!(0, syscall(&mut emu, SYS_READ, &[0, buf, 16]));
+    }
+}
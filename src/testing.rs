@@ -0,0 +1,65 @@
+//! Fixtures shared by the unit tests.
+
+use crate::emulator::{Emulator, ExitReason, Privilege};
+use crate::machine::MachineConfig;
+use crate::primitives::Reg;
+use std::path::{Path, PathBuf};
+
+pub const ECALL: [u8; 4] = [0x73, 0x00, 0x00, 0x00];
+/// `j .`, spinning in place.
+pub const SPIN: [u8; 4] = [0x6f, 0x00, 0x00, 0x00];
+
+/// Default machine running `prgm` from the reset vector, which is returned alongside.
+pub fn machine(prgm: &[u8]) -> (Emulator, u64) {
+    let config = MachineConfig::default();
+    let mut emu = config.build().unwrap();
+    emu.flash_prgm(prgm, config.reset_pc).unwrap();
+    (emu, config.reset_pc)
+}
+
+/// [`machine`] with a heap for `brk` and `mmap` above the program.
+pub fn user(prgm: &[u8]) -> (Emulator, u64) {
+    let (mut emu, pc) = machine(prgm);
+    emu.process_mut()
+        .set_heap(0x4400_0000..0x4800_0000, 0x4400_0000);
+    (emu, pc)
+}
+
+/// [`machine`] in S-mode, where an `ecall` is an SBI call.
+pub fn supervisor(prgm: &[u8]) -> (Emulator, u64) {
+    let (mut emu, pc) = machine(prgm);
+    emu.set_privilege(Privilege::Supervisor);
+    (emu, pc)
+}
+
+/// Put `nr` in `a7` and `args` in `a0` onwards, then run the `ecall` at pc.
+pub fn syscall(emu: &mut Emulator, nr: u64, args: &[u64]) -> ExitReason {
+    emu.set(Reg::A(7), nr);
+    for (i, arg) in args.iter().enumerate() {
+        emu.set(Reg::A(i as u8), *arg);
+    }
+    emu.run_for(1)
+}
+
+/// Directory in the system temp directory for one test, removed again when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Fresh directory named after `name` and this process, so runs never share one.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("emu-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,476 @@
+use crate::emulator::{Emulator, ExitReason};
+use crate::memcheck::Memcheck;
+use crate::primitives::*;
+use crate::process::{Process, Thread, ThreadState};
+use crate::syscalls::{
+    read_guest, read_u64, write_guest, SysResult, EAGAIN, EINVAL, ENOSYS, ETIMEDOUT,
+};
+use std::time::Duration;
+
+/// `clone` flags.
+pub const CLONE_VM: u64 = 0x100;
+pub const CLONE_THREAD: u64 = 0x10000;
+pub const CLONE_SETTLS: u64 = 0x80000;
+pub const CLONE_PARENT_SETTID: u64 = 0x100000;
+pub const CLONE_CHILD_CLEARTID: u64 = 0x200000;
+pub const CLONE_CHILD_SETTID: u64 = 0x1000000;
+
+/// `futex` operations, the command bits without `FUTEX_PRIVATE_FLAG` and
+/// `FUTEX_CLOCK_REALTIME`.
+pub const FUTEX_WAIT: u64 = 0;
+pub const FUTEX_WAKE: u64 = 1;
+pub const FUTEX_REQUEUE: u64 = 3;
+pub const FUTEX_CMP_REQUEUE: u64 = 4;
+pub const FUTEX_WAIT_BITSET: u64 = 9;
+pub const FUTEX_WAKE_BITSET: u64 = 10;
+const FUTEX_CLOCK_REALTIME: u64 = 256;
+const FUTEX_CMD_MASK: u64 = !(128 | FUTEX_CLOCK_REALTIME);
+
+/// Instructions a thread runs before the next runnable one gets its turn.
+pub const QUANTUM: u64 = 10_000;
+
+/// Start a thread in the same address space, as `pthread_create` does.
+///
+/// The child resumes after the `ecall` with `a0` zero, `sp` at `stack` and, with
+/// `CLONE_SETTLS`, `tp` at `tls`. Without `CLONE_VM | CLONE_THREAD` it would be a new
+/// process, which is not supported.
+pub(crate) fn clone(
+    emu: &mut Emulator,
+    flags: u64,
+    stack: u64,
+    ptid: u64,
+    tls: u64,
+    ctid: u64,
+) -> SysResult {
+    if flags & (CLONE_VM | CLONE_THREAD) != CLONE_VM | CLONE_THREAD {
+        return Err(ENOSYS);
+    }
+
+    let tid = emu.process().next_tid;
+    let mut thread = Thread::new(tid);
+    thread.regs = emu.regs();
+    thread.sigmask = emu.process().thread().sigmask;
+    thread.regs[Reg::A(0)] = 0;
+    thread.undefined_regs =
+        emu.memcheck().map_or(0, Memcheck::undefined_regs) & !(1 << Reg::A(0).reg_index());
+    if stack != 0 {
+        thread.regs[Reg::Sp] = stack;
+    }
+    if flags & CLONE_SETTLS != 0 {
+        thread.regs[Reg::Tp] = tls;
+    }
+    thread.pc = emu.current_pc() + 4;
+    if flags & CLONE_CHILD_CLEARTID != 0 {
+        thread.clear_child_tid = ctid;
+    }
+    for (flag, addr) in [(CLONE_PARENT_SETTID, ptid), (CLONE_CHILD_SETTID, ctid)] {
+        if flags & flag != 0 {
+            write_guest(emu, addr, &(tid as u32).to_le_bytes())?;
+        }
+    }
+
+    let process = emu.process_mut();
+    process.next_tid += 1;
+    process.threads.push(thread);
+    Ok(tid)
+}
+
+pub(crate) fn set_tid_address(emu: &mut Emulator, addr: u64) -> SysResult {
+    let thread = emu.process_mut().thread_mut();
+    thread.clear_child_tid = addr;
+    Ok(thread.tid)
+}
+
+/// End the running thread, or the process with `code` if it is the last one.
+///
+/// The word `set_tid_address` pointed at is zeroed and woken, which is how `pthread_join`
+/// learns the thread is gone.
+pub(crate) fn exit(emu: &mut Emulator, code: i32) -> Result<i64, ExitReason> {
+    let live = emu
+        .process()
+        .threads
+        .iter()
+        .filter(|thread| thread.state != ThreadState::Exited)
+        .count();
+    if live == 1 {
+        return Err(ExitReason::Exit(code));
+    }
+
+    let addr = emu.process().thread().clear_child_tid;
+    if addr != 0 && write_guest(emu, addr, &0u32.to_le_bytes()).is_ok() {
+        wake(emu.process_mut(), addr, 1);
+    }
+    let process = emu.process_mut();
+    process.thread_mut().state = ThreadState::Exited;
+    process.reschedule = true;
+    Ok(0)
+}
+
+/// Wait, wake and requeue. With a single process private and shared futexes are the same,
+/// and bitsets are ignored, so the bitset operations wake more than they need to.
+///
+/// A wait's timeout is measured on the guest clock, so on a virtual clock it expires after
+/// a fixed number of instructions, checked whenever threads switch.
+pub(crate) fn futex(
+    emu: &mut Emulator,
+    addr: u64,
+    op: u64,
+    val: u64,
+    timeout: u64,
+    addr2: u64,
+    val3: u64,
+) -> SysResult {
+    let word = |emu: &Emulator| -> Result<u32, i64> {
+        Ok(u32::from_le_bytes(
+            read_guest(emu, addr, 4)?.try_into().unwrap(),
+        ))
+    };
+
+    match op & FUTEX_CMD_MASK {
+        FUTEX_WAIT | FUTEX_WAIT_BITSET => {
+            if word(emu)? != val as u32 {
+                return Err(EAGAIN);
+            }
+            let deadline = match timeout {
+                0 => None,
+                timeout => Some(deadline(emu, op, timeout)?),
+            };
+            if deadline.is_some_and(|deadline| deadline <= emu.uptime()) {
+                return Err(ETIMEDOUT);
+            }
+            let process = emu.process_mut();
+            process.thread_mut().state = ThreadState::Futex { addr, deadline };
+            process.reschedule = true;
+            Ok(0)
+        }
+        FUTEX_WAKE | FUTEX_WAKE_BITSET => Ok(wake(emu.process_mut(), addr, val)),
+        cmd @ (FUTEX_REQUEUE | FUTEX_CMP_REQUEUE) => {
+            if cmd == FUTEX_CMP_REQUEUE && word(emu)? != val3 as u32 {
+                return Err(EAGAIN);
+            }
+            let process = emu.process_mut();
+            let woken = wake(process, addr, val);
+            // The timeout argument is how many of the remaining waiters move to `addr2`.
+            let mut moved = 0;
+            for thread in waiters(process, addr).take(timeout as usize) {
+                if let ThreadState::Futex { addr, .. } = &mut thread.state {
+                    *addr = addr2;
+                }
+                moved += 1;
+            }
+            // Only the compare variant counts the requeued waiters.
+            match cmd {
+                FUTEX_CMP_REQUEUE => Ok(woken + moved),
+                _ => Ok(woken),
+            }
+        }
+        _ => Err(ENOSYS),
+    }
+}
+
+/// Uptime at which a wait with the `struct timespec` at `addr` times out. `FUTEX_WAIT` takes
+/// a relative timeout, `FUTEX_WAIT_BITSET` an absolute one on `CLOCK_MONOTONIC` or, with
+/// `FUTEX_CLOCK_REALTIME`, on `CLOCK_REALTIME`.
+fn deadline(emu: &Emulator, op: u64, addr: u64) -> Result<Duration, i64> {
+    let (secs, nanos) = (read_u64(emu, addr)?, read_u64(emu, addr + 8)?);
+    if nanos >= 1_000_000_000 {
+        return Err(EINVAL);
+    }
+    let time = Duration::new(secs, nanos as u32);
+
+    Ok(match op & FUTEX_CMD_MASK {
+        FUTEX_WAIT => emu.uptime().saturating_add(time),
+        _ if op & FUTEX_CLOCK_REALTIME != 0 => {
+            time.saturating_sub(emu.wall_time().saturating_sub(emu.uptime()))
+        }
+        _ => time,
+    })
+}
+
+fn waiters(process: &mut Process, addr: u64) -> impl Iterator<Item = &mut Thread> {
+    process.threads.iter_mut().filter(move |thread| {
+        matches!(thread.state, ThreadState::Futex { addr: waiting, .. } if waiting == addr)
+    })
+}
+
+/// Make up to `count` threads waiting on `addr` runnable, oldest first, returning how many.
+fn wake(process: &mut Process, addr: u64, count: u64) -> u64 {
+    let mut woken = 0;
+    for thread in waiters(process, addr).take(count as usize) {
+        thread.state = ThreadState::Runnable;
+        woken += 1;
+    }
+    woken
+}
+
+/// Fail the waits due at `now` with `ETIMEDOUT`.
+fn expire(process: &mut Process, now: Duration) {
+    for thread in process.threads.iter_mut() {
+        if let ThreadState::Futex {
+            deadline: Some(deadline),
+            ..
+        } = thread.state
+        {
+            if deadline <= now {
+                thread.state = ThreadState::Runnable;
+                thread.regs[Reg::A(0)] = -ETIMEDOUT as u64;
+            }
+        }
+    }
+}
+
+/// Index of the first runnable thread after the running one, which comes last.
+fn next_runnable(process: &Process) -> Option<usize> {
+    let len = process.threads.len();
+    (1..=len)
+        .map(|i| (process.current + i) % len)
+        .find(|&i| process.threads[i].state == ThreadState::Runnable)
+}
+
+/// Save the running thread and switch to the next runnable one, round robin, after timing
+/// out the waits that are due.
+///
+/// Once every thread is blocked nothing runs to move the clock on, so the waits with the
+/// earliest deadline time out. Returns [`ExitReason::Deadlock`] if nothing can run after
+/// that.
+pub(crate) fn schedule(emu: &mut Emulator) -> Option<ExitReason> {
+    let (regs, pc, now) = (emu.regs(), emu.pc(), emu.uptime());
+    let undefined_regs = emu.memcheck().map_or(0, Memcheck::undefined_regs);
+    let process = emu.process_mut();
+    process.reschedule = false;
+    let current = process.current;
+    process.threads[current].regs = regs;
+    process.threads[current].pc = pc;
+    process.threads[current].undefined_regs = undefined_regs;
+
+    expire(process, now);
+    let next = next_runnable(process).or_else(|| {
+        let earliest = process
+            .threads
+            .iter()
+            .filter_map(|thread| match thread.state {
+                ThreadState::Futex { deadline, .. } => deadline,
+                _ => None,
+            })
+            .min()?;
+        expire(process, earliest);
+        next_runnable(process)
+    });
+    let Some(next) = next else {
+        return Some(ExitReason::Deadlock);
+    };
+
+    let tid = process.threads[next].tid;
+    process
+        .threads
+        .retain(|thread| thread.state != ThreadState::Exited);
+    process.current = process
+        .threads
+        .iter()
+        .position(|thread| thread.tid == tid)
+        .unwrap();
+    let thread = process.thread();
+    let (regs, pc, undefined_regs) = (thread.regs, thread.pc, thread.undefined_regs);
+    emu.set_regs(regs);
+    emu.set_pc(pc);
+    if let Some(memcheck) = emu.memcheck_mut() {
+        memcheck.set_undefined_regs(undefined_regs);
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Clock;
+    use crate::syscalls::{SYS_CLONE, SYS_EXIT, SYS_FUTEX};
+    use crate::testing::{machine, syscall};
+
+    const STACK: u64 = 0x4000_8000;
+    const TLS: u64 = 0x4000_9000;
+    const PTID: u64 = 0x4000_1000;
+    const CTID: u64 = 0x4000_1004;
+
+    const PRGM: [u8; 12] = [
+        0x73, 0x00, 0x00, 0x00, // ecall
+        0x73, 0x00, 0x00, 0x00, // ecall
+        0x6f, 0x00, 0x00, 0x00, // j .
+    ];
+
+    fn spawn(emu: &mut Emulator) {
+        let flags =
+            CLONE_VM | CLONE_THREAD | CLONE_SETTLS | CLONE_PARENT_SETTID | CLONE_CHILD_CLEARTID;
+        syscall(emu, SYS_CLONE, &[flags, STACK, PTID, TLS, CTID]);
+        assert_eq!(2, emu.reg(Reg::A(0)));
+    }
+
+    #[test]
+    fn join_through_futex() {
+        let (mut emu, pc) = machine(&PRGM);
+        spawn(&mut emu);
+        assert_eq!(&2u32.to_le_bytes(), &emu.memory(PTID, 4).unwrap()[..]);
+        emu.host_write(CTID, &2u32.to_le_bytes()).unwrap();
+
+        // The parent waits for the child to clear its id, the child runs meanwhile.
+        syscall(&mut emu, SYS_FUTEX, &[CTID, FUTEX_WAIT | 128, 3]);
+        assert_eq!(-EAGAIN, emu.reg_signed(Reg::A(0)));
+        emu.set_pc(pc + 4);
+        syscall(&mut emu, SYS_FUTEX, &[CTID, FUTEX_WAIT | 128, 2, 0]);
+        assert_eq!(2, emu.process().thread().tid);
+        assert_eq!(pc + 4, emu.pc());
+        assert_eq!(0, emu.reg(Reg::A(0)));
+        assert_eq!((STACK, TLS), (emu.reg(Reg::Sp), emu.reg(Reg::Tp)));
+
+        syscall(&mut emu, SYS_EXIT, &[0]);
+        assert_eq!(1, emu.process().thread().tid);
+        assert_eq!(1, emu.process().threads.len());
+        assert_eq!(pc + 8, emu.pc());
+        assert_eq!(0, emu.reg(Reg::A(0)));
+        assert_eq!(&[0; 4], &emu.memory(CTID, 4).unwrap()[..]);
+    }
+
+    /// Both threads bump a counter 16384 times under a lock taken with `lr.w`/`sc.w` and
+    /// released with `amoswap.w`, and get switched out while holding it along the way.
+    #[test]
+    fn lock_across_threads() {
+        let prgm = [
+            0x73, 0x00, 0x00, 0x00, // ecall
+            0x37, 0x24, 0x00, 0x40, // lui s0, 0x40002
+            0xb7, 0x44, 0x00, 0x00, // lui s1, 4
+            0xaf, 0x22, 0x04, 0x14, // 1: lr.w.aq t0, (s0)
+            0xe3, 0x9e, 0x02, 0xfe, // bnez t0, 1b
+            0x13, 0x03, 0x10, 0x00, // li t1, 1
+            0xaf, 0x23, 0x64, 0x18, // sc.w t2, t1, (s0)
+            0xe3, 0x98, 0x03, 0xfe, // bnez t2, 1b
+            0x03, 0x2e, 0x44, 0x00, // lw t3, 4(s0)
+            0x13, 0x0e, 0x1e, 0x00, // addi t3, t3, 1
+            0x23, 0x22, 0xc4, 0x01, // sw t3, 4(s0)
+            0x2f, 0x20, 0x04, 0x0a, // amoswap.w.rl zero, zero, (s0)
+            0x93, 0x84, 0xf4, 0xff, // addi s1, s1, -1
+            0xe3, 0x9c, 0x04, 0xfc, // bnez s1, 1b
+            0x13, 0x05, 0x00, 0x00, // li a0, 0
+            0x93, 0x08, 0xd0, 0x05, // li a7, SYS_EXIT
+            0x73, 0x00, 0x00, 0x00, // ecall
+        ];
+        let (mut emu, _) = machine(&prgm);
+        spawn(&mut emu);
+
+        assert_eq!(ExitReason::Exit(0), emu.run_for(1_000_000));
+        let lock = emu.memory(0x4000_2000, 8).unwrap();
+        assert_eq!(&[0; 4], &lock[..4]);
+        assert_eq!(&(2 * 16384u32).to_le_bytes(), &lock[4..]);
+    }
+
+    #[test]
+    fn threads_take_turns() {
+        let (mut emu, pc) = machine(&PRGM);
+        emu.set_pc(pc + 4);
+        spawn(&mut emu);
+
+        emu.run_for(QUANTUM as usize - 2);
+        assert_eq!(1, emu.process().thread().tid);
+        emu.run_for(1);
+        assert_eq!(2, emu.process().thread().tid);
+        emu.run_for(QUANTUM as usize);
+        assert_eq!(1, emu.process().thread().tid);
+    }
+
+    #[test]
+    fn waiting_alone() {
+        let (mut emu, pc) = machine(&PRGM);
+        emu.host_write(CTID, &[0; 4]).unwrap();
+
+        syscall(&mut emu, SYS_FUTEX, &[CTID, FUTEX_WAIT, 0, 0x4000_2000]);
+        assert_eq!(-ETIMEDOUT, emu.reg_signed(Reg::A(0)));
+        assert_eq!(pc + 4, emu.pc());
+
+        assert_eq!(
+            ExitReason::Deadlock,
+            syscall(&mut emu, SYS_FUTEX, &[CTID, FUTEX_WAIT, 0, 0])
+        );
+    }
+
+    #[test]
+    fn timeout_while_others_run() {
+        let (mut emu, pc) = machine(&PRGM);
+        emu.set_clock(Clock::seeded(1));
+        spawn(&mut emu);
+        emu.host_write(CTID, &[0; 4]).unwrap();
+        // One millisecond, `QUANTUM` instructions of virtual time.
+        let mut timespec = [0; 16];
+        timespec[8..].copy_from_slice(&1_000_000u64.to_le_bytes());
+        emu.host_write(TLS, &timespec).unwrap();
+
+        syscall(&mut emu, SYS_FUTEX, &[CTID, FUTEX_WAIT, 0, TLS]);
+        assert_eq!(2, emu.process().thread().tid);
+        emu.run_for(QUANTUM as usize);
+        assert_eq!(2, emu.process().thread().tid);
+        emu.run_for(QUANTUM as usize);
+        assert_eq!(1, emu.process().thread().tid);
+        assert_eq!(-ETIMEDOUT, emu.reg_signed(Reg::A(0)));
+        assert_eq!(pc + 8, emu.pc());
+    }
+
+    #[test]
+    fn requeue_counts() {
+        let (mut emu, _) = machine(&PRGM);
+        emu.host_write(CTID, &[0; 4]).unwrap();
+        let wait = |emu: &mut Emulator, tid| {
+            let mut thread = Thread::new(tid);
+            thread.state = ThreadState::Futex {
+                addr: CTID,
+                deadline: None,
+            };
+            emu.process_mut().threads.push(thread);
+        };
+        for tid in 2..5 {
+            wait(&mut emu, tid);
+        }
+
+        syscall(&mut emu, SYS_FUTEX, &[CTID, FUTEX_REQUEUE, 1, 1, PTID]);
+        assert_eq!(1, emu.reg(Reg::A(0)));
+        let states: Vec<_> = emu.process().threads.iter().map(|t| t.state).collect();
+        let futex = |addr| ThreadState::Futex {
+            addr,
+            deadline: None,
+        };
+        assert_eq!(
+            vec![
+                ThreadState::Runnable,
+                ThreadState::Runnable,
+                futex(PTID),
+                futex(CTID)
+            ],
+            states
+        );
+
+        syscall(
+            &mut emu,
+            SYS_FUTEX,
+            &[CTID, FUTEX_CMP_REQUEUE, 0, 1, PTID, 0],
+        );
+        assert_eq!(1, emu.reg(Reg::A(0)));
+        assert_eq!(futex(PTID), emu.process().threads[3].state);
+    }
+
+    #[test]
+    fn memcheck_follows_threads() {
+        let (mut emu, pc) = machine(&PRGM);
+        emu.enable_memcheck();
+        spawn(&mut emu);
+        emu.host_write(CTID, &2u32.to_le_bytes()).unwrap();
+        emu.memcheck_mut()
+            .unwrap()
+            .set_reg_defined(Reg::T(0), false);
+
+        emu.set_pc(pc + 4);
+        syscall(&mut emu, SYS_FUTEX, &[CTID, FUTEX_WAIT, 2, 0]);
+        assert_eq!(2, emu.process().thread().tid);
+        assert!(emu.memcheck().unwrap().is_reg_defined(Reg::T(0)));
+
+        syscall(&mut emu, SYS_EXIT, &[0]);
+        assert_eq!(1, emu.process().thread().tid);
+        assert!(!emu.memcheck().unwrap().is_reg_defined(Reg::T(0)));
+    }
+}